@@ -0,0 +1,324 @@
+extern crate openssl;
+
+use self::openssl::hash::MessageDigest;
+use self::openssl::memcmp;
+use self::openssl::pkcs5;
+use self::openssl::sha;
+
+use std::fs::File;
+use std::io::{BufRead,BufReader};
+use std::str::FromStr;
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Permission {
+    Read,
+    Load,
+    Admin,
+    Node,
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Role {
+    ReadOnly,
+    Loader,
+    Admin,
+    Node,
+}
+
+impl Role {
+    /// loaders may also read and admins may do anything a client may do,
+    /// while node messages are only accepted from other nodes
+    pub fn allows(&self, permission: Permission) -> bool {
+        match (*self, permission) {
+            (Role::ReadOnly, Permission::Read) => true,
+            (Role::Loader, Permission::Read) | (Role::Loader, Permission::Load) => true,
+            (Role::Admin, Permission::Read) | (Role::Admin, Permission::Load) | (Role::Admin, Permission::Admin) => true,
+            (Role::Node, Permission::Node) => true,
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Role,String> {
+        match s {
+            "read_only" => Ok(Role::ReadOnly),
+            "loader" => Ok(Role::Loader),
+            "admin" => Ok(Role::Admin),
+            "node" => Ok(Role::Node),
+            _ => Err(format!("unknown role '{}'", s)),
+        }
+    }
+}
+
+/// The credentials a client or node presents in its hello message.
+#[derive(Clone,Debug,PartialEq)]
+pub enum Credentials {
+    Anonymous,
+    Password(String,String),
+    Token(String),
+}
+
+impl Credentials {
+    /// compares in constant time whether these are the given api token
+    pub fn is_token(&self, token: &str) -> bool {
+        match *self {
+            Credentials::Token(ref secret) => memcmp::eq(&sha::sha256(secret.as_bytes()), &sha::sha256(token.as_bytes())),
+            _ => false,
+        }
+    }
+}
+
+//the stored hash of a secret
+enum Hash {
+    Sha256(Vec<u8>),
+    Pbkdf2Sha256(usize,String,Vec<u8>),
+}
+
+impl Hash {
+    fn verify(&self, secret: &str) -> bool {
+        match *self {
+            Hash::Sha256(ref hash) => memcmp::eq(&sha::sha256(secret.as_bytes()), &hash[..]),
+            Hash::Pbkdf2Sha256(iterations, ref salt, ref hash) => {
+                let mut key = vec!(0; hash.len());
+                match pkcs5::pbkdf2_hmac(secret.as_bytes(), salt.as_bytes(), iterations, MessageDigest::sha256(), &mut key) {
+                    Ok(_) => memcmp::eq(&key, &hash[..]),
+                    Err(_) => false,
+                }
+            },
+        }
+    }
+}
+
+struct Account {
+    kind: String,
+    name: String,
+    role: Role,
+    hash: Hash,
+}
+
+/// Accounts allowed to connect, read from files of the form below. User
+/// passwords are hashed with pbkdf2 hmac sha256 using the given number of
+/// iterations and salt, while tokens, which are long random values rather
+/// than chosen passwords, are stored as their unsalted hex sha256 digest.
+///
+/// ```text
+/// user <username> <role> pbkdf2-sha256:<iterations>:<salt>:<hash>
+/// token <name> <role> sha256::<hash>
+/// ```
+///
+/// Roles are one of 'read_only', 'loader', 'admin' or 'node'.
+pub struct Authenticator {
+    accounts: Vec<Account>,
+}
+
+impl Authenticator {
+    pub fn from_file(filename: &str) -> Result<Authenticator,String> {
+        let file = match File::open(filename) {
+            Ok(file) => file,
+            Err(e) => return Err(format!("failed to open credentials file '{}': {}", filename, e)),
+        };
+
+        Authenticator::parse(BufReader::new(file), filename)
+    }
+
+    //parses the lines of a credentials file, where the filename is only used in errors
+    fn parse<R: BufRead>(reader: R, filename: &str) -> Result<Authenticator,String> {
+        let mut accounts = vec!();
+        for (i, line) in reader.lines().enumerate() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => return Err(format!("failed to read credentials file '{}': {}", filename, e)),
+            };
+
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("#") {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 4 || (fields[0] != "user" && fields[0] != "token") {
+                return Err(format!("invalid account on line {} of '{}'", i + 1, filename));
+            }
+
+            let role = match Role::from_str(fields[2]) {
+                Ok(role) => role,
+                Err(e) => return Err(format!("{} on line {} of '{}'", e, i + 1, filename)),
+            };
+
+            let hash = match parse_hash(fields[0], fields[3]) {
+                Some(hash) => hash,
+                None if fields[0] == "user" => return Err(format!("invalid pbkdf2-sha256 password hash on line {} of '{}'", i + 1, filename)),
+                None => return Err(format!("invalid sha256 token hash on line {} of '{}'", i + 1, filename)),
+            };
+
+            accounts.push(Account {
+                kind: fields[0].to_string(),
+                name: fields[1].to_string(),
+                role: role,
+                hash: hash,
+            });
+        }
+
+        Ok(Authenticator {
+            accounts: accounts,
+        })
+    }
+
+    /// returns the name and role of the account matching the credentials
+    pub fn authenticate(&self, credentials: &Credentials) -> Result<(String,Role),String> {
+        let (kind, name, secret) = match *credentials {
+            Credentials::Anonymous => return Err("authentication required".to_string()),
+            Credentials::Password(ref username, ref password) => ("user", Some(username), password),
+            Credentials::Token(ref token) => ("token", None, token),
+        };
+
+        for account in self.accounts.iter() {
+            if account.kind != kind || name.map(|x| x != &account.name).unwrap_or(false) {
+                continue;
+            }
+
+            if account.hash.verify(secret) {
+                return Ok((account.name.clone(), account.role));
+            }
+        }
+
+        Err("invalid credentials".to_string())
+    }
+}
+
+//parses a pbkdf2-sha256 hash for users or a sha256 hash for tokens
+fn parse_hash(kind: &str, value: &str) -> Option<Hash> {
+    let hash_fields: Vec<&str> = value.split(':').collect();
+    match (kind, &hash_fields[..]) {
+        ("user", &["pbkdf2-sha256", iterations, salt, hash]) => {
+            let iterations = match iterations.parse::<usize>() {
+                Ok(iterations) if iterations > 0 => iterations,
+                _ => return None,
+            };
+
+            match decode_hex(hash) {
+                Some(hash) if !salt.is_empty() && hash.len() == 32 => Some(Hash::Pbkdf2Sha256(iterations, salt.to_string(), hash)),
+                _ => None,
+            }
+        },
+        ("token", &["sha256", "", hash]) => {
+            match decode_hex(hash) {
+                Some(hash) if hash.len() == 32 => Some(Hash::Sha256(hash)),
+                _ => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    if value.len() % 2 != 0 || !value.chars().all(|c| c.is_digit(16)) {
+        return None;
+    }
+
+    let mut bytes = vec!();
+    for i in 0..value.len() / 2 {
+        bytes.push(u8::from_str_radix(&value[i * 2..i * 2 + 2], 16).unwrap());
+    }
+
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    //pbkdf2 hmac sha256 of 'password' salted with 'salt' over two iterations, and sha256 of the token 'abc'
+    static PASSWORD_HASH: &'static str = "pbkdf2-sha256:2:salt:ae4d0c95af6b46d32d0adff928f06dd02a303f8ef3c251dfd6e2d85a95474c43";
+    static TOKEN_HASH: &'static str = "sha256::ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    fn parse(accounts: &str) -> Result<Authenticator,String> {
+        Authenticator::parse(Cursor::new(accounts.as_bytes()), "test.accounts")
+    }
+
+    fn password(username: &str, password: &str) -> Credentials {
+        Credentials::Password(username.to_string(), password.to_string())
+    }
+
+    #[test]
+    fn verify_pbkdf2() {
+        let hash = parse_hash("user", PASSWORD_HASH).unwrap();
+        assert!(hash.verify("password"));
+        assert!(!hash.verify("Password"));
+        assert!(!hash.verify(""));
+
+        //the same password with a different iteration count is a different hash
+        let hash = parse_hash("user", "pbkdf2-sha256:1:salt:ae4d0c95af6b46d32d0adff928f06dd02a303f8ef3c251dfd6e2d85a95474c43").unwrap();
+        assert!(!hash.verify("password"));
+    }
+
+    #[test]
+    fn parse_hashes() {
+        assert!(parse_hash("token", TOKEN_HASH).is_some());
+        assert!(parse_hash("user", TOKEN_HASH).is_none());
+        assert!(parse_hash("token", PASSWORD_HASH).is_none());
+        assert!(parse_hash("user", "pbkdf2-sha256:0:salt:ae4d0c95af6b46d32d0adff928f06dd02a303f8ef3c251dfd6e2d85a95474c43").is_none());
+        assert!(parse_hash("user", "pbkdf2-sha256:2::ae4d0c95af6b46d32d0adff928f06dd02a303f8ef3c251dfd6e2d85a95474c43").is_none());
+        assert!(parse_hash("user", "pbkdf2-sha256:2:salt:ae4d").is_none());
+        assert!(parse_hash("token", "sha256::xyz").is_none());
+    }
+
+    #[test]
+    fn authenticate_accounts() {
+        let authenticator = parse(&format!("# accounts\n\nuser daniel admin {}\ntoken etl loader {}\n", PASSWORD_HASH, TOKEN_HASH)).unwrap();
+        assert_eq!(authenticator.authenticate(&password("daniel", "password")), Ok(("daniel".to_string(), Role::Admin)));
+        assert_eq!(authenticator.authenticate(&Credentials::Token("abc".to_string())), Ok(("etl".to_string(), Role::Loader)));
+        assert!(authenticator.authenticate(&password("daniel", "wrong")).is_err());
+        assert!(authenticator.authenticate(&password("alice", "password")).is_err());
+        assert!(authenticator.authenticate(&Credentials::Token("abd".to_string())).is_err());
+        assert!(authenticator.authenticate(&Credentials::Anonymous).is_err());
+
+        //a password is never checked against a token account
+        assert!(authenticator.authenticate(&password("etl", "abc")).is_err());
+    }
+
+    #[test]
+    fn parse_invalid_accounts() {
+        assert!(parse(&format!("user daniel {}\n", PASSWORD_HASH)).is_err());
+        assert!(parse(&format!("admin daniel admin {}\n", PASSWORD_HASH)).is_err());
+        assert!(parse(&format!("user daniel superuser {}\n", PASSWORD_HASH)).is_err());
+        assert!(parse(&format!("user daniel admin {}\n", TOKEN_HASH)).is_err());
+        assert!(parse(&format!("token etl loader {}\n", PASSWORD_HASH)).is_err());
+    }
+
+    #[test]
+    fn role_permissions() {
+        assert!(Role::ReadOnly.allows(Permission::Read));
+        assert!(!Role::ReadOnly.allows(Permission::Load));
+        assert!(Role::Loader.allows(Permission::Read));
+        assert!(Role::Loader.allows(Permission::Load));
+        assert!(!Role::Loader.allows(Permission::Admin));
+        assert!(Role::Admin.allows(Permission::Read));
+        assert!(Role::Admin.allows(Permission::Load));
+        assert!(Role::Admin.allows(Permission::Admin));
+        assert!(!Role::Admin.allows(Permission::Node));
+        assert!(Role::Node.allows(Permission::Node));
+        assert!(!Role::Node.allows(Permission::Read));
+    }
+
+    #[test]
+    fn role_names() {
+        assert_eq!(Role::from_str("read_only"), Ok(Role::ReadOnly));
+        assert_eq!(Role::from_str("loader"), Ok(Role::Loader));
+        assert_eq!(Role::from_str("admin"), Ok(Role::Admin));
+        assert_eq!(Role::from_str("node"), Ok(Role::Node));
+        assert!(Role::from_str("root").is_err());
+    }
+
+    #[test]
+    fn token_comparison() {
+        assert!(Credentials::Token("abc".to_string()).is_token("abc"));
+        assert!(!Credentials::Token("abc".to_string()).is_token("abd"));
+        assert!(!password("abc", "abc").is_token("abc"));
+    }
+}
@@ -1,41 +1,121 @@
 extern crate argparse;
 use argparse::{ArgumentParser,Store,StoreTrue};
 
-extern crate capnp;
 extern crate csv;
 
 extern crate fuzzydb;
-use fuzzydb::message_capnp;
-use fuzzydb::message_capnp::message::msg_type::{EntitiesMsg,ResultMsg};
-use fuzzydb::parser::Command::{Exit,Help,Load,Query};
+use fuzzydb::auth::Credentials;
+use fuzzydb::client::{Candidate,Client,Entity,Error};
+use fuzzydb::model::Classification;
+use fuzzydb::transport::Transport;
+use fuzzydb::parser::Command::{Aggregate,Deduplicate,Exit,Help,Load,Match,MatchFile,Query,Stats};
 
 extern crate nom;
 extern crate time;
+extern crate unicode_width;
+use unicode_width::UnicodeWidthStr;
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap,BTreeSet};
 use std::io;
 use std::io::prelude::*; //needed for flushing stdout
-use std::net::{Ipv4Addr,SocketAddrV4,TcpStream};
-use std::str::FromStr;
+use std::sync::{Arc,Mutex};
+use std::sync::mpsc::{channel,sync_channel};
+use std::thread;
 
 fn main() {
     let mut host_ip: String = "127.0.0.1".to_string();
     let mut host_port: u16 = 0;
     let mut batch_size: u16 = 250;
+    let mut max_in_flight: usize = 8;
+    let mut tls = false;
+    let mut tls_ca_file: String = "".to_string();
+    let mut tls_cert_file: String = "".to_string();
+    let mut tls_key_file: String = "".to_string();
+    let mut allow_insecure_auth = false;
+    let mut username: String = "".to_string();
+    let mut token: String = "".to_string();
     let mut debug = false;
     {    //solely to limit scope of parser variable
         let mut parser = ArgumentParser::new();
         parser.set_description("start a fuzzydb client session");
-        parser.refer(&mut host_ip).add_option(&["-i", "--host-ip"], Store, "Ip address of the host to connect to").required();
+        parser.refer(&mut host_ip).add_option(&["-i", "--host-ip"], Store, "Hostname or ip address of the host to connect to").required();
         parser.refer(&mut host_port).add_option(&["-p", "--host-port"], Store, "Port of the host to connect to").required();
         parser.refer(&mut batch_size).add_option(&["-b", "--batch-size"], Store, "Number of records in each batch sent for insertion");
+        parser.refer(&mut max_in_flight).add_option(&["-m", "--max-in-flight"], Store, "Number of concurrent match requests when matching a file");
+        parser.refer(&mut tls).add_option(&["--tls"], StoreTrue, "Connect to the host using tls");
+        parser.refer(&mut tls_ca_file).add_option(&["--tls-ca"], Store, "Pem file of the certificate authorities trusted to sign the host certificate");
+        parser.refer(&mut tls_cert_file).add_option(&["--tls-cert"], Store, "Pem certificate chain file presented to hosts requiring mutual tls");
+        parser.refer(&mut tls_key_file).add_option(&["--tls-key"], Store, "Pem private key file of the tls certificate");
+        parser.refer(&mut allow_insecure_auth).add_option(&["--allow-insecure-auth"], StoreTrue, "Send credentials to the host without tls, for networks that are otherwise protected");
+        parser.refer(&mut username).add_option(&["-u", "--username"], Store, "Username to authenticate as, the password is read from FUZZYDB_PASSWORD or prompted for");
+        parser.refer(&mut token).add_option(&["--token"], Store, "Api token to authenticate with");
         parser.refer(&mut debug).add_option(&["-d", "--debug"], StoreTrue, "Turn debug output on");
         parser.parse_args_or_exit();
     }
 
-    //parse the host address
-    let host_ip = Ipv4Addr::from_str(&host_ip[..]).unwrap();
-    let host_addr = SocketAddrV4::new(host_ip, host_port);
+    //resolve the host addresses, which are tried in turn until one connects
+    let host_addrs = match fuzzydb::transport::resolve(&host_ip[..], host_port) {
+        Ok(socket_addrs) => socket_addrs,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        },
+    };
+
+    //create transport, tls is used when requested or any tls file is given
+    let mut transport = match (tls || tls_ca_file.len() != 0 || tls_cert_file.len() != 0, tls_cert_file.len() != 0 && tls_key_file.len() != 0) {
+        (false, _) => Transport::plain(),
+        (true, identity) => {
+            let ca_file = if tls_ca_file.len() == 0 { None } else { Some(&tls_ca_file[..]) };
+            let identity = if identity { Some((&tls_cert_file[..], &tls_key_file[..])) } else { None };
+            match Transport::client(ca_file, identity) {
+                Ok(transport) => transport,
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                },
+            }
+        },
+    };
+
+    transport.set_allow_insecure_auth(allow_insecure_auth);
+
+    //create credentials presented on every connection
+    let credentials = match (username.len(), token.len()) {
+        (0, 0) => Credentials::Anonymous,
+        (0, _) => Credentials::Token(token),
+        _ => {
+            let password = match std::env::var("FUZZYDB_PASSWORD") {
+                Ok(password) => password,
+                Err(_) => {
+                    print!("Password: ");
+                    std::io::stdout().flush().ok();
+
+                    let mut password = String::new();
+                    io::stdin().read_line(&mut password).ok();
+                    password.trim_right_matches(|c| c == '\r' || c == '\n').to_string()
+                },
+            };
+
+            Credentials::Password(username, password)
+        },
+    };
+
+    //check that the host speaks a compatible protocol and accepts our credentials before accepting commands
+    let mut client = match Client::connect(&host_addrs, Arc::new(transport), credentials) {
+        Ok(client) => {
+            if debug { println!("connected to host {}:{}", host_ip, host_port); }
+            client
+        },
+        Err(e) => {
+            println!("unable to use host {}:{}: {}", host_ip, host_port, e);
+            return;
+        },
+    };
+    client.set_batch_size(batch_size as usize);
+
+    //at least one match request must be in flight to make progress
+    let max_in_flight = std::cmp::max(max_in_flight, 1);
 
     //loop read user input
     let stdin = io::stdin();
@@ -65,14 +145,107 @@ fn main() {
 
         //execute command
         match cmd {
+            Aggregate(field_names, filters, group_by) => {
+                //start time
+                let start_time = time::precise_time_ns();
+
+                //send aggregate message and read group counts
+                let group_counts = client.aggregate(&field_names, &filters, &group_by);
+
+                //print out query execution time
+                let duration = (time::precise_time_ns() - start_time) / 1000000;
+                println!("query execution in {}ms", duration);
+
+                let group_counts = match group_counts {
+                    Ok(group_counts) => group_counts,
+                    Err(e) => {
+                        println!("error: {}", e);
+                        continue;
+                    },
+                };
+
+                //find lengths of columns
+                let mut lengths: Vec<usize> = group_by.iter().map(|x| x.width()).collect();
+                lengths.push("count".len());
+                for &(ref values, count) in group_counts.iter() {
+                    for (i, value) in values.iter().enumerate() {
+                        if value.width() > lengths[i] {
+                            lengths[i] = value.width();
+                        }
+                    }
+
+                    let count_length = format!("{}", count).len();
+                    if count_length > lengths[group_by.len()] {
+                        lengths[group_by.len()] = count_length;
+                    }
+                }
+
+                //print out column names
+                let mut total_length = 1;
+                print!("|");
+                for (i, name) in group_by.iter().map(|x| &x[..]).chain(Some("count").into_iter()).enumerate() {
+                    print!(" {} |", pad(name, lengths[i]));
+                    total_length += 3 + lengths[i];
+                }
+                println!("");
+
+                //print separating line
+                for _ in 0..total_length {
+                    print!("-");
+                }
+                println!("");
+
+                //print out group counts
+                for &(ref values, count) in group_counts.iter() {
+                    print!("|");
+                    for (i, value) in values.iter().enumerate() {
+                        print!(" {} |", pad(value, lengths[i]));
+                    }
+                    println!(" {:>1$} |", count, lengths[group_by.len()]);
+                }
+            },
+            Deduplicate(model) => {
+                //start time
+                let start_time = time::precise_time_ns();
+
+                //send deduplicate message and read cluster counts
+                let clusters = client.deduplicate(&model);
+
+                //print out deduplicate execution time
+                let duration = (time::precise_time_ns() - start_time) / 1000000;
+                println!("deduplicate execution in {}ms", duration);
+
+                let clusters = match clusters {
+                    Ok(clusters) => clusters,
+                    Err(e) => {
+                        println!("error: {}", e);
+                        continue;
+                    },
+                };
+
+                let entity_count = clusters.iter().fold(0, |sum, x| sum + x.1);
+                println!("found {} duplicate clusters containing {} entities", clusters.len(), entity_count);
+
+                //print out clusters
+                println!("| {:>20} | {:>10} |", "cluster_id", "count");
+                for &(ref cluster_id, count) in clusters.iter() {
+                    println!("| {:>20} | {:>10} |", cluster_id, count);
+                }
+            },
             Exit => {
                 break;
             },
             Help => {
+                println!("\tDEDUPLICATE USING <model> => assign every entity the cluster_id of the entities it matches");
                 println!("\tEXIT => exit the session");
                 println!("\tHELP => print this menu");
                 println!("\tLOAD <filename> => load csv file into cluster");
-                println!("\tSELECT [ * | <field> ( , <field> )* ] WHERE <field> ~<type> <value> (AND <field> ~<type> <value>)* => perfrom query on cluster");
+                println!("\tMATCH {{ <field> : <value> ( , <field> : <value> )* }} USING <model> [LIMIT <count>] => find weighted matches for a record");
+                println!("\tMATCH FILE <filename> USING <model> OUTPUT <filename> [LIMIT <count>] => match every record of a csv file and write candidates to a csv file");
+                println!("\tSELECT [ * | <field> ( , <field> )* ] WHERE <filter> (AND <filter>)* => perfrom query on cluster");
+                println!("\tSELECT ( <field> , )* COUNT(*) WHERE <field> ~<type> <value> (AND <field> ~<type> <value>)* [GROUP BY <field> ( , <field> )*] => count matching entities on cluster");
+                println!("\tSTATS => print filter cache statistics for each node");
+                println!("\t\t<filter> = [ * | <field> | ANY( <field> ( , <field> )* ) ] ~[ token: | value: ][ noaccent: ]<type> <value>");
             },
             Load(filename) => {
                 //start time
@@ -87,215 +260,313 @@ fn main() {
                 let mut reader = reader.unwrap();
                 let header = reader.headers().unwrap();
 
-                //loop through all records in the reader
-                let mut record_count = 0;
-                let mut record_buffer = Vec::new();
-                for record in reader.records() {
-                    let record = record.unwrap();
-                    record_buffer.push(record.clone());
-
-                    if record_buffer.len() == batch_size as usize {
-                        let rb_clone = record_buffer.clone();
-                        let mut msg_builder = capnp::message::Builder::new_default();
-                        {
-                            let msg = msg_builder.init_root::<message_capnp::message::Builder>(); 
-                            let mut insert_entities_msg = msg.get_msg_type().init_insert_entities_msg(rb_clone.len() as u32);
-                        
-                            for (i, record) in rb_clone.iter().enumerate() {
-                                let entity = insert_entities_msg.borrow().get(i as u32);
-                                let mut fields = entity.init_fields(header.len() as u32);
-
-                                for j in 0..header.len() {
-                                    let mut field = fields.borrow().get(j as u32);
-                                    field.set_name(&header[j][..]);
-                                    field.set_value(&record[j].to_lowercase()[..]);
-                                }
-                            }
-                        }
-
-                        //send insert entity message
-                        let mut stream = TcpStream::connect(host_addr).unwrap();
-                        capnp::serialize::write_message(&mut stream, &msg_builder).unwrap();
+                //insert every record, the client sends them in batches
+                let entities = reader.records().map(|record| header.iter().cloned().zip(record.unwrap().into_iter()).collect::<Entity>());
+                match client.insert_entities(entities) {
+                    Ok(record_count) => {
+                        let duration = (time::precise_time_ns() - start_time) / 1000000;
+                        println!("\tloaded {} records in {}ms", record_count, duration);
+                    },
+                    Err(e) => println!("error: {}", e),
+                }
+            },
+            Match(fields, model, limit) => {
+                //start time
+                let start_time = time::precise_time_ns();
 
-                        //wait for success result message
-                        let msg_reader = capnp::serialize::read_message(&mut stream, ::capnp::message::ReaderOptions::new()).unwrap();
-                        let msg = msg_reader.get_root::<message_capnp::message::Reader>().unwrap();
+                //send match message and read candidates
+                let candidates = client.match_record(&fields, &model, limit.unwrap_or(0));
 
-                        match msg.get_msg_type().which() {
-                            Ok(ResultMsg(result_msg)) => {
-                                if result_msg {
-                                    if debug { println!("inserted {} records", record_count); }
-                                } else {
-                                    panic!("Error writing entity buffer");
-                                }
-                            }
-                            _ => panic!("Unexpected message type returned"),
-                        }
+                //print out query execution time
+                let duration = (time::precise_time_ns() - start_time) / 1000000;
+                println!("match execution in {}ms", duration);
 
-                        record_buffer.clear();
+                let candidates = match candidates {
+                    Ok(candidates) => candidates,
+                    Err(e) => {
+                        println!("error: {}", e);
+                        continue;
+                    },
+                };
+                println!("candidates returned {}", candidates.len());
+
+                //collect field names of all candidates
+                let mut field_names = BTreeSet::new();
+                for candidate in candidates.iter() {
+                    for &(ref name, _) in candidate.entity.iter() {
+                        field_names.insert(name.clone());
                     }
+                }
 
-                    record_count += 1;
+                //print out candidates
+                print!("| {:>10} | {:>14} |", "weight", "classification");
+                for field_name in field_names.iter() {
+                    print!(" {} |", field_name);
                 }
+                println!("");
 
-                //send remaining records in the buffer
-                if record_buffer.len() != 0 {
-                    let mut msg_builder = capnp::message::Builder::new_default();
-                    {
-                        let msg = msg_builder.init_root::<message_capnp::message::Builder>(); 
-                        let mut insert_entities_msg = msg.get_msg_type().init_insert_entities_msg(record_buffer.len() as u32);
-                    
-                        for (i, record) in record_buffer.iter().enumerate() {
-                            let entity = insert_entities_msg.borrow().get(i as u32);
-                            let mut fields = entity.init_fields(header.len() as u32);
-
-                            for j in 0..header.len() {
-                                let mut field = fields.borrow().get(j as u32);
-                                field.set_name(&header[j][..]);
-                                field.set_value(&record[j].to_lowercase()[..]);
-                            }
-                        }
+                for candidate in candidates.iter() {
+                    print!("| {:>10.3} | {:>14} |", candidate.weight, classification_name(candidate.classification));
+                    for field_name in field_names.iter() {
+                        let value = candidate.entity.iter().find(|x| &x.0 == field_name).map(|x| &x.1[..]).unwrap_or("");
+                        print!(" {} |", value);
                     }
+                    println!("");
+                }
+            },
+            MatchFile(input_filename, model, output_filename, limit) => {
+                //start time
+                let start_time = time::precise_time_ns();
 
-                    //send insert entity message
-                    let mut stream = TcpStream::connect(host_addr).unwrap();
-                    capnp::serialize::write_message(&mut stream, &msg_builder).unwrap();
+                //open csv file reader and writer
+                let reader = csv::Reader::from_file(input_filename.clone());
+                if !reader.is_ok() {
+                    println!("file '{}' does not exist or cannot be opened", input_filename);
+                    continue;
+                }
+                let mut reader = reader.unwrap();
+                let header = match reader.headers() {
+                    Ok(header) => header,
+                    Err(e) => {
+                        println!("failed to read header of '{}': {}", input_filename, e);
+                        continue;
+                    },
+                };
 
-                    //wait for success result message
-                    let msg_reader = capnp::serialize::read_message(&mut stream, ::capnp::message::ReaderOptions::new()).unwrap();
-                    let msg = msg_reader.get_root::<message_capnp::message::Reader>().unwrap();
+                let writer = csv::Writer::from_file(output_filename.clone());
+                if !writer.is_ok() {
+                    println!("file '{}' cannot be created", output_filename);
+                    continue;
+                }
+                let mut writer = writer.unwrap();
+
+                //the candidate's values for the input fields are followed by the weight each field added
+                let mut output_header = vec!("row_id".to_string(), "weight".to_string(), "classification".to_string());
+                output_header.extend(header.iter().cloned());
+                output_header.extend(header.iter().map(|x| format!("{}_score", x)));
+                if let Err(e) = writer.write(output_header.iter()) {
+                    println!("failed to write to '{}': {}", output_filename, e);
+                    continue;
+                }
 
-                    match msg.get_msg_type().which() {
-                        Ok(ResultMsg(result_msg)) => {
-                            if result_msg {
-                                if debug { println!("inserted {} records", record_count); }
-                            } else {
-                                panic!("Error writing entity buffer");
+                //start workers that each keep a single match request in flight, rows are numbered in the order they are sent
+                let (row_tx, row_rx) = sync_channel::<(usize,usize,Vec<(String,String)>)>(max_in_flight);
+                let (result_tx, result_rx) = channel::<(usize,usize,Result<Vec<Candidate>,Error>)>();
+                let row_rx = Arc::new(Mutex::new(row_rx));
+                let mut workers = vec!();
+                for _ in 0..max_in_flight {
+                    let (row_rx, result_tx, model, client) = (row_rx.clone(), result_tx.clone(), model.clone(), client.clone());
+                    workers.push(thread::spawn(move || {
+                        loop {
+                            let row = row_rx.lock().unwrap().recv();
+                            let (sequence, row_id, fields) = match row {
+                                Ok(row) => row,
+                                Err(_) => break,
+                            };
+
+                            //the results are no longer read once writing the output fails
+                            let candidates = client.match_record(&fields, &model, limit.unwrap_or(0));
+                            if result_tx.send((sequence, row_id, candidates)).is_err() {
+                                break;
                             }
                         }
-                        _ => panic!("Unexpected message type returned"),
-                    }
+                    }));
                 }
+                drop((row_rx, result_tx));
+
+                //feed rows to the workers from a separate thread so results may be written as they arrive
+                let header_clone = header.clone();
+                let feeder = thread::spawn(move || {
+                    let (mut row_count, mut skipped_count) = (0, 0);
+                    for (i, record) in reader.records().enumerate() {
+                        //rows that cannot be read are reported and skipped
+                        let record = match record {
+                            Ok(record) => record,
+                            Err(e) => {
+                                println!("skipping row {}: {}", i + 1, e);
+                                skipped_count += 1;
+                                continue;
+                            },
+                        };
+
+                        let fields = header_clone.iter().cloned().zip(record.into_iter()).collect();
+                        if row_tx.send((row_count, i + 1, fields)).is_err() {
+                            break;
+                        }
 
-                let duration = (time::precise_time_ns() - start_time) / 1000000;
-                println!("\tloaded {} records in {}ms", record_count, duration);
-            },
-            Query(field_names, filters) => {
-                //start time
-                let start_time = time::precise_time_ns();
+                        row_count += 1;
+                    }
+
+                    (row_count, skipped_count)
+                });
+
+                //write one output row per candidate, holding results that arrive early so rows keep the order of the input
+                let (mut match_count, mut error_count) = (0, 0);
+                let (mut pending, mut next_sequence) = (BTreeMap::new(), 0);
+                let mut write_error = None;
+                'results: for (sequence, row_id, candidates) in result_rx.iter() {
+                    pending.insert(sequence, (row_id, candidates));
+                    while let Some((row_id, candidates)) = pending.remove(&next_sequence) {
+                        next_sequence += 1;
+                        match candidates {
+                            Ok(candidates) => {
+                                for candidate in candidates {
+                                    let mut row = vec!(format!("{}", row_id), format!("{}", candidate.weight), classification_name(candidate.classification).to_string());
+                                    for field_name in header.iter() {
+                                        let value = candidate.entity.iter().find(|x| &x.0 == field_name).map(|x| x.1.clone()).unwrap_or(String::new());
+                                        row.push(value);
+                                    }
+
+                                    //fields the model does not compare have no score
+                                    for field_name in header.iter() {
+                                        let score = candidate.scores.iter().find(|x| &x.0 == field_name).map(|x| format!("{}", x.1)).unwrap_or(String::new());
+                                        row.push(score);
+                                    }
 
-                //create query message
-                let mut msg_builder = capnp::message::Builder::new_default();
-                {
-                    let msg = msg_builder.init_root::<message_capnp::message::Builder>();
-                    let mut query_msg = msg.get_msg_type().init_query_msg(filters.len() as u32);
-                    for (i, filter) in filters.iter().enumerate() {
-                        let mut query_filter = query_msg.borrow().get(i as u32);
-                        query_filter.set_field_name(&filter.field_name[..]);
-                        query_filter.set_filter_type(&filter.filter_type[..]);
-                        query_filter.set_value(&filter.value[..]);
-                        
-                        let mut filter_params = query_filter.init_params(filter.params.len() as u32);
-                        for (j, param) in filter.params.iter().enumerate() {
-                            filter_params.set(j as u32, &param[..]);
+                                    if let Err(e) = writer.write(row.iter()) {
+                                        write_error = Some(e);
+                                        break 'results;
+                                    }
+                                    match_count += 1;
+                                }
+                            },
+                            Err(e) => {
+                                if debug { println!("failed to match row {}: {}", row_id, e); }
+                                error_count += 1;
+                            },
                         }
                     }
                 }
 
-                //send query message
-                let mut stream = TcpStream::connect(host_addr).unwrap();
-                capnp::serialize::write_message(&mut stream, &msg_builder).unwrap();
+                //stop the workers and the feeder when writing failed by no longer reading their results
+                drop(result_rx);
+                let (row_count, skipped_count) = feeder.join().unwrap();
+                for worker in workers {
+                    worker.join().unwrap();
+                }
 
-                //read entities tokens message
-                let msg_reader = capnp::serialize::read_message(&mut stream, ::capnp::message::ReaderOptions::new()).unwrap();
-                let msg = msg_reader.get_root::<message_capnp::message::Reader>().unwrap();
+                if let Some(e) = write_error.or(writer.flush().err()) {
+                    println!("failed to write to '{}': {}", output_filename, e);
+                    continue;
+                }
 
-                //print out query execution time
                 let duration = (time::precise_time_ns() - start_time) / 1000000;
-                println!("query execution in {}ms", duration);
+                println!("\tmatched {} records to {} candidates in {}ms", row_count, match_count, duration);
+                if error_count != 0 {
+                    println!("\tfailed to match {} records", error_count);
+                }
 
-                //parse out message
-                match msg.get_msg_type().which() {
-                    Ok(EntitiesMsg(entities_msg)) => {
-                        let entities = entities_msg.unwrap();
-                        let mut field_lengths = BTreeMap::new();
-                        let mut entity_count = 0;
-
-                        //find lengths of fields
-                        for entity in entities.iter() {
-                            let fields = entity.get_fields().unwrap();
-                            for field in fields.iter() {
-                                let field_name = field.get_name().unwrap();
-                                let value = field.get_value().unwrap();
-
-                                //if the field name is not required in output continue
-                                if !field_names.contains(&field_name.to_string()) && field_names.len() != 0 {
-                                    continue;
-                                }
+                if skipped_count != 0 {
+                    println!("\tskipped {} unreadable records", skipped_count);
+                }
+            },
+            Query(field_names, filters) => {
+                //start time
+                let start_time = time::precise_time_ns();
 
-                                //if field name hasn't been inserted in field lengths yet insert
-                                if !field_lengths.contains_key(field_name) {
-                                    field_lengths.insert(field_name, field_name.len() as u32);
-                                }
+                //send query message
+                let mut results = match client.select(&field_names, &filters) {
+                    Ok(results) => results,
+                    Err(e) => {
+                        println!("error: {}", e);
+                        continue;
+                    },
+                };
+                println!("entities matched {}", results.matched());
+
+                //print entities as each chunk arrives, reprinting the column header whenever a chunk widens a column
+                let mut field_lengths: BTreeMap<String,usize> = BTreeMap::new();
+                for chunk in results.by_ref() {
+                    let chunk = match chunk {
+                        Ok(chunk) => chunk,
+                        Err(e) => {
+                            println!("error: {}", e);
+                            break;
+                        },
+                    };
+
+                    //collect the fields required in output
+                    let mut entities = vec!();
+                    for entity in chunk {
+                        let fields: BTreeMap<String,String> = entity.into_iter()
+                            .filter(|x| field_names.len() == 0 || field_names.contains(&x.0))
+                            .collect();
+
+                        entities.push(fields);
+                    }
 
-                                //if length is greater than previous insert
-                                if value.len() as u32 > *field_lengths.get(field_name).unwrap() {
-                                    field_lengths.insert(field_name, value.len() as u32);
-                                }
+                    //find lengths of fields
+                    let mut layout_changed = false;
+                    for fields in entities.iter() {
+                        for (field_name, value) in fields.iter() {
+                            let length = field_lengths.entry(field_name.clone()).or_insert(0);
+                            let width = std::cmp::max(field_name.width(), value.width());
+                            if width > *length {
+                                *length = width;
+                                layout_changed = true;
                             }
-
-                            entity_count += 1;
                         }
+                    }
 
-                        println!("entities returned {}", entity_count);
-                        
-                        //print out fields
-                        let mut total_length = 1;
+                    //print out fields and a separating line
+                    if layout_changed {
                         print!("|");
                         for (field_name, length) in field_lengths.iter() {
-                            print!(" ");
-                            for _ in 0..(length - field_name.len() as u32) {
-                                print!(" ");
-                            }
-                            print!("{} |", field_name);
-
-                            total_length += 3 + length;
+                            print!(" {} |", pad(field_name, *length));
                         }
                         println!("");
 
-                        //print separating line
-                        for _ in 0..total_length {
-                            print!("-");
+                        let total_length = field_lengths.values().fold(1, |total, length| total + 3 + length);
+                        println!("{}", (0..total_length).map(|_| "-").collect::<String>());
+                    }
+
+                    //print out entities
+                    for fields in entities.iter() {
+                        print!("|");
+                        for (field_name, length) in field_lengths.iter() {
+                            print!(" {} |", pad(fields.get(field_name).map(|x| &x[..]).unwrap_or(""), *length));
                         }
                         println!("");
+                    }
+                }
 
-                        //print out entities
-                        for entity in entities.iter() {
-                            print!("|");
-                            for (field_name, length) in field_lengths.iter() {
-                                let fields = entity.get_fields().unwrap();
-                                
-                                for field in fields.iter() {
-                                    let name = field.get_name().unwrap();
-                                    if &name != field_name {
-                                        continue;
-                                    }
-
-                                    let value = field.get_value().unwrap();
-                                    print!(" ");
-                                    for _ in 0..(length - value.len() as u32) {
-                                        print!(" ");
-                                    }
-                                    print!("{} |", value);
-                                }
-                            }
-                            println!("");
+                if let Some((entity_count, server_duration)) = results.totals() {
+                    let duration = (time::precise_time_ns() - start_time) / 1000000;
+                    println!("entities returned {} in {}ms ({}ms on server)", entity_count, duration, server_duration);
+                }
+            },
+            Stats => {
+                match client.stats() {
+                    Ok(node_stats) => {
+                        println!("| {:>20} | {:>12} | {:>12} | {:>12} | {:>12} |", "token", "cache_hits", "cache_misses", "cache_count", "cache_bytes");
+                        println!("{}", (0..91).map(|_| "-").collect::<String>());
+                        for stats in node_stats.iter() {
+                            println!("| {:>20} | {:>12} | {:>12} | {:>12} | {:>12} |", stats.token, stats.cache_hits, stats.cache_misses, stats.cache_entries, stats.cache_bytes);
                         }
                     },
-                    Ok(_) => panic!("Unknown message type"),
-                    Err(capnp::NotInSchema(e)) => panic!("Error capnp::NotInSchema: {}", e),
+                    Err(e) => println!("error: {}", e),
                 }
             },
         }
     }
 }
+
+//name a classification as it is written in match output
+fn classification_name(classification: Classification) -> &'static str {
+    match classification {
+        Classification::Match => "match",
+        Classification::Possible => "possible",
+        Classification::NonMatch => "non-match",
+    }
+}
+
+//right align a value by its display width rather than its length in bytes or chars
+fn pad(value: &str, width: usize) -> String {
+    let mut padded = String::new();
+    for _ in value.width()..width {
+        padded.push(' ');
+    }
+
+    padded.push_str(value);
+    padded
+}
@@ -0,0 +1,361 @@
+extern crate argparse;
+use argparse::{ArgumentParser,Store,StoreTrue};
+
+extern crate fuzzydb;
+use fuzzydb::auth::Credentials;
+use fuzzydb::client::{Client,Error};
+use fuzzydb::parser::Command::Query;
+use fuzzydb::parser::Filter;
+use fuzzydb::pool::ThreadPool;
+use fuzzydb::transport::Transport;
+
+extern crate nom;
+
+extern crate num_cpus;
+
+extern crate openssl;
+use openssl::base64;
+
+#[macro_use]
+extern crate serde_json;
+use serde_json::{Map,Value};
+
+extern crate tiny_http;
+use tiny_http::{Header,Method,Request,Response,Server};
+
+use std::io::Read;
+use std::net::SocketAddr;
+use std::process;
+use std::sync::Arc;
+
+fn main() {
+    let mut host_ip: String = "127.0.0.1".to_string();
+    let mut host_port: u16 = 0;
+    let mut listen_ip: String = "127.0.0.1".to_string();
+    let mut http_port: u16 = 0;
+    let mut http_threads: usize = 0;
+    let mut tls_ca_file: String = "".to_string();
+    let mut tls_cert_file: String = "".to_string();
+    let mut tls_key_file: String = "".to_string();
+    let mut allow_insecure_auth = false;
+    let mut behind_tls_proxy = false;
+    {    //solely to limit scope of parser variable
+        let mut parser = ArgumentParser::new();
+        parser.set_description("start an http json gateway to a fuzzydb cluster");
+        parser.refer(&mut host_ip).add_option(&["-i", "--host-ip"], Store, "Hostname or ip address of the host to connect to").required();
+        parser.refer(&mut host_port).add_option(&["-p", "--host-port"], Store, "Port of the host to connect to").required();
+        parser.refer(&mut listen_ip).add_option(&["-l", "--listen-ip"], Store, "Ip address for the http server to listen on");
+        parser.refer(&mut http_port).add_option(&["-a", "--http-port"], Store, "Port for the http server to listen on").required();
+        parser.refer(&mut http_threads).add_option(&["--http-threads"], Store, "Number of threads serving http requests, defaults to the number of cpus");
+        parser.refer(&mut tls_ca_file).add_option(&["--tls-ca"], Store, "Pem file of the certificate authorities trusted to sign the host certificate, enables tls");
+        parser.refer(&mut tls_cert_file).add_option(&["--tls-cert"], Store, "Pem certificate chain file presented to hosts requiring mutual tls");
+        parser.refer(&mut tls_key_file).add_option(&["--tls-key"], Store, "Pem private key file of the tls certificate");
+        parser.refer(&mut allow_insecure_auth).add_option(&["--allow-insecure-auth"], StoreTrue, "Send credentials to the host without tls, for networks that are otherwise protected");
+        parser.refer(&mut behind_tls_proxy).add_option(&["--behind-tls-proxy"], StoreTrue, "Accept authorization headers over plain http, for gateways only reached through a tls terminating proxy");
+        parser.parse_args_or_exit();
+    }
+
+    //resolve the host addresses, which are tried in turn until one connects
+    let host_addrs = match fuzzydb::transport::resolve(&host_ip[..], host_port) {
+        Ok(socket_addrs) => Arc::new(socket_addrs),
+        Err(e) => exit_with_error(&e),
+    };
+
+    //create transport
+    let mut transport = match tls_ca_file.len() != 0 || tls_cert_file.len() != 0 {
+        false => Transport::plain(),
+        true => {
+            let ca_file = if tls_ca_file.len() == 0 { None } else { Some(&tls_ca_file[..]) };
+            let identity = if tls_cert_file.len() != 0 && tls_key_file.len() != 0 { Some((&tls_cert_file[..], &tls_key_file[..])) } else { None };
+            match Transport::client(ca_file, identity) {
+                Ok(transport) => transport,
+                Err(e) => exit_with_error(&e),
+            }
+        },
+    };
+    transport.set_allow_insecure_auth(allow_insecure_auth);
+    let transport = Arc::new(transport);
+
+    let server = match Server::http((&listen_ip[..], http_port)) {
+        Ok(server) => server,
+        Err(e) => exit_with_error(&format!("unable to listen on {}:{}: {}", listen_ip, http_port, e)),
+    };
+
+    //serve requests on a fixed number of threads, queueing them while every thread is busy
+    let pool = ThreadPool::new(if http_threads == 0 { num_cpus::get() } else { http_threads });
+    for request in server.incoming_requests() {
+        let (host_addrs, transport) = (host_addrs.clone(), transport.clone());
+        pool.execute(move || {
+            handle_request(request, &host_addrs, transport, behind_tls_proxy);
+        });
+    }
+}
+
+//prints the error and exits with a failure status
+fn exit_with_error(error: &str) -> ! {
+    eprintln!("{}", error);
+    process::exit(1);
+}
+
+enum Endpoint {
+    Insert,
+    Query,
+    Status,
+}
+
+fn handle_request(mut request: Request, host_addrs: &[SocketAddr], transport: Arc<Transport>, behind_tls_proxy: bool) {
+    let result = serve_request(&mut request, host_addrs, transport, behind_tls_proxy);
+    let (status_code, value) = match result {
+        Ok(value) => (200, value),
+        Err((status_code, error)) => (status_code, json!({ "error": error })),
+    };
+
+    let header = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+    let response = Response::from_string(value.to_string()).with_status_code(status_code).with_header(header);
+    request.respond(response).ok();
+}
+
+//routes and parses the request before connecting, so invalid requests never open a connection to the cluster
+fn serve_request(request: &mut Request, host_addrs: &[SocketAddr], transport: Arc<Transport>, behind_tls_proxy: bool) -> Result<Value,(u16,String)> {
+    //strip query parameters from the path
+    let path = request.url().split('?').next().unwrap_or("").to_string();
+    let endpoint = match (request.method().clone(), &path[..]) {
+        (Method::Post, "/insert") => Endpoint::Insert,
+        (Method::Post, "/query") => Endpoint::Query,
+        (Method::Get, "/status") => Endpoint::Status,
+        (_, "/insert") | (_, "/query") | (_, "/status") => return Err((405, "method not allowed".to_string())),
+        _ => return Err((404, "not found".to_string())),
+    };
+
+    let mut body = String::new();
+    if let Err(e) = request.as_reader().read_to_string(&mut body) {
+        return Err((400, format!("failed to read request body: {}", e)));
+    }
+
+    let value = match endpoint {
+        Endpoint::Insert | Endpoint::Query => try!(parse_json(&body)),
+        Endpoint::Status => Value::Null,
+    };
+
+    //connect to the cluster as the caller, so every request is limited to the role of its own credentials
+    let credentials = try!(get_credentials(request, behind_tls_proxy));
+    let client = try!(Client::connect(host_addrs, transport, credentials).map_err(get_status));
+    match endpoint {
+        Endpoint::Insert => insert(&value, &client),
+        Endpoint::Query => query(&value, &client),
+        Endpoint::Status => status(&client),
+    }
+}
+
+//reads the credentials of the caller from a basic or bearer authorization header, connecting anonymously without one.
+//the gateway only speaks plain http, so credentials are refused unless a tls terminating proxy sits in front of it
+fn get_credentials(request: &Request, behind_tls_proxy: bool) -> Result<Credentials,(u16,String)> {
+    let authorization = match request.headers().iter().find(|header| header.field.equiv("Authorization")) {
+        Some(_) if !behind_tls_proxy => return Err((403, "authorization headers are refused over plain http, pass --behind-tls-proxy when a tls proxy fronts the gateway".to_string())),
+        Some(header) => header.value.as_str().trim().to_string(),
+        None => return Ok(Credentials::Anonymous),
+    };
+
+    let mut parts = authorization.splitn(2, ' ');
+    match (parts.next(), parts.next().map(|x| x.trim())) {
+        (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => Ok(Credentials::Token(token.to_string())),
+        (Some(scheme), Some(encoded)) if scheme.eq_ignore_ascii_case("basic") => {
+            let decoded = try!(base64::decode_block(encoded).ok()
+                .and_then(|bytes| String::from_utf8(bytes).ok())
+                .ok_or((401, "invalid basic authorization header".to_string())));
+
+            match decoded.find(':') {
+                Some(index) => Ok(Credentials::Password(decoded[..index].to_string(), decoded[index+1..].to_string())),
+                None => Err((401, "invalid basic authorization header".to_string())),
+            }
+        },
+        _ => Err((401, "unsupported authorization scheme, expected basic or bearer".to_string())),
+    }
+}
+
+//returns the http status and message reported for a client error
+fn get_status(error: Error) -> (u16,String) {
+    match error {
+        Error::Auth(_) => (401, error.to_string()),
+        Error::Query(_) => (400, error.to_string()),
+        Error::Io(_) | Error::Protocol(_) | Error::Server(_) => (502, error.to_string()),
+    }
+}
+
+fn parse_json(body: &str) -> Result<Value,(u16,String)> {
+    serde_json::from_str(body).map_err(|e| (400, format!("invalid json: {}", e)))
+}
+
+//inserts the entities of an array of objects, or of the 'entities' field of an object, mapping field names to values
+fn insert(value: &Value, client: &Client) -> Result<Value,(u16,String)> {
+    let entities_value = match value.get("entities") {
+        Some(entities_value) => entities_value,
+        None => value,
+    };
+
+    let entities_array = match entities_value.as_array() {
+        Some(entities_array) => entities_array,
+        None => return Err((400, "expected an array of entities".to_string())),
+    };
+
+    let mut entities = vec!();
+    for entity_value in entities_array.iter() {
+        let entity_object = match entity_value.as_object() {
+            Some(entity_object) => entity_object,
+            None => return Err((400, "expected each entity to be an object".to_string())),
+        };
+
+        let mut entity = vec!();
+        for (name, value) in entity_object.iter() {
+            match *value {
+                Value::Null => continue,
+                Value::String(ref value) => entity.push((name.clone(), value.clone())),
+                Value::Array(_) | Value::Object(_) => return Err((400, format!("field '{}' must have a scalar value", name))),
+                _ => entity.push((name.clone(), value.to_string())),
+            }
+        }
+
+        entities.push(entity);
+    }
+
+    //insert entities in batches
+    let entity_count = try!(client.insert_entities(entities).map_err(get_status));
+    Ok(json!({ "inserted": entity_count }))
+}
+
+//queries with either the text of a select command in the 'query' field or the 'fields' and 'filters' fields
+fn query(value: &Value, client: &Client) -> Result<Value,(u16,String)> {
+    let (field_names, filters) = match value.get("query") {
+        Some(query_value) => {
+            let query = match query_value.as_str() {
+                Some(query) => query.trim(),
+                None => return Err((400, "expected 'query' to be a string".to_string())),
+            };
+
+            match fuzzydb::parser::cmd(query.as_bytes()) {
+                nom::IResult::Done(bytes, Query(field_names, filters)) => {
+                    if bytes.len() != 0 {
+                        return Err((400, "invalid query".to_string()));
+                    }
+
+                    (field_names, filters)
+                },
+                nom::IResult::Done(_, _) => return Err((400, "only select queries are supported".to_string())),
+                _ => return Err((400, "invalid query".to_string())),
+            }
+        },
+        None => try!(parse_structured_query(value).map_err(|e| (400, e))),
+    };
+
+    //collect the streamed entities until the trailer arrives
+    let mut results = try!(client.select(&field_names, &filters).map_err(get_status));
+    let mut entities = vec!();
+    for chunk in results.by_ref() {
+        for entity in try!(chunk.map_err(get_status)) {
+            let mut entity_object = Map::new();
+            for (name, value) in entity {
+                entity_object.insert(name, Value::String(value));
+            }
+
+            entities.push(Value::Object(entity_object));
+        }
+    }
+
+    let (entity_count, duration) = results.totals().unwrap_or((entities.len() as u64, 0));
+    Ok(json!({
+        "matched": results.matched(),
+        "count": entity_count,
+        "duration_ms": duration,
+        "entities": entities,
+    }))
+}
+
+//parses a query of the form below, where the filters are combined with AND
+//just as in the query text, and an empty or missing field list selects every
+//field
+//
+//{ "fields": ["first_name"], "filters": [{ "fields": ["last_name"], "type": "levenshtein", "params": ["1"], "value": "rammer", "tokens": false, "ignore_accents": false }] }
+fn parse_structured_query(value: &Value) -> Result<(Vec<String>,Vec<Filter>),String> {
+    let field_names = match value.get("fields") {
+        Some(fields_value) => try!(parse_strings(fields_value, "fields")),
+        None => vec!(),
+    };
+
+    let filters_array = match value.get("filters").and_then(|x| x.as_array()) {
+        Some(filters_array) if !filters_array.is_empty() => filters_array,
+        _ => return Err("expected a 'query' string or a non empty 'filters' array".to_string()),
+    };
+
+    let mut filters = vec!();
+    for filter_value in filters_array.iter() {
+        let filter_type = match filter_value.get("type").and_then(|x| x.as_str()) {
+            Some(filter_type) => filter_type.to_string(),
+            None => return Err("expected each filter to have a 'type' string".to_string()),
+        };
+
+        let filter_value_str = match filter_value.get("value") {
+            Some(&Value::String(ref value)) => value.clone(),
+            Some(value) if !value.is_null() && !value.is_array() && !value.is_object() => value.to_string(),
+            _ => return Err("expected each filter to have a scalar 'value'".to_string()),
+        };
+
+        //a single field may be given as 'field', no fields applies the filter to every field
+        let field_names = match (filter_value.get("field").and_then(|x| x.as_str()), filter_value.get("fields")) {
+            (Some(field_name), _) => vec!(field_name.to_string()),
+            (None, Some(fields_value)) => try!(parse_strings(fields_value, "fields")),
+            (None, None) => vec!(),
+        };
+
+        let params = match filter_value.get("params") {
+            Some(params_value) => try!(parse_strings(params_value, "params")),
+            None => vec!(),
+        };
+
+        filters.push(Filter {
+            field_names: field_names,
+            filter_type: filter_type,
+            params: params,
+            value: filter_value_str,
+            match_tokens: filter_value.get("tokens").and_then(|x| x.as_bool()).unwrap_or(false),
+            ignore_accents: filter_value.get("ignore_accents").and_then(|x| x.as_bool()).unwrap_or(false),
+        });
+    }
+
+    Ok((field_names, filters))
+}
+
+//parses an array of scalars as strings, so that numeric params may be given as numbers
+fn parse_strings(value: &Value, name: &str) -> Result<Vec<String>,String> {
+    let array = match value.as_array() {
+        Some(array) => array,
+        None => return Err(format!("expected '{}' to be an array", name)),
+    };
+
+    let mut strings = vec!();
+    for value in array.iter() {
+        match *value {
+            Value::String(ref value) => strings.push(value.clone()),
+            Value::Number(_) | Value::Bool(_) => strings.push(value.to_string()),
+            _ => return Err(format!("expected '{}' to hold strings", name)),
+        }
+    }
+
+    Ok(strings)
+}
+
+fn status(client: &Client) -> Result<Value,(u16,String)> {
+    let node_stats = try!(client.stats().map_err(get_status));
+
+    let mut nodes = vec!();
+    for stats in node_stats.iter() {
+        nodes.push(json!({
+            "token": stats.token,
+            "cache_hits": stats.cache_hits,
+            "cache_misses": stats.cache_misses,
+            "cache_entries": stats.cache_entries,
+            "cache_bytes": stats.cache_bytes,
+        }));
+    }
+
+    Ok(json!({ "nodes": nodes }))
+}
@@ -0,0 +1,663 @@
+extern crate argparse;
+use argparse::{ArgumentParser,Store,StoreTrue};
+
+extern crate fuzzydb;
+use fuzzydb::auth::Credentials;
+use fuzzydb::client::{Client,Error};
+use fuzzydb::parser::Command::{Aggregate,Query};
+use fuzzydb::parser::Filter;
+use fuzzydb::pool::ThreadPool;
+use fuzzydb::transport::{Stream,Transport};
+
+extern crate nom;
+
+use std::collections::{BTreeSet,HashMap};
+use std::io;
+use std::io::{Read,Write};
+use std::net::{SocketAddr,TcpListener,TcpStream};
+use std::process;
+use std::sync::Arc;
+
+//startup codes sent in place of a protocol version
+static PROTOCOL_VERSION_3: i32 = 196608;
+static SSL_REQUEST_CODE: i32 = 80877103;
+static CANCEL_REQUEST_CODE: i32 = 80877102;
+
+//largest message body read from a client
+static MAX_MESSAGE_LENGTH: usize = 1 << 20;
+
+//postgres type oids of the columns returned
+static TEXT_OID: i32 = 25;
+static INT8_OID: i32 = 20;
+
+fn main() {
+    let mut host_ip: String = "127.0.0.1".to_string();
+    let mut host_port: u16 = 0;
+    let mut listen_ip: String = "127.0.0.1".to_string();
+    let mut listen_port: u16 = 5432;
+    let mut ssl_cert_file: String = "".to_string();
+    let mut ssl_key_file: String = "".to_string();
+    let mut tls_ca_file: String = "".to_string();
+    let mut tls_cert_file: String = "".to_string();
+    let mut tls_key_file: String = "".to_string();
+    let mut allow_insecure_auth = false;
+    let mut session_threads: usize = 64;
+    {    //solely to limit scope of parser variable
+        let mut parser = ArgumentParser::new();
+        parser.set_description("start a postgres wire protocol frontend to a fuzzydb cluster");
+        parser.refer(&mut host_ip).add_option(&["-i", "--host-ip"], Store, "Hostname or ip address of the host to connect to").required();
+        parser.refer(&mut host_port).add_option(&["-p", "--host-port"], Store, "Port of the host to connect to").required();
+        parser.refer(&mut listen_ip).add_option(&["-l", "--listen-ip"], Store, "Ip address for the frontend to listen on");
+        parser.refer(&mut listen_port).add_option(&["-a", "--listen-port"], Store, "Port for the frontend to listen on (defaults to 5432)");
+        parser.refer(&mut ssl_cert_file).add_option(&["--ssl-cert"], Store, "Pem certificate chain file presented to postgres clients, enables ssl and password authentication");
+        parser.refer(&mut ssl_key_file).add_option(&["--ssl-key"], Store, "Pem private key file of the ssl certificate");
+        parser.refer(&mut tls_ca_file).add_option(&["--tls-ca"], Store, "Pem file of the certificate authorities trusted to sign the host certificate, enables tls");
+        parser.refer(&mut tls_cert_file).add_option(&["--tls-cert"], Store, "Pem certificate chain file presented to hosts requiring mutual tls");
+        parser.refer(&mut tls_key_file).add_option(&["--tls-key"], Store, "Pem private key file of the tls certificate");
+        parser.refer(&mut allow_insecure_auth).add_option(&["--allow-insecure-auth"], StoreTrue, "Send credentials to the host without tls, for networks that are otherwise protected");
+        parser.refer(&mut session_threads).add_option(&["--session-threads"], Store, "Number of threads serving postgres sessions (defaults to 64)");
+        parser.parse_args_or_exit();
+    }
+
+    //resolve the host addresses, which are tried in turn until one connects
+    let host_addrs = match fuzzydb::transport::resolve(&host_ip[..], host_port) {
+        Ok(socket_addrs) => Arc::new(socket_addrs),
+        Err(e) => exit_with_error(&e),
+    };
+
+    //create transport used to connect to the host
+    let mut transport = match tls_ca_file.len() != 0 || tls_cert_file.len() != 0 {
+        false => Transport::plain(),
+        true => {
+            let ca_file = if tls_ca_file.len() == 0 { None } else { Some(&tls_ca_file[..]) };
+            let identity = if tls_cert_file.len() != 0 && tls_key_file.len() != 0 { Some((&tls_cert_file[..], &tls_key_file[..])) } else { None };
+            match Transport::client(ca_file, identity) {
+                Ok(transport) => transport,
+                Err(e) => exit_with_error(&e),
+            }
+        },
+    };
+
+    //create transport used to accept ssl from postgres clients
+    let ssl_transport = match (ssl_cert_file.len(), ssl_key_file.len()) {
+        (0, 0) => None,
+        (0, _) | (_, 0) => exit_with_error("ssl requires both --ssl-cert and --ssl-key"),
+        _ => match Transport::server(&ssl_cert_file[..], &ssl_key_file[..], None, false) {
+            Ok(ssl_transport) => Some(ssl_transport),
+            Err(e) => exit_with_error(&e),
+        },
+    };
+    transport.set_allow_insecure_auth(allow_insecure_auth);
+    let (transport, ssl_transport) = (Arc::new(transport), Arc::new(ssl_transport));
+
+    if session_threads == 0 {
+        exit_with_error("--session-threads must be at least 1");
+    }
+
+    //serve sessions on a fixed number of threads, queueing connections while every thread is busy
+    let listener = match TcpListener::bind((&listen_ip[..], listen_port)) {
+        Ok(listener) => listener,
+        Err(e) => exit_with_error(&format!("unable to listen on {}:{}: {}", listen_ip, listen_port, e)),
+    };
+
+    let pool = ThreadPool::new(session_threads);
+    for (i, stream) in listener.incoming().enumerate() {
+        let (host_addrs, transport, ssl_transport) = (host_addrs.clone(), transport.clone(), ssl_transport.clone());
+        pool.execute(move || {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => return,
+            };
+
+            if let Ok(Some((mut stream, parameters))) = start_session(stream, &ssl_transport) {
+                if let Err(e) = serve_session(&mut stream, parameters, i as i32, &host_addrs, &transport) {
+                    let _ = write_error(&mut stream, "08006", &format!("{}", e));
+                }
+            }
+        });
+    }
+}
+
+//prints the error and exits with a failure status
+fn exit_with_error(error: &str) -> ! {
+    eprintln!("{}", error);
+    process::exit(1);
+}
+
+//reads the startup message, switching the connection to ssl when the client asks and the frontend has a certificate
+fn start_session(stream: TcpStream, ssl_transport: &Option<Transport>) -> io::Result<Option<(Stream,HashMap<String,String>)>> {
+    let mut stream = Stream::Plain(stream);
+    loop {
+        let length = try!(read_i32(&mut stream));
+        let mut body = vec!(0; try!(body_length(length)));
+        try!(stream.read_exact(&mut body));
+        if body.len() < 4 {
+            return Ok(None);
+        }
+
+        let code = get_i32(&body[0..4]);
+        if code == SSL_REQUEST_CODE {
+            stream = match (stream, ssl_transport) {
+                (Stream::Plain(mut tcp_stream), &Some(ref ssl_transport)) => {
+                    try!(tcp_stream.write_all(b"S"));
+                    match ssl_transport.accept(tcp_stream) {
+                        Ok(stream) => stream,
+                        Err(_) => return Ok(None),
+                    }
+                },
+                (mut stream, _) => {
+                    try!(stream.write_all(b"N"));
+                    stream
+                },
+            };
+
+            continue;
+        } else if code == CANCEL_REQUEST_CODE {
+            return Ok(None);
+        } else if code != PROTOCOL_VERSION_3 {
+            try!(write_error(&mut stream, "0A000", &format!("unsupported frontend protocol {}", code)));
+            return Ok(None);
+        }
+
+        return Ok(Some((stream, parse_parameters(&body[4..]))));
+    }
+}
+
+fn serve_session(stream: &mut Stream, parameters: HashMap<String,String>, process_id: i32, host_addrs: &[SocketAddr], transport: &Arc<Transport>) -> io::Result<()> {
+    //pass the postgres user and password through to the host, passwords are only asked for over ssl
+    let ssl = match *stream {
+        Stream::Tls(_) => true,
+        Stream::Plain(_) => false,
+    };
+
+    let credentials = match ssl {
+        true => {
+            try!(write_message(stream, b'R', &put_i32(vec!(), 3)));
+            let (message_type, body) = try!(read_message(stream));
+            if message_type != b'p' {
+                return write_error(stream, "28000", "expected a password message");
+            }
+
+            let password = String::from_utf8_lossy(&body).trim_right_matches('\0').to_string();
+            match parameters.get("user") {
+                Some(user) if !user.is_empty() => Credentials::Password(user.clone(), password),
+                _ => Credentials::Anonymous,
+            }
+        },
+        false => Credentials::Anonymous,
+    };
+
+    //check the host accepts the credentials before the session starts
+    let client = match Client::connect(host_addrs, transport.clone(), credentials) {
+        Ok(client) => client,
+        Err(e) => match ssl {
+            true => return write_error(stream, "28P01", &e.to_string()),
+            false => return write_error(stream, "28000", &format!("{}, password authentication requires an ssl connection", e)),
+        },
+    };
+
+    try!(write_message(stream, b'R', &put_i32(vec!(), 0)));
+    for &(name, value) in [("server_version", "9.6.0"), ("server_encoding", "UTF8"), ("client_encoding", "UTF8"),
+            ("DateStyle", "ISO, MDY"), ("integer_datetimes", "on"), ("standard_conforming_strings", "on")].iter() {
+        try!(write_message(stream, b'S', &put_cstr(put_cstr(vec!(), name), value)));
+    }
+
+    try!(write_message(stream, b'K', &put_i32(put_i32(vec!(), process_id), 0)));
+    try!(write_message(stream, b'Z', b"I"));
+
+    //serve simple queries until the client terminates
+    let mut extended_error = false;
+    loop {
+        let (message_type, body) = try!(read_message(stream));
+        match message_type {
+            b'Q' => {
+                let query = String::from_utf8_lossy(&body).trim_right_matches('\0').to_string();
+                try!(execute(stream, &query, &client));
+                try!(write_message(stream, b'Z', b"I"));
+            },
+            b'X' => return Ok(()),
+            b'S' => {
+                //the end of an extended query, which is rejected once per sync
+                extended_error = false;
+                try!(write_message(stream, b'Z', b"I"));
+            },
+            _ => {
+                if !extended_error {
+                    try!(write_error(stream, "0A000", "only the simple query protocol is supported"));
+                    extended_error = true;
+                }
+            },
+        }
+    }
+}
+
+//executes each statement of a simple query, stopping at the first error
+fn execute(stream: &mut Stream, query: &str, client: &Client) -> io::Result<()> {
+    let statements: Vec<&str> = split_statements(query).into_iter().map(|x| x.trim()).filter(|x| !x.is_empty()).collect();
+    if statements.is_empty() {
+        return write_message(stream, b'I', &[]);
+    }
+
+    for statement in statements {
+        //drivers configure sessions on connect, accept those settings without applying them
+        if statement.to_lowercase().starts_with("set ") {
+            try!(write_message(stream, b'C', &put_cstr(vec!(), "SET")));
+            continue;
+        }
+
+        let cmd = match fuzzydb::parser::cmd(statement.as_bytes()) {
+            nom::IResult::Done(bytes, cmd) => if bytes.len() == 0 { Some(cmd) } else { None },
+            _ => None,
+        };
+
+        let result = match cmd {
+            Some(Query(field_names, filters)) => query_entities(field_names, filters, client),
+            Some(Aggregate(field_names, filters, group_by)) => query_group_counts(field_names, filters, group_by, client),
+            Some(_) => Err(("0A000", "only SELECT statements are supported".to_string())),
+            None => Err(("42601", format!("syntax error in '{}'", statement))),
+        };
+
+        let (columns, rows) = match result {
+            Ok(result_set) => result_set,
+            Err((code, message)) => return write_error(stream, code, &message),
+        };
+
+        //send row description, each row and the command tag
+        let mut body = put_i16(vec!(), columns.len() as i16);
+        for &(ref name, type_oid) in columns.iter() {
+            body = put_cstr(body, name);
+            body = put_i32(body, 0);
+            body = put_i16(body, 0);
+            body = put_i32(body, type_oid);
+            body = put_i16(body, if type_oid == INT8_OID { 8 } else { -1 });
+            body = put_i32(body, -1);
+            body = put_i16(body, 0);
+        }
+        try!(write_message(stream, b'T', &body));
+
+        for row in rows.iter() {
+            let mut body = put_i16(vec!(), row.len() as i16);
+            for value in row.iter() {
+                match *value {
+                    Some(ref value) => {
+                        body = put_i32(body, value.len() as i32);
+                        body.extend_from_slice(value.as_bytes());
+                    },
+                    None => body = put_i32(body, -1),
+                }
+            }
+            try!(write_message(stream, b'D', &body));
+        }
+
+        try!(write_message(stream, b'C', &put_cstr(vec!(), &format!("SELECT {}", rows.len()))));
+    }
+
+    Ok(())
+}
+
+//splits a query on the semicolons outside of quoted values and identifiers
+fn split_statements(query: &str) -> Vec<&str> {
+    let (mut statements, mut start) = (vec!(), 0);
+    let (mut quote, mut escaped) = (None, false);
+    for (i, c) in query.char_indices() {
+        match quote {
+            //double quoted values may escape their quote, a doubled single quote reopens the value
+            Some(quote_char) => {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' && quote_char == '"' {
+                    escaped = true;
+                } else if c == quote_char {
+                    quote = None;
+                }
+            },
+            None => match c {
+                '\'' | '"' => quote = Some(c),
+                ';' => {
+                    statements.push(&query[start..i]);
+                    start = i + 1;
+                },
+                _ => {},
+            },
+        }
+    }
+
+    statements.push(&query[start..]);
+    statements
+}
+
+type ResultSet = (Vec<(String,i32)>, Vec<Vec<Option<String>>>);
+
+//returns text columns for the selected fields, or every field of the returned entities for '*'
+fn query_entities(field_names: Vec<String>, filters: Vec<Filter>, client: &Client) -> Result<ResultSet,(&'static str,String)> {
+    //send query message and collect the streamed entities until the trailer arrives
+    let mut entities = vec!();
+    for chunk in try!(client.select(&field_names, &filters).map_err(get_sqlstate)) {
+        for entity in try!(chunk.map_err(get_sqlstate)) {
+            entities.push(entity.into_iter().collect::<HashMap<String,String>>());
+        }
+    }
+
+    //columns keep the selected order, or are sorted when every field is selected
+    let column_names: Vec<String> = match field_names.len() {
+        0 => entities.iter().flat_map(|x| x.keys().cloned()).collect::<BTreeSet<String>>().into_iter().collect(),
+        _ => field_names,
+    };
+
+    let rows = entities.iter().map(|entity| {
+        column_names.iter().map(|x| entity.get(x).cloned()).collect()
+    }).collect();
+
+    Ok((column_names.into_iter().map(|x| (x, TEXT_OID)).collect(), rows))
+}
+
+//returns a text column for each group by field followed by a bigint count column
+fn query_group_counts(field_names: Vec<String>, filters: Vec<Filter>, group_by: Vec<String>, client: &Client) -> Result<ResultSet,(&'static str,String)> {
+    //send aggregate message and read group counts
+    let group_counts = try!(client.aggregate(&field_names, &filters, &group_by).map_err(get_sqlstate));
+
+    let mut rows = vec!();
+    for (values, count) in group_counts {
+        let mut row: Vec<Option<String>> = values.into_iter().map(|x| Some(x)).collect();
+        row.push(Some(format!("{}", count)));
+        rows.push(row);
+    }
+
+    let mut columns: Vec<(String,i32)> = group_by.into_iter().map(|x| (x, TEXT_OID)).collect();
+    columns.push(("count".to_string(), INT8_OID));
+    Ok((columns, rows))
+}
+
+//returns the sqlstate and message reported for a client error
+fn get_sqlstate(error: Error) -> (&'static str,String) {
+    match error {
+        Error::Io(_) => ("08006", error.to_string()),
+        Error::Auth(_) => ("28000", error.to_string()),
+        Error::Query(_) => ("42803", error.to_string()),
+        Error::Protocol(_) | Error::Server(_) => ("XX000", error.to_string()),
+    }
+}
+
+fn parse_parameters(body: &[u8]) -> HashMap<String,String> {
+    let strings: Vec<String> = body.split(|x| *x == 0).map(|x| String::from_utf8_lossy(x).to_string()).collect();
+    let mut parameters = HashMap::new();
+    for pair in strings.chunks(2) {
+        if pair.len() == 2 && !pair[0].is_empty() {
+            parameters.insert(pair[0].clone(), pair[1].clone());
+        }
+    }
+
+    parameters
+}
+
+//every message after startup is a type byte followed by a length that includes itself
+fn read_message(stream: &mut Stream) -> io::Result<(u8,Vec<u8>)> {
+    let mut message_type = [0; 1];
+    try!(stream.read_exact(&mut message_type));
+
+    let length = try!(read_i32(stream));
+    let mut body = vec!(0; try!(body_length(length)));
+    try!(stream.read_exact(&mut body));
+    Ok((message_type[0], body))
+}
+
+fn write_message(stream: &mut Stream, message_type: u8, body: &[u8]) -> io::Result<()> {
+    let mut message = vec!(message_type);
+    message = put_i32(message, body.len() as i32 + 4);
+    message.extend_from_slice(body);
+    stream.write_all(&message)
+}
+
+fn write_error(stream: &mut Stream, code: &str, message: &str) -> io::Result<()> {
+    let mut body = vec!(b'S');
+    body = put_cstr(body, "ERROR");
+    body.push(b'V');
+    body = put_cstr(body, "ERROR");
+    body.push(b'C');
+    body = put_cstr(body, code);
+    body.push(b'M');
+    body = put_cstr(body, message);
+    body.push(0);
+    write_message(stream, b'E', &body)
+}
+
+fn read_i32(stream: &mut Stream) -> io::Result<i32> {
+    let mut bytes = [0; 4];
+    try!(stream.read_exact(&mut bytes));
+    Ok(get_i32(&bytes))
+}
+
+//returns the number of bytes following a length field that counts itself, refusing messages too large to buffer
+fn body_length(length: i32) -> io::Result<usize> {
+    if length < 4 {
+        Ok(0)
+    } else if length as usize - 4 > MAX_MESSAGE_LENGTH {
+        Err(io::Error::new(io::ErrorKind::InvalidData, format!("message of {} bytes exceeds the limit of {} bytes", length - 4, MAX_MESSAGE_LENGTH)))
+    } else {
+        Ok(length as usize - 4)
+    }
+}
+
+fn get_i32(bytes: &[u8]) -> i32 {
+    ((bytes[0] as i32) << 24) | ((bytes[1] as i32) << 16) | ((bytes[2] as i32) << 8) | (bytes[3] as i32)
+}
+
+fn put_i16(mut buf: Vec<u8>, value: i16) -> Vec<u8> {
+    buf.push((value >> 8) as u8);
+    buf.push(value as u8);
+    buf
+}
+
+fn put_i32(mut buf: Vec<u8>, value: i32) -> Vec<u8> {
+    buf.push((value >> 24) as u8);
+    buf.push((value >> 16) as u8);
+    buf.push((value >> 8) as u8);
+    buf.push(value as u8);
+    buf
+}
+
+fn put_cstr(mut buf: Vec<u8>, value: &str) -> Vec<u8> {
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(0);
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate openssl;
+
+    use super::*;
+
+    use self::openssl::ssl::{SslConnector,SslMethod};
+
+    use std::thread;
+
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    //runs the client against a session started on a local connection, returning whether the session uses ssl with its parameters and what the client read
+    fn start<F>(ssl_transport: Option<Transport>, client: F) -> (io::Result<Option<(bool,HashMap<String,String>)>>, Vec<u8>)
+            where F: FnOnce(TcpStream) -> Vec<u8> + Send + 'static {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket_addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || client(TcpStream::connect(socket_addr).unwrap()));
+
+        let (stream, _) = listener.accept().unwrap();
+        let session = start_session(stream, &ssl_transport).map(|x| x.map(|(stream, parameters)| {
+            match stream {
+                Stream::Plain(_) => (false, parameters),
+                Stream::Tls(_) => (true, parameters),
+            }
+        }));
+
+        (session, handle.join().unwrap())
+    }
+
+    //ssl and cancel requests are a length and code without parameters
+    fn request(code: i32) -> Vec<u8> {
+        put_i32(put_i32(vec!(), 8), code)
+    }
+
+    fn startup_message(code: i32, parameters: &[(&str,&str)]) -> Vec<u8> {
+        let mut body = put_i32(vec!(), code);
+        for &(name, value) in parameters.iter() {
+            body = put_cstr(put_cstr(body, name), value);
+        }
+        body.push(0);
+
+        let mut message = put_i32(vec!(), body.len() as i32 + 4);
+        message.extend_from_slice(&body);
+        message
+    }
+
+    fn user(name: &str) -> HashMap<String,String> {
+        let mut parameters = HashMap::new();
+        parameters.insert("user".to_string(), name.to_string());
+        parameters.insert("database".to_string(), "fuzzydb".to_string());
+        parameters
+    }
+
+    #[test]
+    fn split_on_semicolons() {
+        assert_eq!(split_statements("SELECT * WHERE a = b"), vec!("SELECT * WHERE a = b"));
+        assert_eq!(split_statements("SET a = b; SELECT *"), vec!("SET a = b", " SELECT *"));
+        assert_eq!(split_statements("SELECT *;"), vec!("SELECT *", ""));
+        assert_eq!(split_statements(";;"), vec!("", "", ""));
+        assert_eq!(split_statements(""), vec!(""));
+    }
+
+    #[test]
+    fn split_outside_quotes() {
+        assert_eq!(split_statements("SELECT * WHERE a = \"b;c\"; COUNT"), vec!("SELECT * WHERE a = \"b;c\"", " COUNT"));
+        assert_eq!(split_statements("SELECT * WHERE a = 'b;c'"), vec!("SELECT * WHERE a = 'b;c'"));
+        assert_eq!(split_statements("SELECT \"a;b\", 'c;d';"), vec!("SELECT \"a;b\", 'c;d'", ""));
+
+        //an unterminated quote runs to the end of the query
+        assert_eq!(split_statements("SELECT * WHERE a = \"b; COUNT"), vec!("SELECT * WHERE a = \"b; COUNT"));
+    }
+
+    #[test]
+    fn split_escaped_quotes() {
+        assert_eq!(split_statements("SELECT * WHERE a = \"b\\\";c\"; COUNT"), vec!("SELECT * WHERE a = \"b\\\";c\"", " COUNT"));
+        assert_eq!(split_statements("SELECT * WHERE a = \"b\\\\\"; COUNT"), vec!("SELECT * WHERE a = \"b\\\\\"", " COUNT"));
+        assert_eq!(split_statements("SELECT * WHERE a = 'it''s;'; COUNT"), vec!("SELECT * WHERE a = 'it''s;'", " COUNT"));
+
+        //backslashes do not escape single quotes
+        assert_eq!(split_statements("SELECT * WHERE a = 'b\\'; COUNT"), vec!("SELECT * WHERE a = 'b\\'", " COUNT"));
+    }
+
+    #[test]
+    fn plain_startup() {
+        let (session, reply) = start(None, |mut stream| {
+            stream.write_all(&startup_message(PROTOCOL_VERSION_3, &[("user", "bob"), ("database", "fuzzydb")])).unwrap();
+            vec!()
+        });
+
+        assert_eq!(session.unwrap(), Some((false, user("bob"))));
+        assert_eq!(reply, Vec::<u8>::new());
+    }
+
+    #[test]
+    fn ssl_refused_without_certificate() {
+        let (session, reply) = start(None, |mut stream| {
+            stream.write_all(&request(SSL_REQUEST_CODE)).unwrap();
+            let mut reply = [0; 1];
+            stream.read_exact(&mut reply).unwrap();
+            stream.write_all(&startup_message(PROTOCOL_VERSION_3, &[("user", "bob"), ("database", "fuzzydb")])).unwrap();
+            reply.to_vec()
+        });
+
+        assert_eq!(session.unwrap(), Some((false, user("bob"))));
+        assert_eq!(reply, b"N".to_vec());
+    }
+
+    #[test]
+    fn ssl_startup() {
+        let ssl_transport = Transport::server(&fixture("node.pem"), &fixture("node.key"), None, false).unwrap();
+        let (session, reply) = start(Some(ssl_transport), |mut stream| {
+            stream.write_all(&request(SSL_REQUEST_CODE)).unwrap();
+            let mut reply = [0; 1];
+            stream.read_exact(&mut reply).unwrap();
+
+            //the startup message follows the handshake inside the ssl connection
+            let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+            connector.set_ca_file(fixture("ca.pem")).unwrap();
+            let mut stream = connector.build().connect("127.0.0.1", stream).unwrap();
+            stream.write_all(&startup_message(PROTOCOL_VERSION_3, &[("user", "bob"), ("database", "fuzzydb")])).unwrap();
+            stream.flush().unwrap();
+
+            //wait for the session to close, leaving no unread session tickets to reset the connection
+            let _ = stream.read_to_end(&mut vec!());
+            reply.to_vec()
+        });
+
+        assert_eq!(session.unwrap(), Some((true, user("bob"))));
+        assert_eq!(reply, b"S".to_vec());
+    }
+
+    #[test]
+    fn failed_ssl_handshake() {
+        let ssl_transport = Transport::server(&fixture("node.pem"), &fixture("node.key"), None, false).unwrap();
+        let (session, reply) = start(Some(ssl_transport), |mut stream| {
+            stream.write_all(&request(SSL_REQUEST_CODE)).unwrap();
+            let mut reply = [0; 1];
+            stream.read_exact(&mut reply).unwrap();
+            stream.write_all(b"not a handshake").unwrap();
+            reply.to_vec()
+        });
+
+        assert_eq!(session.unwrap(), None);
+        assert_eq!(reply, b"S".to_vec());
+    }
+
+    #[test]
+    fn cancel_request() {
+        let (session, _) = start(None, |mut stream| {
+            stream.write_all(&request(CANCEL_REQUEST_CODE)).unwrap();
+            vec!()
+        });
+
+        assert_eq!(session.unwrap(), None);
+    }
+
+    #[test]
+    fn unsupported_protocol() {
+        let (session, reply) = start(None, |mut stream| {
+            stream.write_all(&startup_message(131072, &[("user", "bob")])).unwrap();
+            let mut reply = vec!();
+            stream.read_to_end(&mut reply).unwrap();
+            reply
+        });
+
+        //an error message carrying the feature not supported sqlstate
+        assert_eq!(session.unwrap(), None);
+        assert_eq!(reply[0], b'E');
+        assert_eq!(get_i32(&reply[1..5]) as usize, reply.len() - 1);
+        assert!(reply.windows(7).any(|x| x == b"C0A000\0"));
+    }
+
+    #[test]
+    fn oversized_startup() {
+        let (session, _) = start(None, |mut stream| {
+            stream.write_all(&put_i32(vec!(), MAX_MESSAGE_LENGTH as i32 + 5)).unwrap();
+            vec!()
+        });
+
+        assert_eq!(session.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn message_framing() {
+        assert_eq!(body_length(8).unwrap(), 4);
+        assert_eq!(body_length(4).unwrap(), 0);
+        assert_eq!(body_length(-1).unwrap(), 0);
+        assert!(body_length(MAX_MESSAGE_LENGTH as i32 + 5).is_err());
+
+        assert_eq!(put_i16(vec!(), -1), vec!(0xff, 0xff));
+        assert_eq!(put_i32(vec!(), SSL_REQUEST_CODE), vec!(0x04, 0xd2, 0x16, 0x2f));
+        assert_eq!(get_i32(&put_i32(vec!(), PROTOCOL_VERSION_3)), PROTOCOL_VERSION_3);
+        assert_eq!(put_cstr(vec!(b'S'), "user"), b"Suser\0".to_vec());
+        assert_eq!(parse_parameters(b"user\0bob\0database\0fuzzydb\0\0"), user("bob"));
+    }
+}
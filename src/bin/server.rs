@@ -4,20 +4,70 @@ use argparse::{ArgumentParser,Store,StoreTrue};
 extern crate capnp;
 
 extern crate fuzzydb;
+use fuzzydb::auth::{Authenticator,Credentials,Permission,Role};
+use fuzzydb::blocking::{BlockingKeys,CLUSTER_ID_FIELD};
+use fuzzydb::cache::FilterCache;
+use fuzzydb::connection::ConnectionPool;
 use fuzzydb::message_capnp;
-use fuzzydb::message_capnp::message::msg_type::{CloseWriteStreamMsg,InsertEntitiesMsg,EntityMsg,EntityKeysMsg,OpenWriteStreamMsg,QueryMsg,QueryEntityMsg,QueryFilterMsg,WriteEntityMsg,WriteFieldMsg};
+use fuzzydb::message_capnp::message::msg_type::{AggregateMsg,CloseWriteStreamMsg,DeduplicateMsg,DeduplicateNodeMsg,DuplicatesMsg,ErrorMsg,FieldValuesMsg,HelloMsg,InsertEntitiesMsg,EntitiesMsg,EntityKeysMsg,GroupCountsMsg,MatchMsg,OpenWriteStreamMsg,QueryMsg,QueryBlockValuesMsg,QueryEntitiesMsg,QueryEntityMsg,QueryFieldValuesMsg,QueryFilterMsg,QueryGroupCountsMsg,QueryNearestMsg,QueryNodeStatsMsg,QueryStatsMsg,ResultMsg,ScoredValuesMsg,StatsMsg,WriteEntityMsg,WriteFieldMsg};
+use fuzzydb::model::{Classification,Comparator,MatchModel,MatchModels};
+use fuzzydb::normalize::{Normalizer,Normalizers};
+use fuzzydb::pool::ThreadPool;
+use fuzzydb::query::Comparison;
+use fuzzydb::protocol;
+use fuzzydb::synonym::Synonyms;
+use fuzzydb::transport::{self,Stream,Transport};
+
+extern crate num_cpus;
 
 extern crate rustdht;
 use rustdht::event::Event;
 
-use std::collections::{BTreeMap,HashMap};
+extern crate time;
+
+use std::cmp::{self,Ordering};
+use std::collections::{BTreeMap,HashMap,HashSet};
 use std::hash::{Hash,Hasher,SipHasher};
 use std::io::{Read,Write};
-use std::net::{Ipv4Addr,SocketAddrV4,Shutdown,TcpListener,TcpStream};
-use std::str::FromStr;
+use std::net::{SocketAddr,SocketAddrV4,TcpListener,TcpStream};
+use std::process;
 use std::sync::{Arc,Mutex,RwLock};
-use std::sync::mpsc::{channel,Sender};
+use std::sync::atomic::{self,AtomicUsize};
+use std::sync::mpsc::{channel,sync_channel,Receiver,Sender};
 use std::thread;
+use std::time::Duration;
+
+//number of candidates returned by a match command without a limit
+static DEFAULT_MATCH_LIMIT: usize = 25;
+
+//entity keys or scored values returned by a single node for a filter
+enum NodeFilterResult {
+    EntityKeys(Vec<u64>),
+    ScoredValues(Vec<(f64,String,Vec<u64>)>),
+}
+
+//held by the thread of each open connection, the slot is released when the thread ends even if it panics
+struct ConnectionSlot {
+    open_connections: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.open_connections.fetch_sub(1, atomic::Ordering::SeqCst);
+    }
+}
+
+//number of nodes entities are fetched from at once for a single request
+static MAX_NODE_REQUESTS: usize = 8;
+
+//largest number of entities sent in a single entities message, to the client or between nodes
+static ENTITY_CHUNK_SIZE: usize = 500;
+
+//largest number of values sent to another node in a single field values message
+static FIELD_VALUE_CHUNK_SIZE: usize = 1000;
+
+//largest number of values of a compared field a deduplicate command without blocks compares every pair of
+static MAX_UNBLOCKED_VALUES: usize = 100000;
 
 pub fn main() {
     let mut token: u64 = 0;
@@ -26,38 +76,148 @@ pub fn main() {
     let mut service_port: u16 = 0;
     let mut seed_ip: String = "127.0.0.1".to_string();
     let mut seed_port: u16 = 0;
+    let mut query_threads: usize = 0;
+    let mut cache_size: usize = 64;
+    let mut normalizers_file: String = "".to_string();
+    let mut tokenized_fields: String = "".to_string();
+    let mut synonyms_dir: String = "".to_string();
+    let mut models_dir: String = "".to_string();
+    let mut blocking_keys_file: String = "".to_string();
+    let mut idle_connections: usize = 8;
+    let mut max_connections: usize = 512;
+    let mut request_threads: usize = 64;
+    let mut tls_cert_file: String = "".to_string();
+    let mut tls_key_file: String = "".to_string();
+    let mut tls_ca_file: String = "".to_string();
+    let mut tls_mutual = false;
+    let mut allow_insecure_auth = false;
+    let mut credentials_file: String = "".to_string();
+    let mut node_token: String = "".to_string();
     let mut debug = false;
     {    //solely to limit scope of parser variable
         let mut parser = ArgumentParser::new();
         parser.set_description("start an instance of fuzzydb server");
         parser.refer(&mut token).add_option(&["-t", "--token"], Store, "token of node").required();
-        parser.refer(&mut app_ip).add_option(&["-i", "--listen-ip"], Store, "ip address for application and service to listen on").required();
+        parser.refer(&mut app_ip).add_option(&["-i", "--listen-ip"], Store, "hostname or ip address for application and service to listen on").required();
         parser.refer(&mut app_port).add_option(&["-a", "--app-port"], Store, "port for application to listen on").required();
         parser.refer(&mut service_port).add_option(&["-p", "--service-port"], Store, "port for the p2p service listen on").required();
-        parser.refer(&mut seed_ip).add_option(&["-s", "--seed-ip"], Store, "p2p service seed node ip address");
+        parser.refer(&mut seed_ip).add_option(&["-s", "--seed-ip"], Store, "p2p service seed node hostname or ip address");
         parser.refer(&mut seed_port).add_option(&["-e", "--seed-port"], Store, "p2p service seed node port");
+        parser.refer(&mut query_threads).add_option(&["-q", "--query-threads"], Store, "number of threads used to evaluate filters (defaults to number of cpus)");
+        parser.refer(&mut cache_size).add_option(&["-c", "--cache-size"], Store, "memory budget of the filter result cache in megabytes (0 disables the cache)");
+        parser.refer(&mut normalizers_file).add_option(&["-n", "--normalizers"], Store, "file defining the normalization pipeline of each field (defaults to lowercase)");
+        parser.refer(&mut tokenized_fields).add_option(&["-k", "--tokenized-fields"], Store, "comma separated list of fields whose individual words are also indexed");
+        parser.refer(&mut synonyms_dir).add_option(&["-y", "--synonyms"], Store, "directory of csv synonym dictionaries used by synonym filters");
+        parser.refer(&mut models_dir).add_option(&["-m", "--models"], Store, "directory of record matching models used by match commands");
+        parser.refer(&mut blocking_keys_file).add_option(&["-b", "--blocking-keys"], Store, "file defining the blocking keys computed for every inserted entity");
+        parser.refer(&mut idle_connections).add_option(&["-l", "--idle-connections"], Store, "number of idle connections kept open to each peer");
+        parser.refer(&mut max_connections).add_option(&["--max-connections"], Store, "number of client and node connections served at once, further connections are refused");
+        parser.refer(&mut request_threads).add_option(&["--request-threads"], Store, "number of threads sending requests to other nodes");
+        parser.refer(&mut tls_cert_file).add_option(&["--tls-cert"], Store, "pem certificate chain file, enables tls on the application port and between nodes");
+        parser.refer(&mut tls_key_file).add_option(&["--tls-key"], Store, "pem private key file of the tls certificate");
+        parser.refer(&mut tls_ca_file).add_option(&["--tls-ca"], Store, "pem file of the certificate authorities trusted to sign peer certificates");
+        parser.refer(&mut tls_mutual).add_option(&["--tls-mutual"], StoreTrue, "require connecting clients and nodes to present a certificate signed by the tls ca");
+        parser.refer(&mut allow_insecure_auth).add_option(&["--allow-insecure-auth"], StoreTrue, "send and accept credentials and node tokens without tls, for networks that are otherwise protected");
+        parser.refer(&mut credentials_file).add_option(&["--credentials"], Store, "file of the accounts allowed to connect, enables authentication");
+        parser.refer(&mut node_token).add_option(&["--node-token"], Store, "token of a node account presented when connecting to other nodes, without credentials only nodes presenting it may send node messages");
         parser.refer(&mut debug).add_option(&["-d", "--debug"], StoreTrue, "print debug output");
         parser.parse_args_or_exit();
     }
 
-    //create application and service addresses
-    let ip = Ipv4Addr::from_str(&app_ip[..]).unwrap();
-    let app_addr = SocketAddrV4::new(ip, app_port);
-    let service_addr = SocketAddrV4::new(ip, service_port);
+    //create application and service addresses, nodes on ipv6 only hosts are refused since the
+    //p2p service and its lookup table address nodes by ipv4
+    let app_addr = match resolve_ipv4(&app_ip[..], app_port) {
+        Ok(app_addr) => app_addr,
+        Err(e) => exit_with_error(&e),
+    };
+    let service_addr = SocketAddrV4::new(*app_addr.ip(), service_port);
 
     //create seed address
     let seed_addr = match seed_port {
         0 => None,
-        _ => {
-            let seed_ip = Ipv4Addr::from_str(&seed_ip[..]).unwrap();
-            Some(SocketAddrV4::new(seed_ip, seed_port))
-        }
+        _ => match resolve_ipv4(&seed_ip[..], seed_port) {
+            Ok(seed_addr) => Some(seed_addr),
+            Err(e) => exit_with_error(&e),
+        },
     };
 
     //create application specific variables
     let lookup_table = Arc::new(RwLock::new(BTreeMap::new()));
     let entities: Arc<RwLock<HashMap<u64,HashMap<String,String>>>> = Arc::new(RwLock::new(HashMap::new()));
     let fields: Arc<RwLock<HashMap<String,HashMap<String,Vec<u64>>>>> = Arc::new(RwLock::new(HashMap::new()));
+    let tokens: Arc<RwLock<HashMap<String,HashMap<String,Vec<u64>>>>> = Arc::new(RwLock::new(HashMap::new()));
+    let tokenized_fields: Arc<HashSet<String>> = Arc::new(tokenized_fields.split(',').map(|x| x.trim().to_string()).filter(|x| !x.is_empty()).collect());
+    let query_pool = Arc::new(ThreadPool::new(if query_threads == 0 { num_cpus::get() } else { query_threads }));
+    let filter_cache = Arc::new(Mutex::new(FilterCache::new(cache_size * 1024 * 1024)));
+    let normalizers = match normalizers_file.len() {
+        0 => Normalizers::new(),
+        _ => match Normalizers::from_file(&normalizers_file[..]) {
+            Ok(normalizers) => normalizers,
+            Err(e) => panic!("{}", e),
+        },
+    };
+    let normalizers = Arc::new(normalizers);
+    let synonyms = match synonyms_dir.len() {
+        0 => Synonyms::new(),
+        _ => match Synonyms::from_dir(&synonyms_dir[..]) {
+            Ok(synonyms) => synonyms,
+            Err(e) => panic!("{}", e),
+        },
+    };
+    let synonyms = Arc::new(synonyms);
+    let models = match models_dir.len() {
+        0 => MatchModels::new(),
+        _ => match MatchModels::from_dir(&models_dir[..]) {
+            Ok(models) => models,
+            Err(e) => panic!("{}", e),
+        },
+    };
+    let models = Arc::new(models);
+    let blocking_keys = match blocking_keys_file.len() {
+        0 => BlockingKeys::new(),
+        _ => match BlockingKeys::from_file(&blocking_keys_file[..]) {
+            Ok(blocking_keys) => blocking_keys,
+            Err(e) => panic!("{}", e),
+        },
+    };
+    let blocking_keys = Arc::new(blocking_keys);
+    let mut transport = match (tls_cert_file.len(), tls_key_file.len()) {
+        (0, 0) => Transport::plain(),
+        (0, _) | (_, 0) => panic!("tls requires both a certificate and a private key file"),
+        _ => match Transport::server(&tls_cert_file[..], &tls_key_file[..], if tls_ca_file.len() == 0 { None } else { Some(&tls_ca_file[..]) }, tls_mutual) {
+            Ok(transport) => transport,
+            Err(e) => panic!("{}", e),
+        },
+    };
+    transport.set_allow_insecure_auth(allow_insecure_auth);
+    let transport = Arc::new(transport);
+    let authenticator = match credentials_file.len() {
+        0 => None,
+        _ => match Authenticator::from_file(&credentials_file[..]) {
+            Ok(authenticator) => Some(authenticator),
+            Err(e) => panic!("{}", e),
+        },
+    };
+    let authenticator = Arc::new(authenticator);
+    let node_credentials = match node_token.len() {
+        0 if authenticator.is_some() => panic!("a node token is required when authentication is enabled"),
+        0 if tls_cert_file.len() != 0 => panic!("a node token is required when tls is enabled without credentials"),
+        0 => Credentials::Anonymous,
+        _ => Credentials::Token(node_token.clone()),
+    };
+
+    //credentials and node tokens would be readable by anyone on the network without tls
+    if (authenticator.is_some() || node_credentials != Credentials::Anonymous) && !transport.allows_credentials() {
+        exit_with_error("credentials and node tokens require tls, pass --allow-insecure-auth to send them over plain connections");
+    }
+
+    //without credentials the node token alone guards the messages nodes send to each other
+    let node_token = match (authenticator.is_some(), node_token.len()) {
+        (false, length) if length != 0 => Some(node_token),
+        _ => None,
+    };
+    let node_token = Arc::new(node_token);
+    let connection_pool = Arc::new(ConnectionPool::new(idle_connections, request_threads, transport.clone(), node_credentials));
     let (debug_tx, debug_rx) = channel::<String>();
     let arc_debug_tx = Arc::new(Mutex::new(debug_tx));
 
@@ -74,251 +234,944 @@ pub fn main() {
     let dht_rx = rustdht::service::start(token, app_addr, service_addr, seed_addr, lookup_table.clone());
 
     //start listening on the application
-    let (lookup_table, entities, fields, arc_debug_tx_closure) = (lookup_table.clone(), entities.clone(), fields.clone(), arc_debug_tx.clone());
+    let (lookup_table, entities, fields, tokens, tokenized_fields, query_pool, filter_cache, normalizers, synonyms, models, blocking_keys, connection_pool, arc_debug_tx_closure) = (lookup_table.clone(), entities.clone(), fields.clone(), tokens.clone(), tokenized_fields.clone(), query_pool.clone(), filter_cache.clone(), normalizers.clone(), synonyms.clone(), models.clone(), blocking_keys.clone(), connection_pool.clone(), arc_debug_tx.clone());
     let listener = TcpListener::bind(app_addr).unwrap();
+    let open_connections = Arc::new(AtomicUsize::new(0));
     thread::spawn(move || {
         for stream in listener.incoming() {
-            let (lookup_table, entities, fields, arc_debug_tx) = (lookup_table.clone(), entities.clone(), fields.clone(), arc_debug_tx_closure.clone());
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+
+            //each connection is served by its own thread, so bound the threads by refusing connections over the limit
+            if open_connections.fetch_add(1, atomic::Ordering::SeqCst) >= max_connections {
+                open_connections.fetch_sub(1, atomic::Ordering::SeqCst);
+                let error = format!("too many connections, {} connections already open", max_connections);
+                let result = refuse_connection(stream, &transport, &error);
+
+                let debug_tx = arc_debug_tx_closure.lock().unwrap();
+                match result {
+                    Ok(_) => debug_tx.send(format!("refused connection: {}", error)).unwrap(),
+                    Err(e) => debug_tx.send(format!("refused connection: {} ({})", error, e)).unwrap(),
+                }
+                continue;
+            }
+
+            let slot = ConnectionSlot { open_connections: open_connections.clone() };
+            let (transport, authenticator, node_token) = (transport.clone(), authenticator.clone(), node_token.clone());
+            let (lookup_table, entities, fields, tokens, tokenized_fields, query_pool, filter_cache, normalizers, synonyms, models, blocking_keys, connection_pool, arc_debug_tx) = (lookup_table.clone(), entities.clone(), fields.clone(), tokens.clone(), tokenized_fields.clone(), query_pool.clone(), filter_cache.clone(), normalizers.clone(), synonyms.clone(), models.clone(), blocking_keys.clone(), connection_pool.clone(), arc_debug_tx_closure.clone());
 
             thread::spawn(move || {
-                let mut stream = stream.unwrap();
+                let _slot = slot;
+                let mut stream = match transport.accept(stream) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        let debug_tx = arc_debug_tx.lock().unwrap();
+                        debug_tx.send(format!("rejected connection: {}", e)).unwrap();
+                        return;
+                    },
+                };
+
+                //every connection starts with a hello message, incompatible or unauthenticated peers are sent an error and disconnected
+                let role = match accept_hello(&mut stream, &transport, &authenticator, &node_token) {
+                    Ok((version, account)) => {
+                        let debug_tx = arc_debug_tx.lock().unwrap();
+                        match account {
+                            Some((name, role)) => {
+                                debug_tx.send(format!("accepted connection from '{}' with role {:?} speaking protocol version {}", name, role, version)).unwrap();
+                                Some(role)
+                            },
+                            None => {
+                                debug_tx.send(format!("accepted connection speaking protocol version {}", version)).unwrap();
+                                None
+                            },
+                        }
+                    },
+                    Err(e) => {
+                        let debug_tx = arc_debug_tx.lock().unwrap();
+                        debug_tx.send(format!("rejected connection: {}", e)).unwrap();
+                        return;
+                    },
+                };
 
-                //read capnproto message
-                let msg_reader = capnp::serialize::read_message(&mut stream, ::capnp::message::ReaderOptions::new()).unwrap();
-                let msg = msg_reader.get_root::<message_capnp::message::Reader>().unwrap();
+                //serve messages until the connection is closed, peers reuse connections across requests
+                loop {
+                    //read capnproto message
+                    let msg_reader = match capnp::serialize::read_message(&mut stream, ::capnp::message::ReaderOptions::new()) {
+                        Ok(msg_reader) => msg_reader,
+                        Err(_) => break,
+                    };
+                    let msg = msg_reader.get_root::<message_capnp::message::Reader>().unwrap();
+
+                    //check the caller may send the message, without credentials every message is allowed unless a node token guards node messages
+                    let denied = match (role, get_required_permission(&msg)) {
+                        (Some(role), Some(permission)) if !role.allows(permission) => Some(format!("permission denied for role {:?}", role)),
+                        (None, Some(Permission::Node)) if node_token.is_some() => Some("permission denied, node messages require the node token".to_string()),
+                        _ => None,
+                    };
+
+                    if let Some(e) = denied {
+                        capnp::serialize::write_message(&mut stream, &create_error_msg(&e)).unwrap();
+                        continue;
+                    }
 
-                //parse out message
-                match msg.get_msg_type().which() {
-                    Ok(InsertEntitiesMsg(insert_entities_msg)) => {
-                        let mut streams = HashMap::new();
-                        for entity in insert_entities_msg.unwrap().iter() {
-                            //compute hash over all fields
-                            let mut hasher = SipHasher::new();
-                            for field in entity.get_fields().unwrap().iter() {
-                                field.get_value().unwrap().hash(&mut hasher);
+                    //parse out message
+                    match msg.get_msg_type().which() {
+                        Ok(InsertEntitiesMsg(insert_entities_msg)) => {
+                            let (mut streams, mut result) = (HashMap::new(), Ok(()));
+                            'entities: for entity in insert_entities_msg.unwrap().iter() {
+                                //compute hash over all fields
+                                let mut hasher = SipHasher::new();
+                                for field in entity.get_fields().unwrap().iter() {
+                                    field.get_value().unwrap().hash(&mut hasher);
+                                }
+                                let entity_key = hasher.finish();
+
+                                //lookup into peer table
+                                let lookup_table = lookup_table.read().unwrap();
+                                let socket_addr = rustdht::service::lookup(&lookup_table, entity_key).unwrap();
+
+                                //create write entity message
+                                let mut msg_builder = capnp::message::Builder::new_default();
+                                {
+                                    let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+                                    let mut write_entity_msg = msg.get_msg_type().init_write_entity_msg();
+                                    write_entity_msg.set_entity_key(entity_key);
+                                    write_entity_msg.set_fields(entity.get_fields().unwrap()).unwrap();
+                                }
+
+                                //send write entity message
+                                result = write_stream_msg(&mut streams, socket_addr, &msg_builder, &connection_pool);
+                                if result.is_err() {
+                                    break 'entities;
+                                }
+
+                                //send write field value message
+                                for field in entity.get_fields().unwrap().iter() {
+                                    //compute hash of field value
+                                    let mut hasher = SipHasher::new();
+                                    field.get_value().unwrap().hash(&mut hasher);
+                                    let field_hash = hasher.finish();
+
+                                    //create write field message
+                                    let mut msg_builder = capnp::message::Builder::new_default();
+                                    {
+                                        let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+                                        let mut write_field_msg = msg.get_msg_type().init_write_field_msg();
+                                        write_field_msg.set_entity_key(entity_key);
+                                        write_field_msg.set_field(field).unwrap();
+                                    }
+
+                                    //send write field message
+                                    let socket_addr = rustdht::service::lookup(&lookup_table, field_hash).unwrap();
+                                    result = write_stream_msg(&mut streams, socket_addr, &msg_builder, &connection_pool);
+                                    if result.is_err() {
+                                        break 'entities;
+                                    }
+                                }
+
+                                //index blocking keys as fields that are not stored with the entity
+                                let mut record = HashMap::new();
+                                for field in entity.get_fields().unwrap().iter() {
+                                    record.insert(field.get_name().unwrap().to_string(), field.get_value().unwrap().to_string());
+                                }
+
+                                for (name, value) in blocking_keys.compute(&record, &normalizers) {
+                                    //compute hash of blocking key value
+                                    let mut hasher = SipHasher::new();
+                                    (&value[..]).hash(&mut hasher);
+                                    let field_hash = hasher.finish();
+
+                                    //create write field message
+                                    let mut msg_builder = capnp::message::Builder::new_default();
+                                    {
+                                        let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+                                        let mut write_field_msg = msg.get_msg_type().init_write_field_msg();
+                                        write_field_msg.set_entity_key(entity_key);
+
+                                        let mut field = write_field_msg.init_field();
+                                        field.set_name(&name[..]);
+                                        field.set_value(&value[..]);
+                                    }
+
+                                    //send write field message
+                                    let socket_addr = rustdht::service::lookup(&lookup_table, field_hash).unwrap();
+                                    result = write_stream_msg(&mut streams, socket_addr, &msg_builder, &connection_pool);
+                                    if result.is_err() {
+                                        break 'entities;
+                                    }
+                                }
+                            }
+
+                            //close all streams, including those opened before a node failed
+                            close_write_streams(streams, &connection_pool);
+
+                            //return result message, or the node that could not be written to
+                            let msg_builder = match result {
+                                Ok(_) => {
+                                    let mut msg_builder = capnp::message::Builder::new_default();
+                                    {
+                                        let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+                                        msg.get_msg_type().set_result_msg(true);
+                                    }
+
+                                    msg_builder
+                                },
+                                Err(e) => create_error_msg(&format!("failed to insert entities: {}", e)),
+                            };
+
+                            capnp::serialize::write_message(&mut stream, &msg_builder).unwrap();
+                        },
+                        Ok(OpenWriteStreamMsg(_)) => {
+                            //acknowledge the write stream
+                            {
+                                let mut msg_builder = capnp::message::Builder::new_default();
+                                {
+                                    let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+                                    msg.get_msg_type().set_result_msg(true);
+                                }
+                                capnp::serialize::write_message(&mut stream, &msg_builder).unwrap();
+                            }
+
+                            //read from stream until a close write stream message is received, closing the connection on invalid input
+                            let result = loop {
+                                let msg_reader = match capnp::serialize::read_message(&mut stream, ::capnp::message::ReaderOptions::new()) {
+                                    Ok(msg_reader) => msg_reader,
+                                    Err(e) => break Err(format!("failed to read write stream: {}", e)),
+                                };
+
+                                let msg = match msg_reader.get_root::<message_capnp::message::Reader>() {
+                                    Ok(msg) => msg,
+                                    Err(e) => break Err(format!("invalid message on write stream: {}", e)),
+                                };
+
+                                match apply_write_msg(msg, &entities, &fields, &tokens, &tokenized_fields, &filter_cache, &normalizers, &blocking_keys, &arc_debug_tx) {
+                                    Ok(true) => break Ok(()),
+                                    Ok(false) => continue,
+                                    Err(e) => break Err(e),
+                                }
+                            };
+
+                            //the writer does not read replies until the stream is closed, so the connection is dropped
+                            //rather than answered to keep an error from being read as the reply to a later request
+                            if let Err(e) = result {
+                                let debug_tx = arc_debug_tx.lock().unwrap();
+                                debug_tx.send(format!("closed write stream: {}", e)).unwrap();
+                                break;
                             }
-                            let entity_key = hasher.finish();
+                        },
+                        Ok(QueryMsg(query_msg)) => {
+                            //start time
+                            let start_time = time::precise_time_ns();
+
+                            //get entity keys
+                            let entity_keys = match get_entity_keys(query_msg.get_filters().unwrap(), &lookup_table, &connection_pool) {
+                                Ok(entity_keys) => entity_keys,
+                                Err(e) => {
+                                    capnp::serialize::write_message(&mut stream, &create_error_msg(&e)).unwrap();
+                                    continue;
+                                },
+                            };
 
-                            //lookup into peer table
-                            let lookup_table = lookup_table.read().unwrap();
-                            let socket_addr = rustdht::service::lookup(&lookup_table, entity_key).unwrap();
+                            //get projected field names, where none means all fields
+                            let field_names_msg = query_msg.get_field_names().unwrap();
+                            let mut field_names = Vec::new();
+                            for i in 0..field_names_msg.len() {
+                                field_names.push(field_names_msg.get(i).unwrap().to_string());
+                            }
 
-                            //create write entity message
+                            //send results header message with the number of matching entity keys
                             let mut msg_builder = capnp::message::Builder::new_default();
                             {
                                 let msg = msg_builder.init_root::<message_capnp::message::Builder>();
-                                let mut write_entity_msg = msg.get_msg_type().init_write_entity_msg();
-                                write_entity_msg.set_entity_key(entity_key);
-                                write_entity_msg.set_fields(entity.get_fields().unwrap()).unwrap();
+                                msg.get_msg_type().set_query_results_header_msg(entity_keys.len() as u64);
+                            }
+
+                            capnp::serialize::write_message(&mut stream, &msg_builder).unwrap();
+
+                            //send entities to the client in chunks as each node replies
+                            let (entities_tx, entities_rx) = channel::<Result<Vec<(u64,HashMap<String,String>)>,String>>();
+                            let batch_count = fetch_entities(entity_keys, field_names, &lookup_table, &connection_pool, entities_tx);
+
+                            let (mut entity_count, mut error) = (0, None);
+                            for _ in 0..batch_count {
+                                let entities = match recv_node_result(&entities_rx) {
+                                    Ok(entities) => entities,
+                                    Err(e) => {
+                                        error = Some(e);
+                                        break;
+                                    },
+                                };
+
+                                for chunk in entities.chunks(ENTITY_CHUNK_SIZE) {
+                                    //create entities message
+                                    let mut msg_builder = capnp::message::Builder::new_default();
+                                    {
+                                        let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+                                        let mut entities_msg = msg.get_msg_type().init_entities_msg(chunk.len() as u32);
+
+                                        for (i, &(entity_key, ref entity)) in chunk.iter().enumerate() {
+                                            let mut entity_msg = entities_msg.borrow().get(i as u32);
+                                            entity_msg.set_key(entity_key);
+
+                                            let mut fields = entity_msg.init_fields(entity.len() as u32);
+                                            for (j, (name, value)) in entity.iter().enumerate() {
+                                                let mut field = fields.borrow().get(j as u32);
+                                                field.set_name(name);
+                                                field.set_value(value);
+                                            }
+                                        }
+                                    }
+
+                                    //send entities message
+                                    capnp::serialize::write_message(&mut stream, &msg_builder).unwrap();
+                                    entity_count += chunk.len();
+                                }
+                            }
+
+                            //a failed node ends the results with an error in place of the trailer
+                            if let Some(e) = error {
+                                capnp::serialize::write_message(&mut stream, &create_error_msg(&e)).unwrap();
+                                continue;
                             }
 
-                            //send write entity message
+                            //send results trailer message
+                            let mut msg_builder = capnp::message::Builder::new_default();
                             {
-                                let stream = streams.entry(socket_addr).or_insert_with(|| { open_write_stream(socket_addr) });
-                                capnp::serialize::write_message(stream, &msg_builder).unwrap();
+                                let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+                                let mut trailer_msg = msg.get_msg_type().init_query_results_trailer_msg();
+                                trailer_msg.set_entity_count(entity_count as u64);
+                                trailer_msg.set_duration((time::precise_time_ns() - start_time) / 1000000);
                             }
 
-                            //send write field value message
-                            for field in entity.get_fields().unwrap().iter() {
-                                //compute hash of field value
-                                let mut hasher = SipHasher::new();
-                                field.get_value().unwrap().hash(&mut hasher);
-                                let field_hash = hasher.finish();
+                            capnp::serialize::write_message(&mut stream, &msg_builder).unwrap();
+                        },
+                        Ok(QueryEntitiesMsg(query_entities_msg)) => {
+                            let entity_keys = query_entities_msg.get_entity_keys().unwrap();
+                            let field_names = query_entities_msg.get_field_names().unwrap();
 
-                                //create write field message
-                                let mut msg_builder = capnp::message::Builder::new_default();
-                                {
-                                    let msg = msg_builder.init_root::<message_capnp::message::Builder>();
-                                    let mut write_field_msg = msg.get_msg_type().init_write_field_msg();
-                                    write_field_msg.set_entity_key(entity_key);
-                                    write_field_msg.set_field(field).unwrap();
+                            //create entities message, skipping keys that are not stored here
+                            let mut msg_builder = capnp::message::Builder::new_default();
+                            let entity_count = {
+                                let entities = entities.read().unwrap();
+                                let mut entity_fields_list = vec!();
+                                for i in 0..entity_keys.len() {
+                                    let entity_key = entity_keys.get(i);
+                                    let entity_fields = match entities.get(&entity_key) {
+                                        Some(entity_fields) => entity_fields,
+                                        None => continue,
+                                    };
+
+                                    //project requested fields, where none means all fields
+                                    let entity_fields: Vec<(&str, &str)> = match field_names.len() {
+                                        0 => entity_fields.iter().map(|(name, value)| (&name[..], &value[..])).collect(),
+                                        _ => {
+                                            let mut projected_fields = vec!();
+                                            for j in 0..field_names.len() {
+                                                let name = field_names.get(j).unwrap();
+                                                if let Some(value) = entity_fields.get(name) {
+                                                    projected_fields.push((name, &value[..]));
+                                                }
+                                            }
+
+                                            projected_fields
+                                        },
+                                    };
+
+                                    entity_fields_list.push((entity_key, entity_fields));
                                 }
 
-                                //send write field message
-                                let socket_addr = rustdht::service::lookup(&lookup_table, field_hash).unwrap();
-                                let stream = streams.entry(socket_addr).or_insert_with(|| { open_write_stream(socket_addr) });
-                                capnp::serialize::write_message(stream, &msg_builder).unwrap();
-                            }
-                        }
+                                let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+                                let mut entities_msg = msg.get_msg_type().init_entities_msg(entity_fields_list.len() as u32);
+                                for (i, &(entity_key, ref entity_fields)) in entity_fields_list.iter().enumerate() {
+                                    let mut entity_msg = entities_msg.borrow().get(i as u32);
+                                    entity_msg.set_key(entity_key);
+
+                                    let mut fields = entity_msg.init_fields(entity_fields.len() as u32);
+                                    for (j, &(name, value)) in entity_fields.iter().enumerate() {
+                                        let mut field = fields.borrow().get(j as u32);
+                                        field.set_name(name);
+                                        field.set_value(value);
+                                    }
+                                }
+
+                                entity_fields_list.len()
+                            };
+
+                            //send entities message
+                            capnp::serialize::write_message(&mut stream, &msg_builder).unwrap();
+
+                            //send debug information
+                            let debug_tx = arc_debug_tx.lock().unwrap();
+                            debug_tx.send(format!("query entities for {} keys results in {} entities", entity_keys.len(), entity_count)).unwrap();
+                        },
+                        Ok(QueryEntityMsg(query_entity_msg)) => {
+                            let entity_key = query_entity_msg.get_entity_key();
+                            let field_names = query_entity_msg.get_field_names().unwrap();
+
+                            //search for entity, an unknown key is reported rather than answered with an empty entity
+                            let entities = entities.read().unwrap();
+                            let entity_fields = match entities.get(&entity_key) {
+                                Some(entity_fields) => entity_fields,
+                                None => {
+                                    capnp::serialize::write_message(&mut stream, &create_error_msg(&format!("unknown entity key {}", entity_key))).unwrap();
+                                    continue;
+                                },
+                            };
+
+                            //project requested fields, where none means all fields
+                            let entity_fields: Vec<(&str, &str)> = match field_names.len() {
+                                0 => entity_fields.iter().map(|(name, value)| (&name[..], &value[..])).collect(),
+                                _ => {
+                                    let mut projected_fields = vec!();
+                                    for i in 0..field_names.len() {
+                                        let name = field_names.get(i).unwrap();
+                                        if let Some(value) = entity_fields.get(name) {
+                                            projected_fields.push((name, &value[..]));
+                                        }
+                                    }
+
+                                    projected_fields
+                                },
+                            };
 
-                        //return result message
-                        {
+                            //create entity message
                             let mut msg_builder = capnp::message::Builder::new_default();
                             {
                                 let msg = msg_builder.init_root::<message_capnp::message::Builder>();
-                                msg.get_msg_type().set_result_msg(true);
+                                let mut entity_msg = msg.get_msg_type().init_entity_msg(entity_fields.len() as u32);
+
+                                for (i, &(name, value)) in entity_fields.iter().enumerate() {
+                                    let mut field = entity_msg.borrow().get(i as u32);
+                                    field.set_name(name);
+                                    field.set_value(value);
+                                }
                             }
+
+                            //send entity message
                             capnp::serialize::write_message(&mut stream, &msg_builder).unwrap();
-                        }
 
-                        //close all streams
-                        let mut msg_builder = capnp::message::Builder::new_default();
-                        {
-                            let msg = msg_builder.init_root::<message_capnp::message::Builder>();
-                            msg.get_msg_type().set_close_write_stream_msg(());
-                        }
+                            //send debug information
+                            let debug_tx = arc_debug_tx.lock().unwrap();
+                            debug_tx.send(format!("query entity for key '{}'", entity_key)).unwrap();
+                        },
+                        Ok(QueryFilterMsg(query_filter_msg)) => {
+                            let filter = query_filter_msg.unwrap();
+
+                            //create values for query, fields is always locked before tokens
+                            let fields = fields.read().unwrap();
+                            let tokens = tokens.read().unwrap();
+                            let mut params = Vec::new();
+                            let filter_params = filter.get_params().unwrap();
+                            for i in 0..filter_params.len() {
+                                params.push(filter_params.get(i).unwrap());
+                            }
 
-                        for (_, stream) in streams.iter_mut() {
-                            capnp::serialize::write_message(stream, &msg_builder).unwrap();
-                            stream.shutdown(Shutdown::Both).unwrap();
-                        }
-                    },
-                    Ok(OpenWriteStreamMsg(_)) => {
-                        //read from stream untill close write stream message recieved
-                        loop {
-                            //read capnproto message
-                            let msg_reader = capnp::serialize::read_message(&mut stream, ::capnp::message::ReaderOptions::new()).unwrap();
-                            let msg = msg_reader.get_root::<message_capnp::message::Reader>().unwrap();
-
-                            //check message type
-                            match msg.get_msg_type().which() {
-                                Ok(CloseWriteStreamMsg(_)) => break,
-                                Ok(WriteEntityMsg(write_entity_msg)) => {
-                                    //create entity hash map
-                                    let mut entity = HashMap::new();
-                                    for field in write_entity_msg.get_fields().unwrap().iter() {
-                                        entity.insert(field.get_name().unwrap().to_string(), field.get_value().unwrap().to_string());
+                            let mut field_names = Vec::new();
+                            let filter_field_names = filter.get_field_names().unwrap();
+                            for i in 0..filter_field_names.len() {
+                                field_names.push(filter_field_names.get(i).unwrap());
+                            }
+
+                            //check the filter cache before performing actual query
+                            let (filter_type, value, match_tokens, ignore_accents) = (filter.get_filter_type().unwrap(), filter.get_value().unwrap(), filter.get_match_tokens(), filter.get_ignore_accents());
+                            let cached_entity_keys = filter_cache.lock().unwrap().get(&field_names[..], filter_type, &params[..], value, match_tokens, ignore_accents);
+                            let cached = cached_entity_keys.is_some();
+                            let entity_keys = match cached_entity_keys {
+                                Some(entity_keys) => Ok(entity_keys),
+                                None => {
+                                    //synonym filters apply their inner filter to every expanded value
+                                    let expanded_filter = match filter_type {
+                                        "synonym" => synonyms.filter(&params[..]),
+                                        _ => Ok((filter_type, params.clone())),
+                                    };
+
+                                    let (query_filter_type, query_params) = match expanded_filter {
+                                        Ok(expanded_filter) => expanded_filter,
+                                        Err(e) => {
+                                            capnp::serialize::write_message(&mut stream, &create_error_msg(&e)).unwrap();
+                                            continue;
+                                        },
+                                    };
+
+                                    //expand and normalize the values for each field under its own pipeline, querying fields with equal
+                                    //values together
+                                    let mut value_field_names = BTreeMap::new();
+                                    let all_field_names = get_user_field_names(&fields, &blocking_keys);
+                                    for field_name in if field_names.is_empty() { all_field_names.iter() } else { field_names.iter() } {
+                                        let normalizer_list = get_pipeline(&normalizers, &blocking_keys, field_name);
+                                        let values = match filter_type {
+                                            "synonym" => synonyms.get(params[0]).map(|dictionary| dictionary.expand(value, normalizer_list)).unwrap_or(vec!(value.to_string())),
+                                            _ => vec!(value.to_string()),
+                                        };
+
+                                        for value in values.iter() {
+                                            let normalized_value = fuzzydb::normalize::apply_pipeline(normalizer_list, value);
+                                            value_field_names.entry(normalized_value).or_insert(vec!()).push(*field_name);
+                                        }
                                     }
 
-                                    //insert entity into entities
-                                    let mut entities = entities.write().unwrap();
-                                    entities.insert(write_entity_msg.get_entity_key(), entity);
+                                    //match against either whole values or individual tokens
+                                    let dictionary = if match_tokens { &*tokens } else { &*fields };
+                                    let (mut entity_keys, mut result) = (HashSet::new(), Ok(()));
+                                    for (normalized_value, value_field_names) in value_field_names.iter() {
+                                        match fuzzydb::query::query_field(&value_field_names[..], query_filter_type, query_params.clone(), normalized_value, ignore_accents, dictionary, &blocking_keys, &query_pool) {
+                                            Ok(keys) => entity_keys.extend(keys),
+                                            Err(e) => {
+                                                result = Err(e);
+                                                break;
+                                            },
+                                        }
+                                    }
 
-                                    //send debug information
-                                    let debug_tx = arc_debug_tx.lock().unwrap();
-                                    debug_tx.send(format!("wrote entity with key {}", write_entity_msg.get_entity_key())).unwrap();
+                                    //fields read lock is still held so no write may have invalidated this result
+                                    result.map(|_| {
+                                        filter_cache.lock().unwrap().insert(&field_names[..], filter_type, &params[..], value, match_tokens, ignore_accents, &entity_keys);
+                                        entity_keys
+                                    })
                                 },
-                                Ok(WriteFieldMsg(write_field_msg)) => {
-                                    //get field_values HashMap<String,[]u64>
-                                    let mut fields = fields.write().unwrap();
-                                    let field = write_field_msg.get_field().unwrap();
-                                    let (fieldname, field_value) = (field.get_name().unwrap(), field.get_value().unwrap());
-                                    
-                                    //search for and create entry in fields if necessary
-                                    let mut field_values = fields.entry(fieldname.to_string()).or_insert(HashMap::new());
-                                    let mut entity_keys = field_values.entry(field_value.to_string()).or_insert(vec!());
-                                    entity_keys.push(write_field_msg.get_entity_key());
-
-                                    //send debug information
-                                    let debug_tx = arc_debug_tx.lock().unwrap();
-                                    debug_tx.send(format!("wrote field value {} for field name {} and entity key {}", field_value, fieldname, write_field_msg.get_entity_key())).unwrap();
+                            };
+
+                            //reply with an error when the filter is invalid
+                            let entity_keys = match entity_keys {
+                                Ok(entity_keys) => entity_keys,
+                                Err(e) => {
+                                    capnp::serialize::write_message(&mut stream, &create_error_msg(&e)).unwrap();
+                                    continue;
                                 },
-                                Ok(_) => panic!("Unknown message type on write stream"),
-                                Err(capnp::NotInSchema(e)) => panic!("Error capnp::NotInSchema: {}", e),
+                            };
+                            let keys = entity_keys.iter().map(|x| { format!("{}", *x) } ).collect::<Vec<String>>().join(",");
+
+                            //create entity keys message
+                            let mut msg_builder = capnp::message::Builder::new_default();
+                            {
+                                let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+                                let mut entity_keys_msg = msg.get_msg_type().init_entity_keys_msg(entity_keys.len() as u32);
+
+                                let mut count = 0;
+                                for entity_key in entity_keys {
+                                    entity_keys_msg.set(count, entity_key);
+                                    count+=1;
+                                }
                             }
-                        }
-                    },
-                    Ok(QueryMsg(query_msg)) => {
-                        //get entity keys
-                        let entity_keys = get_entity_keys(query_msg.unwrap(), &lookup_table);
-                        
-                        //create entities message
-                        {
-                            //poll for entities
-                            let (entities_tx, entities_rx) = channel::<HashMap<String,String>>();
-                            let entity_keys_len = entity_keys.len();
-                            get_entities(entity_keys, lookup_table, entities_tx);
-
-                            let mut entity_vec = vec!();
-                            for _ in 0..entity_keys_len {
-                                entity_vec.push(entities_rx.recv().unwrap());
+
+                            //send entity keys message
+                            capnp::serialize::write_message(&mut stream, &msg_builder).unwrap();
+
+                            //send debug information
+                            let debug_tx = arc_debug_tx.lock().unwrap();
+                            debug_tx.send(
+                                format!(
+                                    "filter type '{}'{}{} on fields '{}' for value '{}' results in entity keys '{}'{}",
+                                    filter_type,
+                                    if match_tokens { " on tokens" } else { "" },
+                                    if ignore_accents { " ignoring accents" } else { "" },
+                                    if field_names.is_empty() { "*".to_string() } else { field_names.join(",") },
+                                    value,
+                                    keys,
+                                    if cached { " (cached)" } else { "" }
+                                )
+                            ).unwrap();
+                        },
+                        Ok(QueryNearestMsg(query_nearest_msg)) => {
+                            let filter = query_nearest_msg.unwrap();
+
+                            //create values for query, fields is always locked before tokens
+                            let fields = fields.read().unwrap();
+                            let tokens = tokens.read().unwrap();
+                            let mut params = Vec::new();
+                            let filter_params = filter.get_params().unwrap();
+                            for i in 0..filter_params.len() {
+                                params.push(filter_params.get(i).unwrap());
+                            }
+
+                            let mut field_names = Vec::new();
+                            let filter_field_names = filter.get_field_names().unwrap();
+                            for i in 0..filter_field_names.len() {
+                                field_names.push(filter_field_names.get(i).unwrap());
+                            }
+
+                            //parameters are the metric, the number of values and then the metric parameters, reply with an error when they are invalid
+                            let (value, match_tokens, ignore_accents) = (filter.get_value().unwrap(), filter.get_match_tokens(), filter.get_ignore_accents());
+                            let (metric, k, metric_params) = match fuzzydb::query::parse_nearest_params(&params[..]) {
+                                Ok(nearest_params) => nearest_params,
+                                Err(e) => {
+                                    capnp::serialize::write_message(&mut stream, &create_error_msg(&e)).unwrap();
+                                    continue;
+                                },
+                            };
+
+                            //normalize the value for each field, querying fields with equal values together
+                            let mut value_field_names = BTreeMap::new();
+                            let all_field_names = get_user_field_names(&fields, &blocking_keys);
+                            for field_name in if field_names.is_empty() { all_field_names.iter() } else { field_names.iter() } {
+                                let normalized_value = normalize_field(&normalizers, &blocking_keys, field_name, value);
+                                value_field_names.entry(normalized_value).or_insert(vec!()).push(*field_name);
+                            }
+
+                            //keep the best score of values found through more than one field
+                            let dictionary = if match_tokens { &*tokens } else { &*fields };
+                            let (mut value_scores, mut result): (HashMap<String,(f64,Vec<u64>)>, Result<(),String>) = (HashMap::new(), Ok(()));
+                            for (normalized_value, value_field_names) in value_field_names.iter() {
+                                let nearest_values = match fuzzydb::query::nearest_field_values(&value_field_names[..], metric, k, metric_params.clone(), normalized_value, ignore_accents, dictionary, &blocking_keys, &query_pool) {
+                                    Ok(nearest_values) => nearest_values,
+                                    Err(e) => {
+                                        result = Err(e);
+                                        break;
+                                    },
+                                };
+
+                                for (score, nearest_value, entity_keys) in nearest_values {
+                                    let value_score = value_scores.entry(nearest_value).or_insert((score, vec!()));
+                                    if score > value_score.0 {
+                                        value_score.0 = score;
+                                    }
+
+                                    value_score.1.extend(entity_keys);
+                                }
+                            }
+
+                            //reply with an error when the metric is invalid
+                            if let Err(e) = result {
+                                capnp::serialize::write_message(&mut stream, &create_error_msg(&e)).unwrap();
+                                continue;
                             }
 
-                            //create entities message
+                            let scored_values = get_nearest_values(value_scores, k);
+
+                            //create scored values message
                             let mut msg_builder = capnp::message::Builder::new_default();
                             {
                                 let msg = msg_builder.init_root::<message_capnp::message::Builder>();
-                                let mut entities_msg = msg.get_msg_type().init_entities_msg(entity_vec.len() as u32);
+                                let mut scored_values_msg = msg.get_msg_type().init_scored_values_msg(scored_values.len() as u32);
 
-                                for (i, entity) in entity_vec.iter().enumerate() {
-                                    let entity_msg = entities_msg.borrow().get(i as u32);
-                                    let mut fields = entity_msg.init_fields(entity.len() as u32);
+                                for (i, &(score, ref nearest_value, ref entity_keys)) in scored_values.iter().enumerate() {
+                                    let mut scored_value = scored_values_msg.borrow().get(i as u32);
+                                    scored_value.set_value(&nearest_value[..]);
+                                    scored_value.set_score(score);
 
-                                    for (j, (name, value)) in entity.iter().enumerate() {
-                                        let mut field = fields.borrow().get(j as u32);
-                                        field.set_name(name);
-                                        field.set_value(value);
+                                    let mut entity_keys_msg = scored_value.init_entity_keys(entity_keys.len() as u32);
+                                    for (j, entity_key) in entity_keys.iter().enumerate() {
+                                        entity_keys_msg.set(j as u32, *entity_key);
                                     }
                                 }
                             }
 
-                            //send entities message
+                            //send scored values message
                             capnp::serialize::write_message(&mut stream, &msg_builder).unwrap();
-                        }
-                    },
-                    Ok(QueryEntityMsg(query_entity_msg)) => {
-                        //search for entity
-                        let entities = entities.read().unwrap();
-                        let entity_fields = entities.get(&query_entity_msg).unwrap();
-
-                        //create entity message
-                        let mut msg_builder = capnp::message::Builder::new_default();
-                        {
-                            let msg = msg_builder.init_root::<message_capnp::message::Builder>();
-                            let mut entity_msg = msg.get_msg_type().init_entity_msg(entity_fields.len() as u32);
-
-                            for (i, (name, value)) in entity_fields.iter().enumerate() {
-                                let mut field = entity_msg.borrow().get(i as u32);
-                                field.set_name(name);
-                                field.set_value(value);
+
+                            //send debug information
+                            let debug_tx = arc_debug_tx.lock().unwrap();
+                            debug_tx.send(
+                                format!(
+                                    "nearest {} values by '{}'{} on fields '{}' for value '{}' results in values '{}'",
+                                    k,
+                                    metric,
+                                    if match_tokens { " on tokens" } else { "" },
+                                    if field_names.is_empty() { "*".to_string() } else { field_names.join(",") },
+                                    value,
+                                    scored_values.iter().map(|x| format!("{}:{}", x.1, x.0)).collect::<Vec<String>>().join(",")
+                                )
+                            ).unwrap();
+                        },
+                        Ok(AggregateMsg(aggregate_msg)) => {
+                            let group_by_msg = aggregate_msg.get_group_by().unwrap();
+                            let mut group_by = Vec::new();
+                            for i in 0..group_by_msg.len() {
+                                group_by.push(group_by_msg.get(i).unwrap().to_string());
                             }
-                        }
 
-                        //send entity message
-                        capnp::serialize::write_message(&mut stream, &msg_builder).unwrap();
+                            let field_names_msg = aggregate_msg.get_field_names().unwrap();
+                            let mut field_names = Vec::new();
+                            for i in 0..field_names_msg.len() {
+                                field_names.push(field_names_msg.get(i).unwrap().to_string());
+                            }
 
-                        //send debug information
-                        let debug_tx = arc_debug_tx.lock().unwrap();
-                        debug_tx.send(format!("query entity for key '{}'", query_entity_msg)).unwrap();
-                    },
-                    Ok(QueryFilterMsg(query_filter_msg)) => {
-                        let filter = query_filter_msg.unwrap();
-
-                        //create values for query
-                        let fields = fields.read().unwrap();
-                        let mut params = Vec::new();
-                        let filter_params = filter.get_params().unwrap();
-                        for i in 0..filter_params.len() {
-                            params.push(filter_params.get(i).unwrap());
-                        }
+                            //validate the fields before any lookup, clients other than ours may send any message
+                            let group_counts = validate_group_by(&field_names, &group_by).and_then(|_| get_entity_keys(aggregate_msg.get_filters().unwrap(), &lookup_table, &connection_pool)).and_then(|mut entity_keys| {
+                                //remove any duplicates returned by separate nodes
+                                entity_keys.sort();
+                                entity_keys.dedup();
+
+                                //ungrouped counts are computed from the entity keys alone
+                                match group_by.len() {
+                                    0 => {
+                                        let mut group_counts = BTreeMap::new();
+                                        group_counts.insert(vec!(), entity_keys.len() as u64);
+                                        Ok(group_counts)
+                                    },
+                                    _ => get_group_counts(entity_keys, group_by, &lookup_table, &connection_pool),
+                                }
+                            });
 
-                        //perform actual query
-                        let entity_keys = fuzzydb::query::query_field(filter.get_field_name().unwrap(), filter.get_filter_type().unwrap(), params, filter.get_value().unwrap(), &fields);
-                        let keys = entity_keys.iter().map(|x| { format!("{}", *x) } ).collect::<Vec<String>>().join(",");
+                            //send group counts message
+                            let msg_builder = match group_counts {
+                                Ok(group_counts) => create_group_counts_msg(&group_counts),
+                                Err(e) => create_error_msg(&e),
+                            };
+                            capnp::serialize::write_message(&mut stream, &msg_builder).unwrap();
+                        },
+                        Ok(QueryGroupCountsMsg(query_group_counts_msg)) => {
+                            let entity_keys = query_group_counts_msg.get_entity_keys().unwrap();
+                            let group_by = query_group_counts_msg.get_group_by().unwrap();
 
-                        //create entity keys message
-                        let mut msg_builder = capnp::message::Builder::new_default();
-                        {
-                            let msg = msg_builder.init_root::<message_capnp::message::Builder>();
-                            let mut entity_keys_msg = msg.get_msg_type().init_entity_keys_msg(entity_keys.len() as u32);
+                            //count local entities by their group by field values
+                            let mut group_counts = BTreeMap::new();
+                            {
+                                let entities = entities.read().unwrap();
+                                for i in 0..entity_keys.len() {
+                                    let entity_fields = match entities.get(&entity_keys.get(i)) {
+                                        Some(entity_fields) => entity_fields,
+                                        None => continue,
+                                    };
+
+                                    let mut values = Vec::new();
+                                    for j in 0..group_by.len() {
+                                        let value = entity_fields.get(group_by.get(j).unwrap()).cloned().unwrap_or(String::new());
+                                        values.push(value);
+                                    }
 
-                            let mut count = 0;
-                            for entity_key in entity_keys {
-                                entity_keys_msg.set(count, entity_key);
-                                count+=1;
+                                    *group_counts.entry(values).or_insert(0) += 1;
+                                }
                             }
-                        }
 
-                        //send entity keys message
-                        capnp::serialize::write_message(&mut stream, &msg_builder).unwrap();
+                            //send group counts message
+                            let msg_builder = create_group_counts_msg(&group_counts);
+                            capnp::serialize::write_message(&mut stream, &msg_builder).unwrap();
 
-                        //send debug information
-                        let debug_tx = arc_debug_tx.lock().unwrap();
-                        debug_tx.send(
-                            format!(
-                                "filter type '{}' on field '{}' for value '{}' results in entity keys '{}'",
-                                filter.get_filter_type().unwrap(),
-                                filter.get_field_name().unwrap(),
-                                filter.get_value().unwrap(),
-                                keys
-                            )
-                        ).unwrap();
-                    },
-                    Ok(_) => panic!("Unknown message type"),
-                    Err(capnp::NotInSchema(e)) => panic!("Error capnp::NotInSchema: {}", e),
-                };
+                            //send debug information
+                            let debug_tx = arc_debug_tx.lock().unwrap();
+                            debug_tx.send(format!("query group counts for {} entity keys results in {} groups", entity_keys.len(), group_counts.len())).unwrap();
+                        },
+                        Ok(MatchMsg(match_msg)) => {
+                            let model_name = match_msg.get_model().unwrap();
+                            let limit = match match_msg.get_limit() {
+                                0 => DEFAULT_MATCH_LIMIT,
+                                limit => limit as usize,
+                            };
+
+                            let msg_builder = match models.get(model_name) {
+                                Some(model) => {
+                                    //create record from fields
+                                    let mut record = HashMap::new();
+                                    for field in match_msg.get_fields().unwrap().iter() {
+                                        record.insert(field.get_name().unwrap().to_string(), field.get_value().unwrap().to_string());
+                                    }
+
+                                    //score and classify candidates
+                                    match get_candidates(&record, model, limit, &blocking_keys, &normalizers, &synonyms, &lookup_table, &connection_pool) {
+                                        Ok(candidates) => {
+                                            //create candidates message
+                                            let mut msg_builder = capnp::message::Builder::new_default();
+                                            {
+                                                let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+                                                let mut candidates_msg = msg.get_msg_type().init_candidates_msg(candidates.len() as u32);
+
+                                                for (i, &(weight, classification, ref entity, ref field_weights)) in candidates.iter().enumerate() {
+                                                    let mut candidate = candidates_msg.borrow().get(i as u32);
+                                                    candidate.set_weight(weight);
+                                                    candidate.set_classification(match classification {
+                                                        Classification::Match => message_capnp::Classification::Match,
+                                                        Classification::Possible => message_capnp::Classification::Possible,
+                                                        Classification::NonMatch => message_capnp::Classification::NonMatch,
+                                                    });
+
+                                                    {
+                                                        let mut fields = candidate.borrow().init_fields(entity.len() as u32);
+                                                        for (j, (name, value)) in entity.iter().enumerate() {
+                                                            let mut field = fields.borrow().get(j as u32);
+                                                            field.set_name(name);
+                                                            field.set_value(value);
+                                                        }
+                                                    }
+
+                                                    let mut scores = candidate.init_scores(field_weights.len() as u32);
+                                                    for (j, &(ref name, weight)) in field_weights.iter().enumerate() {
+                                                        let mut score = scores.borrow().get(j as u32);
+                                                        score.set_name(name);
+                                                        score.set_score(weight);
+                                                    }
+                                                }
+                                            }
+
+                                            msg_builder
+                                        },
+                                        Err(e) => create_error_msg(&e),
+                                    }
+                                },
+                                None => create_error_msg(&format!("unknown model '{}'", model_name)),
+                            };
+
+                            //send candidates message
+                            capnp::serialize::write_message(&mut stream, &msg_builder).unwrap();
+                        },
+                        Ok(DeduplicateMsg(deduplicate_msg)) => {
+                            let model_name = deduplicate_msg.unwrap();
+
+                            let msg_builder = match models.get(model_name) {
+                                Some(_) => match get_duplicates(model_name, &lookup_table, &connection_pool) {
+                                    Ok(duplicates) => {
+                                        //assign each connected component of duplicates a cluster id
+                                        let cluster_ids = get_cluster_ids(duplicates);
+                                        match write_cluster_ids(&cluster_ids, &lookup_table, &connection_pool) {
+                                            Ok(_) => {
+                                                //report the size of every cluster with more than one entity
+                                                let mut cluster_counts = BTreeMap::new();
+                                                for (_, cluster_id) in cluster_ids.iter() {
+                                                    *cluster_counts.entry(vec!(format!("{}", cluster_id))).or_insert(0) += 1;
+                                                }
+
+                                                let group_counts: BTreeMap<Vec<String>,u64> = cluster_counts.into_iter().filter(|&(_, count)| count > 1).collect();
+                                                create_group_counts_msg(&group_counts)
+                                            },
+                                            Err(e) => create_error_msg(&format!("failed to write cluster ids: {}", e)),
+                                        }
+                                    },
+                                    Err(e) => create_error_msg(&e),
+                                },
+                                None => create_error_msg(&format!("unknown model '{}'", model_name)),
+                            };
+
+                            //send group counts message
+                            capnp::serialize::write_message(&mut stream, &msg_builder).unwrap();
+                        },
+                        Ok(DeduplicateNodeMsg(deduplicate_node_msg)) => {
+                            let model_name = deduplicate_node_msg.unwrap();
+
+                            let msg_builder = match models.get(model_name) {
+                                Some(model) => {
+                                    //drop cluster ids from any previous deduplicate command
+                                    {
+                                        let mut entities = entities.write().unwrap();
+                                        for (_, entity) in entities.iter_mut() {
+                                            entity.remove(CLUSTER_ID_FIELD);
+                                        }
+                                    }
+
+                                    {
+                                        let mut fields = fields.write().unwrap();
+                                        let mut tokens = tokens.write().unwrap();
+                                        fields.remove(CLUSTER_ID_FIELD);
+                                        tokens.remove(CLUSTER_ID_FIELD);
+                                        filter_cache.lock().unwrap().invalidate_field(CLUSTER_ID_FIELD);
+                                    }
+
+                                    //find the entities each local entity matches under the model
+                                    //create duplicates message, or report why the model could not be applied
+                                    match get_local_duplicates(model, &entities, &fields, &normalizers, &synonyms, &blocking_keys, &query_pool, &lookup_table, &connection_pool) {
+                                        Err(e) => create_error_msg(&e),
+                                        Ok(duplicates) => {
+                                            let mut msg_builder = capnp::message::Builder::new_default();
+                                            {
+                                                let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+                                                let mut duplicates_msg = msg.get_msg_type().init_duplicates_msg(duplicates.len() as u32);
+
+                                                for (i, &(entity_key, ref duplicate_keys)) in duplicates.iter().enumerate() {
+                                                    let mut entity_duplicates = duplicates_msg.borrow().get(i as u32);
+                                                    entity_duplicates.set_entity_key(entity_key);
+
+                                                    let mut duplicate_keys_msg = entity_duplicates.init_duplicate_keys(duplicate_keys.len() as u32);
+                                                    for (j, duplicate_key) in duplicate_keys.iter().enumerate() {
+                                                        duplicate_keys_msg.set(j as u32, *duplicate_key);
+                                                    }
+                                                }
+                                            }
+
+                                            //send debug information
+                                            let debug_tx = arc_debug_tx.lock().unwrap();
+                                            debug_tx.send(format!("deduplicated {} local entities using model '{}'", duplicates.len(), model_name)).unwrap();
+
+                                            msg_builder
+                                        },
+                                    }
+                                },
+                                None => create_error_msg(&format!("unknown model '{}'", model_name)),
+                            };
+
+                            //send duplicates message
+                            capnp::serialize::write_message(&mut stream, &msg_builder).unwrap();
+                        },
+                        Ok(QueryStatsMsg(_)) => {
+                            //gather stats from every node
+                            let msg_builder = match get_node_stats(&lookup_table, &connection_pool) {
+                                Ok(node_stats) => {
+                                    //create stats message
+                                    let mut msg_builder = capnp::message::Builder::new_default();
+                                    {
+                                        let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+                                        let mut stats_msg = msg.get_msg_type().init_stats_msg(node_stats.len() as u32);
+
+                                        for (i, &(token, cache_hits, cache_misses, cache_entries, cache_bytes)) in node_stats.iter().enumerate() {
+                                            let mut stats = stats_msg.borrow().get(i as u32);
+                                            stats.set_token(token);
+                                            stats.set_cache_hits(cache_hits);
+                                            stats.set_cache_misses(cache_misses);
+                                            stats.set_cache_entries(cache_entries);
+                                            stats.set_cache_bytes(cache_bytes);
+                                        }
+                                    }
+
+                                    msg_builder
+                                },
+                                Err(e) => create_error_msg(&e),
+                            };
+
+                            //send stats message
+                            capnp::serialize::write_message(&mut stream, &msg_builder).unwrap();
+                        },
+                        Ok(QueryNodeStatsMsg(_)) => {
+                            //create stats message
+                            let mut msg_builder = capnp::message::Builder::new_default();
+                            {
+                                let filter_cache = filter_cache.lock().unwrap();
+                                let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+                                let stats_msg = msg.get_msg_type().init_stats_msg(1);
+
+                                let mut stats = stats_msg.get(0);
+                                stats.set_token(token);
+                                stats.set_cache_hits(filter_cache.hits());
+                                stats.set_cache_misses(filter_cache.misses());
+                                stats.set_cache_entries(filter_cache.len() as u64);
+                                stats.set_cache_bytes(filter_cache.size() as u64);
+                            }
 
-                stream.shutdown(Shutdown::Both).unwrap();
+                            //send stats message
+                            capnp::serialize::write_message(&mut stream, &msg_builder).unwrap();
+                        },
+                        Ok(QueryFieldValuesMsg(query_field_values_msg)) => {
+                            //copy the values so the fields lock is not held while sending them
+                            let field_values: Vec<(String,Vec<u64>)> = match fields.read().unwrap().get(query_field_values_msg.unwrap()) {
+                                Some(field_values) => field_values.iter().map(|(value, entity_keys)| (value.clone(), entity_keys.clone())).collect(),
+                                None => vec!(),
+                            };
+
+                            write_field_values(&mut stream, &field_values).unwrap();
+                        },
+                        Ok(QueryBlockValuesMsg(query_block_values_msg)) => {
+                            //copy only the requested values that are stored on this node
+                            let field_values: Vec<(String,Vec<u64>)> = match fields.read().unwrap().get(query_block_values_msg.get_field_name().unwrap()) {
+                                Some(field_values) => {
+                                    let values = query_block_values_msg.get_values().unwrap();
+                                    (0..values.len()).filter_map(|i| {
+                                        let value = values.get(i).unwrap();
+                                        field_values.get(value).map(|entity_keys| (value.to_string(), entity_keys.clone()))
+                                    }).collect()
+                                },
+                                None => vec!(),
+                            };
+
+                            write_field_values(&mut stream, &field_values).unwrap();
+                        },
+                        Ok(_) => {
+                            capnp::serialize::write_message(&mut stream, &create_error_msg("unexpected message type")).unwrap();
+                        },
+                        Err(capnp::NotInSchema(e)) => {
+                            //a message added in a later release, reject it rather than dropping the connection
+                            capnp::serialize::write_message(&mut stream, &create_error_msg(&format!("unsupported message type {}", e))).unwrap();
+                        },
+                    };
+                }
             });
         }
     });
@@ -346,20 +1199,130 @@ pub fn main() {
     }
 }
 
-fn open_write_stream(socket_addr: SocketAddrV4) -> TcpStream {
-    let mut stream = TcpStream::connect(socket_addr).unwrap();
+//resolves a hostname or ip address to the first ipv4 address, which the p2p service requires to address nodes
+fn resolve_ipv4(host: &str, port: u16) -> Result<SocketAddrV4,String> {
+    let socket_addrs = try!(transport::resolve(host, port));
+    for socket_addr in socket_addrs.iter() {
+        if let SocketAddr::V4(socket_addr) = *socket_addr {
+            return Ok(socket_addr);
+        }
+    }
+
+    Err(format!("'{}' has no ipv4 address, nodes do not support ipv6 only hosts since the p2p service addresses nodes by ipv4", host))
+}
+
+//prints the error and exits with a failure status
+fn exit_with_error(error: &str) -> ! {
+    eprintln!("{}", error);
+    process::exit(1);
+}
 
+fn open_write_stream(socket_addr: SocketAddrV4, connection_pool: &ConnectionPool) -> Result<Stream,String> {
     let mut msg_builder = capnp::message::Builder::new_default();
     {
         let msg = msg_builder.init_root::<message_capnp::message::Builder>();
         msg.get_msg_type().set_open_write_stream_msg(());
     }
-    capnp::serialize::write_message(&mut stream, &msg_builder).unwrap();
 
-    stream
+    //the peer acknowledges the stream so that a closed pooled connection is never written to
+    let (stream, msg_reader) = try!(connection_pool.open(socket_addr, &msg_builder));
+    let msg = try!(msg_reader.get_root::<message_capnp::message::Reader>().map_err(|e| format!("invalid reply from {}: {}", socket_addr, e)));
+    match msg.get_msg_type().which() {
+        Ok(ResultMsg(_)) => Ok(stream),
+        Ok(ErrorMsg(error_msg)) => Err(format!("{}: {}", socket_addr, error_msg.unwrap())),
+        Ok(_) => Err(format!("unexpected message type from {}", socket_addr)),
+        Err(capnp::NotInSchema(e)) => Err(format!("unsupported message type {} from {}", e, socket_addr)),
+    }
+}
+
+//writes a message on the write stream to a node, opening the stream on first use
+fn write_stream_msg<A: capnp::message::Allocator>(streams: &mut HashMap<SocketAddrV4,Stream>, socket_addr: SocketAddrV4, msg_builder: &capnp::message::Builder<A>, connection_pool: &ConnectionPool) -> Result<(),String> {
+    if !streams.contains_key(&socket_addr) {
+        let stream = try!(open_write_stream(socket_addr, connection_pool));
+        streams.insert(socket_addr, stream);
+    }
+
+    capnp::serialize::write_message(streams.get_mut(&socket_addr).unwrap(), msg_builder).map_err(|e| format!("failed to write to {}: {}", socket_addr, e))
+}
+
+//closes every write stream, returning the streams that closed cleanly to the connection pool
+fn close_write_streams(streams: HashMap<SocketAddrV4,Stream>, connection_pool: &ConnectionPool) {
+    let mut msg_builder = capnp::message::Builder::new_default();
+    {
+        let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+        msg.get_msg_type().set_close_write_stream_msg(());
+    }
+
+    for (socket_addr, mut stream) in streams {
+        if capnp::serialize::write_message(&mut stream, &msg_builder).is_ok() {
+            connection_pool.put(socket_addr, stream);
+        }
+    }
+}
+
+//Applies a message received on a write stream, returning true once the stream is closed. Messages other than writes
+//and malformed messages are returned as errors.
+fn apply_write_msg(msg: message_capnp::message::Reader, entities: &Arc<RwLock<HashMap<u64,HashMap<String,String>>>>, fields: &Arc<RwLock<HashMap<String,HashMap<String,Vec<u64>>>>>, tokens: &Arc<RwLock<HashMap<String,HashMap<String,Vec<u64>>>>>, tokenized_fields: &HashSet<String>, filter_cache: &Mutex<FilterCache>, normalizers: &Normalizers, blocking_keys: &BlockingKeys, arc_debug_tx: &Mutex<Sender<String>>) -> Result<bool,String> {
+    match msg.get_msg_type().which() {
+        Ok(CloseWriteStreamMsg(_)) => Ok(true),
+        Ok(WriteEntityMsg(write_entity_msg)) => {
+            //read every field before changing the entity so an invalid message writes nothing
+            let mut entity_fields = vec!();
+            for field in try!(write_entity_msg.get_fields().map_err(invalid_write_msg)).iter() {
+                entity_fields.push((try!(field.get_name().map_err(invalid_write_msg)).to_string(), try!(field.get_value().map_err(invalid_write_msg)).to_string()));
+            }
+
+            //add fields to a new or existing entity
+            let mut entities = entities.write().unwrap();
+            let entity = entities.entry(write_entity_msg.get_entity_key()).or_insert(HashMap::new());
+            for (name, value) in entity_fields {
+                entity.insert(name, value);
+            }
+
+            //send debug information
+            let debug_tx = arc_debug_tx.lock().unwrap();
+            debug_tx.send(format!("wrote entity with key {}", write_entity_msg.get_entity_key())).unwrap();
+            Ok(false)
+        },
+        Ok(WriteFieldMsg(write_field_msg)) => {
+            let field = try!(write_field_msg.get_field().map_err(invalid_write_msg));
+            let fieldname = try!(field.get_name().map_err(invalid_write_msg));
+            let field_value = normalize_field(normalizers, blocking_keys, fieldname, try!(field.get_value().map_err(invalid_write_msg)));
+
+            //search for and create entry in fields if necessary
+            let mut fields = fields.write().unwrap();
+            let field_values = fields.entry(fieldname.to_string()).or_insert(HashMap::new());
+            let entity_keys = field_values.entry(field_value.clone()).or_insert(vec!());
+            entity_keys.push(write_field_msg.get_entity_key());
+
+            //index each token of tokenized fields
+            if tokenized_fields.contains(fieldname) {
+                let mut tokens = tokens.write().unwrap();
+                let token_values = tokens.entry(fieldname.to_string()).or_insert(HashMap::new());
+                for token in fuzzydb::normalize::tokenize(&field_value) {
+                    let entity_keys = token_values.entry(token).or_insert(vec!());
+                    entity_keys.push(write_field_msg.get_entity_key());
+                }
+            }
+
+            //cached results for this field are now stale
+            filter_cache.lock().unwrap().invalidate_field(fieldname);
+
+            //send debug information
+            let debug_tx = arc_debug_tx.lock().unwrap();
+            debug_tx.send(format!("wrote field value {} for field name {} and entity key {}", field_value, fieldname, write_field_msg.get_entity_key())).unwrap();
+            Ok(false)
+        },
+        Ok(_) => Err("unexpected message type on write stream".to_string()),
+        Err(capnp::NotInSchema(e)) => Err(format!("unsupported message type {} on write stream", e)),
+    }
+}
+
+fn invalid_write_msg(e: capnp::Error) -> String {
+    format!("invalid message on write stream: {}", e)
 }
 
-fn get_entity_keys(filters: capnp::struct_list::Reader<fuzzydb::message_capnp::filter::Owned>, lookup_table: &Arc<RwLock<BTreeMap<u64,SocketAddrV4>>>) -> Vec<u64> {
+fn get_entity_keys(filters: capnp::struct_list::Reader<fuzzydb::message_capnp::filter::Owned>, lookup_table: &Arc<RwLock<BTreeMap<u64,SocketAddrV4>>>, connection_pool: &Arc<ConnectionPool>) -> Result<Vec<u64>,String> {
     let mut entity_keys = vec!();
 
     //submit filter queries
@@ -370,41 +1333,85 @@ fn get_entity_keys(filters: capnp::struct_list::Reader<fuzzydb::message_capnp::f
             params.push(filter_params.get(j).unwrap().to_string());
         }
 
-        let (keys_tx, keys_rx) = channel::<Vec<u64>>();
-        let keys_tx = Arc::new(Mutex::new(keys_tx));
-
-        //send query field messages to all peers
-        let lookup_table = lookup_table.read().unwrap();
-        for (_, peer_socket_addr) in lookup_table.iter() {
-            //create variables for query filter message
-            let field_name = filter.get_field_name().unwrap().to_string();
-            let filter_type = filter.get_filter_type().unwrap().to_string();
-            let value = filter.get_value().unwrap().to_string();
-            let (params, peer_socket_addr, keys_tx) = (params.clone(), peer_socket_addr.clone(), keys_tx.clone());
+        let filter_field_names = filter.get_field_names().unwrap();
+        let mut field_names = Vec::new();
+        for j in 0..filter_field_names.len() {
+            field_names.push(filter_field_names.get(j).unwrap().to_string());
+        }
 
-            thread::spawn(move || {
-                //create query filter message
-                let mut msg_builder = capnp::message::Builder::new_default();
-                {
-                    let msg = msg_builder.init_root::<message_capnp::message::Builder>();
-                    let mut query_filter_msg = msg.get_msg_type().init_query_filter_msg();
-                    query_filter_msg.set_field_name(&field_name[..]);
-                    query_filter_msg.set_filter_type(&filter_type[..]);
-                    query_filter_msg.set_value(&value[..]);
-
-                    let mut filter_params = query_filter_msg.init_params(params.len() as u32);
-                    for (i, param) in params.iter().enumerate() {
-                        filter_params.set(i as u32, &param[..]);
-                    }
-                }
+        let filter_type = filter.get_filter_type().unwrap().to_string();
+        let value = filter.get_value().unwrap().to_string();
+        let filter_keys = try!(get_filter_keys(field_names, filter_type, params, value, filter.get_match_tokens(), filter.get_ignore_accents(), lookup_table, connection_pool));
 
-                //send query filter message
-                let mut stream = TcpStream::connect(peer_socket_addr).unwrap();
-                capnp::serialize::write_message(&mut stream, &msg_builder).unwrap();
+        if i == 0 {
+            //first filter
+            for key in filter_keys.iter() {
+                entity_keys.push(*key);
+            }
+        } else {
+            //compute intersection with our running entity keys
+            entity_keys = entity_keys.iter().filter(|x| filter_keys.contains(x)).map(|x| *x).collect();
+        }
 
-                //read entity tokens message
-                let msg_reader = capnp::serialize::read_message(&mut stream, ::capnp::message::ReaderOptions::new()).unwrap();
-                let msg = msg_reader.get_root::<message_capnp::message::Reader>().unwrap();
+        //if no tokens then no need to loop through more filters
+        if entity_keys.is_empty() {
+            break;
+        }
+    }
+
+    Ok(entity_keys)
+}
+
+fn get_filter_keys(field_names: Vec<String>, filter_type: String, params: Vec<String>, value: String, match_tokens: bool, ignore_accents: bool, lookup_table: &Arc<RwLock<BTreeMap<u64,SocketAddrV4>>>, connection_pool: &Arc<ConnectionPool>) -> Result<Vec<u64>,String> {
+    //nearest parameters are checked before any node is asked for its values
+    let k = match &filter_type[..] {
+        "nearest" => {
+            let params: Vec<&str> = params.iter().map(|x| &x[..]).collect();
+            let (_, k, _) = try!(fuzzydb::query::parse_nearest_params(&params[..]));
+            k
+        },
+        _ => 0,
+    };
+
+    let (keys_tx, keys_rx) = channel::<Result<NodeFilterResult,String>>();
+
+    //send query field messages to all peers, the lookup table is not held while waiting on them
+    let peer_socket_addrs: Vec<SocketAddrV4> = lookup_table.read().unwrap().values().cloned().collect();
+    let request_pool = connection_pool.clone();
+    for peer_socket_addr in peer_socket_addrs.iter() {
+        //create variables for query filter message
+        let (field_names, filter_type, params, value, peer_socket_addr, connection_pool, keys_tx) = (field_names.clone(), filter_type.clone(), params.clone(), value.clone(), peer_socket_addr.clone(), connection_pool.clone(), keys_tx.clone());
+
+        request_pool.execute(move || {
+            //create query filter message
+            let mut msg_builder = capnp::message::Builder::new_default();
+            {
+                let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+                let mut query_filter_msg = match &filter_type[..] {
+                    "nearest" => msg.get_msg_type().init_query_nearest_msg(),
+                    _ => msg.get_msg_type().init_query_filter_msg(),
+                };
+                query_filter_msg.set_filter_type(&filter_type[..]);
+                query_filter_msg.set_value(&value[..]);
+                query_filter_msg.set_match_tokens(match_tokens);
+                query_filter_msg.set_ignore_accents(ignore_accents);
+
+                {
+                    let mut filter_field_names = query_filter_msg.borrow().init_field_names(field_names.len() as u32);
+                    for (i, field_name) in field_names.iter().enumerate() {
+                        filter_field_names.set(i as u32, &field_name[..]);
+                    }
+                }
+
+                let mut filter_params = query_filter_msg.init_params(params.len() as u32);
+                for (i, param) in params.iter().enumerate() {
+                    filter_params.set(i as u32, &param[..]);
+                }
+            }
+
+            //send query filter message and read entity tokens message
+            let result = connection_pool.request(peer_socket_addr, &msg_builder).and_then(|msg_reader| {
+                let msg = try!(msg_reader.get_root::<message_capnp::message::Reader>().map_err(|e| format!("invalid reply from {}: {}", peer_socket_addr, e)));
 
                 //parse out message
                 match msg.get_msg_type().which() {
@@ -416,86 +1423,974 @@ fn get_entity_keys(filters: capnp::struct_list::Reader<fuzzydb::message_capnp::f
                             keys.push(entity_keys.get(i));
                         }
 
-                        let keys_tx = keys_tx.lock().unwrap();
-                        keys_tx.send(keys).unwrap();
+                        Ok(NodeFilterResult::EntityKeys(keys))
+                    },
+                    Ok(ScoredValuesMsg(scored_values_msg)) => {
+                        let mut scored_values = vec!();
+                        for scored_value in scored_values_msg.unwrap().iter() {
+                            let entity_keys = scored_value.get_entity_keys().unwrap();
+                            let mut keys = vec!();
+                            for i in 0..entity_keys.len() {
+                                keys.push(entity_keys.get(i));
+                            }
+
+                            scored_values.push((scored_value.get_score(), scored_value.get_value().unwrap().to_string(), keys));
+                        }
+
+                        Ok(NodeFilterResult::ScoredValues(scored_values))
+                    },
+                    Ok(ErrorMsg(error_msg)) => Err(format!("{}: {}", peer_socket_addr, error_msg.unwrap())),
+                    Ok(_) => Err(format!("unexpected message type from {}", peer_socket_addr)),
+                    Err(capnp::NotInSchema(e)) => Err(format!("unsupported message type {} from {}", e, peer_socket_addr)),
+                }
+            });
+
+            //the query may have already failed on another node
+            let _ = keys_tx.send(result);
+        });
+    }
+
+    //compile set of keys for filter, a request that panics drops its sender so the receive fails rather than blocking
+    drop(keys_tx);
+    let mut filter_keys = vec!();
+    let mut value_scores: HashMap<String,(f64,Vec<u64>)> = HashMap::new();
+    for _ in 0..peer_socket_addrs.len() {
+        match try!(recv_node_result(&keys_rx)) {
+            NodeFilterResult::EntityKeys(keys) => {
+                for key in keys {
+                    filter_keys.push(key);
+                }
+            },
+            NodeFilterResult::ScoredValues(scored_values) => {
+                for (score, value, keys) in scored_values {
+                    let value_score = value_scores.entry(value).or_insert((score, vec!()));
+                    if score > value_score.0 {
+                        value_score.0 = score;
+                    }
+
+                    value_score.1.extend(keys);
+                }
+            },
+        }
+    }
+
+    //merge the nearest values of every node into the global nearest values
+    if !value_scores.is_empty() {
+        for (_, _, keys) in get_nearest_values(value_scores, k) {
+            filter_keys.extend(keys);
+        }
+    }
+
+    //filters over several fields may match an entity on more than one node
+    filter_keys.sort();
+    filter_keys.dedup();
+    Ok(filter_keys)
+}
+
+//receives the result of a request sent to another node, where a request that ended without sending its result failed
+fn recv_node_result<T>(result_rx: &Receiver<Result<T,String>>) -> Result<T,String> {
+    match result_rx.recv() {
+        Ok(result) => result,
+        Err(_) => Err("a request to another node failed without a reply".to_string()),
+    }
+}
+
+fn get_nearest_values(value_scores: HashMap<String,(f64,Vec<u64>)>, k: usize) -> Vec<(f64,String,Vec<u64>)> {
+    //order from best to worst score, breaking ties on the value
+    let mut scored_values: Vec<(f64,String,Vec<u64>)> = value_scores.into_iter().map(|(value, (score, keys))| (score, value, keys)).collect();
+    scored_values.sort_by(|a, b| {
+        match b.0.partial_cmp(&a.0) {
+            Some(Ordering::Equal) | None => a.1.cmp(&b.1),
+            Some(ordering) => ordering,
+        }
+    });
+
+    scored_values.truncate(k);
+    scored_values
+}
+
+fn get_entities(entity_keys: Vec<u64>, field_names: Vec<String>, lookup_table: &Arc<RwLock<BTreeMap<u64,SocketAddrV4>>>, connection_pool: &Arc<ConnectionPool>) -> Result<HashMap<u64,HashMap<String,String>>,String> {
+    let (entities_tx, entities_rx) = channel::<Result<Vec<(u64,HashMap<String,String>)>,String>>();
+    let batch_count = fetch_entities(entity_keys, field_names, lookup_table, connection_pool, entities_tx);
+
+    //compile entities from every node
+    let mut entities = HashMap::new();
+    for _ in 0..batch_count {
+        entities.extend(try!(recv_node_result(&entities_rx)));
+    }
+
+    Ok(entities)
+}
+
+//sends the entities of each batch of keys on the channel as soon as its node replies and returns the number of batches
+//requested, each of which sends either its entities or the reason its request failed
+fn fetch_entities(entity_keys: Vec<u64>, field_names: Vec<String>, lookup_table: &Arc<RwLock<BTreeMap<u64,SocketAddrV4>>>, connection_pool: &Arc<ConnectionPool>, entities_tx: Sender<Result<Vec<(u64,HashMap<String,String>)>,String>>) -> usize {
+    //group entity keys by the node they are stored on
+    let mut node_entity_keys = HashMap::new();
+    {
+        let lookup_table = lookup_table.read().unwrap();
+        for entity_key in entity_keys {
+            let socket_addr = rustdht::service::lookup(&lookup_table, entity_key).unwrap();
+            node_entity_keys.entry(socket_addr).or_insert(vec!()).push(entity_key);
+        }
+    }
+
+    //split the keys of each node into batches, so no reply from a node exceeds the size of an entities chunk
+    let mut batches = vec!();
+    for (socket_addr, entity_keys) in node_entity_keys {
+        for batch in entity_keys.chunks(ENTITY_CHUNK_SIZE) {
+            batches.push((socket_addr, batch.to_vec()));
+        }
+    }
+
+    //send one query entities message per batch, with a bounded number of batches requested at once
+    let batch_count = batches.len();
+    let node_entity_keys = Arc::new(Mutex::new(batches));
+    let request_pool = connection_pool.clone();
+    for _ in 0..cmp::min(batch_count, MAX_NODE_REQUESTS) {
+        let (node_entity_keys, field_names, connection_pool, entities_tx) = (node_entity_keys.clone(), field_names.clone(), connection_pool.clone(), entities_tx.clone());
+
+        request_pool.execute(move || {
+            loop {
+                let (socket_addr, entity_keys) = match node_entity_keys.lock().unwrap().pop() {
+                    Some(node) => node,
+                    None => break,
+                };
+
+                //create query entities message
+                let mut msg_builder = capnp::message::Builder::new_default();
+                {
+                    let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+                    let mut query_entities_msg = msg.get_msg_type().init_query_entities_msg();
+                    {
+                        let mut entity_keys_msg = query_entities_msg.borrow().init_entity_keys(entity_keys.len() as u32);
+                        for (i, entity_key) in entity_keys.iter().enumerate() {
+                            entity_keys_msg.set(i as u32, *entity_key);
+                        }
+                    }
+
+                    let mut field_names_msg = query_entities_msg.init_field_names(field_names.len() as u32);
+                    for (i, field_name) in field_names.iter().enumerate() {
+                        field_names_msg.set(i as u32, &field_name[..]);
+                    }
+                }
+
+                //send query entities message and read entities message
+                let result = connection_pool.request(socket_addr, &msg_builder).and_then(|msg_reader| {
+                    let msg = try!(msg_reader.get_root::<message_capnp::message::Reader>().map_err(|e| format!("invalid reply from {}: {}", socket_addr, e)));
+
+                    //parse out message
+                    match msg.get_msg_type().which() {
+                        Ok(EntitiesMsg(entities_msg)) => {
+                            let mut entities = vec!();
+                            for entity_msg in entities_msg.unwrap().iter() {
+                                let mut entity = HashMap::new();
+                                for field in entity_msg.get_fields().unwrap().iter() {
+                                    entity.insert(field.get_name().unwrap().to_string(), field.get_value().unwrap().to_string());
+                                }
+
+                                entities.push((entity_msg.get_key(), entity));
+                            }
+
+                            Ok(entities)
+                        },
+                        Ok(ErrorMsg(error_msg)) => Err(format!("{}: {}", socket_addr, error_msg.unwrap())),
+                        Ok(_) => Err(format!("unexpected message type from {}", socket_addr)),
+                        Err(capnp::NotInSchema(e)) => Err(format!("unsupported message type {} from {}", e, socket_addr)),
+                    }
+                });
+
+                //stop once the receiver has given up on the request
+                if entities_tx.send(result).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    batch_count
+}
+
+fn get_candidate_keys(record: &HashMap<String,String>, model: &MatchModel, blocking_keys: &BlockingKeys, normalizers: &Normalizers, synonyms: &Synonyms, lookup_table: &Arc<RwLock<BTreeMap<u64,SocketAddrV4>>>, connection_pool: &Arc<ConnectionPool>) -> Result<Vec<(f64,u64,Vec<(String,f64)>)>,String> {
+    //only the comparators of fields the record has are weighed
+    let comparisons: Vec<(&Comparator,Comparison)> = try!(parse_comparators(model, synonyms)).into_iter()
+        .filter(|&(comparator, _)| record.contains_key(&comparator.field_name))
+        .collect();
+
+    //find the entities sharing a block with the record
+    let (mut blocked, mut block_keys) = (false, HashSet::new());
+    for block in model.blocks.iter() {
+        if let Some(value) = blocking_keys.get(block).and_then(|blocking_key| blocking_key.compute(record, normalizers)) {
+            block_keys.extend(try!(get_filter_keys(vec!(block.clone()), "equality".to_string(), vec!(), value, false, false, lookup_table, connection_pool)));
+            blocked = true;
+        }
+    }
+
+    let mut candidates = vec!();
+    if blocked {
+        //fetch the compared fields of the block members and compare them here, rather than scanning every node
+        let field_names = comparisons.iter().map(|&(comparator, _)| comparator.field_name.clone()).collect();
+        for (entity_key, entity) in try!(get_entities(block_keys.into_iter().collect(), field_names, lookup_table, connection_pool)) {
+            let agreements = get_agreements(&comparisons, record, &entity, normalizers, synonyms);
+            if agreements.iter().any(|&(_, agrees)| agrees) {
+                let field_weights = get_field_weights(&agreements);
+                candidates.push((field_weights.iter().fold(0.0, |weight, &(_, field_weight)| weight + field_weight), entity_key, field_weights));
+            }
+        }
+    } else {
+        //without a block every node scans its values for the entities agreeing on each compared field
+        let mut comparator_keys = vec!();
+        for &(comparator, _) in comparisons.iter() {
+            let value = record[&comparator.field_name].clone();
+            let keys = try!(get_filter_keys(vec!(comparator.field_name.clone()), comparator.filter_type.clone(), comparator.params.clone(), value, false, comparator.ignore_accents, lookup_table, connection_pool));
+            comparator_keys.push((comparator, keys.into_iter().collect::<HashSet<u64>>()));
+        }
+
+        //weight every entity that agrees on at least one field
+        let mut candidate_keys = HashSet::new();
+        for &(_, ref keys) in comparator_keys.iter() {
+            candidate_keys.extend(keys.iter().cloned());
+        }
+
+        for entity_key in candidate_keys {
+            let agreements: Vec<(&Comparator,bool)> = comparator_keys.iter().map(|&(comparator, ref keys)| (comparator, keys.contains(&entity_key))).collect();
+            let field_weights = get_field_weights(&agreements);
+            candidates.push((field_weights.iter().fold(0.0, |weight, &(_, field_weight)| weight + field_weight), entity_key, field_weights));
+        }
+    }
+
+    //order candidates from highest to lowest weight
+    candidates.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+    Ok(candidates)
+}
+
+//parses the comparison of every comparator in the model, synonym comparators apply the filter they name to each expanded value
+fn parse_comparators<'a>(model: &'a MatchModel, synonyms: &Synonyms) -> Result<Vec<(&'a Comparator,Comparison)>,String> {
+    let mut comparisons = vec!();
+    for comparator in model.comparators.iter() {
+        let params: Vec<&str> = comparator.params.iter().map(|x| &x[..]).collect();
+        let comparison = match &comparator.filter_type[..] {
+            "synonym" => {
+                let (filter_type, filter_params) = try!(synonyms.filter(&params[..]));
+                try!(Comparison::parse(filter_type, &filter_params[..]))
+            },
+            filter_type => try!(Comparison::parse(filter_type, &params[..])),
+        };
+
+        comparisons.push((comparator, comparison));
+    }
+
+    Ok(comparisons)
+}
+
+//returns the values a record value is compared as, which are every synonym of the value for synonym comparators
+fn get_comparator_values(comparator: &Comparator, value: &str, normalizers: &Normalizers, synonyms: &Synonyms) -> Vec<String> {
+    let values = match &comparator.filter_type[..] {
+        "synonym" => {
+            let params: Vec<&str> = comparator.params.iter().map(|x| &x[..]).collect();
+            //dictionaries were checked when the comparators were parsed
+            synonyms.expand_filter(&params[..], value, normalizers.pipeline(&comparator.field_name)).map(|(_, _, values)| values).unwrap_or(vec!(value.to_string()))
+        },
+        _ => vec!(value.to_string()),
+    };
+
+    values.iter().map(|value| normalize_value(normalizers, &comparator.field_name, value, comparator.ignore_accents)).collect()
+}
+
+//weighs an entity against a record, skipping comparators of fields the record does not have and returning none when
+//they agree on no compared field
+fn get_weight(comparisons: &[(&Comparator,Comparison)], record: &HashMap<String,String>, entity: &HashMap<String,String>, normalizers: &Normalizers, synonyms: &Synonyms) -> Option<f64> {
+    let agreements = get_agreements(comparisons, record, entity, normalizers, synonyms);
+    if agreements.iter().any(|&(_, agrees)| agrees) {
+        Some(get_field_weights(&agreements).iter().fold(0.0, |weight, &(_, field_weight)| weight + field_weight))
+    } else {
+        None
+    }
+}
+
+//returns whether the entity agrees with the record on each comparator of a field the record has
+fn get_agreements<'a>(comparisons: &[(&'a Comparator,Comparison)], record: &HashMap<String,String>, entity: &HashMap<String,String>, normalizers: &Normalizers, synonyms: &Synonyms) -> Vec<(&'a Comparator,bool)> {
+    let mut agreements = vec!();
+    for &(comparator, ref comparison) in comparisons.iter() {
+        let value = match record.get(&comparator.field_name) {
+            Some(value) => value,
+            None => continue,
+        };
+
+        let agrees = match entity.get(&comparator.field_name) {
+            Some(entity_value) => {
+                let entity_value = normalize_value(normalizers, &comparator.field_name, entity_value, comparator.ignore_accents);
+                get_comparator_values(comparator, value, normalizers, synonyms).iter().any(|value| comparison.is_match(&entity_value, value))
+            },
+            None => false,
+        };
+
+        agreements.push((comparator, agrees));
+    }
+
+    agreements
+}
+
+//returns the weight each compared field adds, summing the weights of every comparator of the field
+fn get_field_weights(agreements: &[(&Comparator,bool)]) -> Vec<(String,f64)> {
+    let mut field_weights: Vec<(String,f64)> = vec!();
+    for &(comparator, agrees) in agreements.iter() {
+        let weight = if agrees { comparator.agreement_weight } else { comparator.disagreement_weight };
+        match field_weights.iter().position(|x| x.0 == comparator.field_name) {
+            Some(i) => field_weights[i].1 += weight,
+            None => field_weights.push((comparator.field_name.clone(), weight)),
+        }
+    }
+
+    field_weights
+}
+
+//returns the fields a filter on '*' searches, leaving out the blocking keys and cluster ids the node indexes itself
+fn get_user_field_names<'a>(fields: &'a HashMap<String,HashMap<String,Vec<u64>>>, blocking_keys: &BlockingKeys) -> Vec<&'a str> {
+    fields.keys()
+        .filter(|x| !blocking_keys.is_synthetic_field(x))
+        .map(|x| &x[..])
+        .collect()
+}
+
+//normalizes a value of a field when it is written or queried
+fn normalize_field(normalizers: &Normalizers, blocking_keys: &BlockingKeys, field_name: &str, value: &str) -> String {
+    fuzzydb::normalize::apply_pipeline(get_pipeline(normalizers, blocking_keys, field_name), value)
+}
+
+//returns the normalizers of a field, blocking key values are already normalized when they are computed and cluster
+//ids are never normalized, so neither has any
+fn get_pipeline<'a>(normalizers: &'a Normalizers, blocking_keys: &BlockingKeys, field_name: &str) -> &'a [Normalizer] {
+    if blocking_keys.is_synthetic_field(field_name) { &[] } else { normalizers.pipeline(field_name) }
+}
+
+//normalizes a value the way it is indexed for the field, without accents when they are ignored
+fn normalize_value(normalizers: &Normalizers, field_name: &str, value: &str, ignore_accents: bool) -> String {
+    let value = normalizers.normalize(field_name, value);
+    if ignore_accents { fuzzydb::compare::strip_accents(&value) } else { value }
+}
+
+//Finds the duplicates of the entities stored on this node, returning every local entity along with the entities it
+//matches under the model. The values of each block, or of each compared field when the model has no blocks, stored
+//here are compared with the values of that field on every node in a single pass, pairing the entities of values that
+//agree. Each pair is then weighed once from the
+//compared fields of its entities, which are fetched together.
+fn get_local_duplicates(model: &MatchModel, entities: &Arc<RwLock<HashMap<u64,HashMap<String,String>>>>, fields: &Arc<RwLock<HashMap<String,HashMap<String,Vec<u64>>>>>, normalizers: &Normalizers, synonyms: &Synonyms, blocking_keys: &BlockingKeys, query_pool: &ThreadPool, lookup_table: &Arc<RwLock<BTreeMap<u64,SocketAddrV4>>>, connection_pool: &Arc<ConnectionPool>) -> Result<Vec<(u64,Vec<u64>)>,String> {
+    let comparisons = try!(parse_comparators(model, synonyms));
+
+    let mut pairs = HashSet::new();
+    if !model.blocks.is_empty() {
+        //pair the local entities with every entity sharing one of their block values, asking the
+        //nodes for a chunk of the block values at a time rather than for every value they hold
+        for block in model.blocks.iter() {
+            let local_values = get_local_values(block, fields);
+            for chunk in local_values.chunks(FIELD_VALUE_CHUNK_SIZE) {
+                let chunk_values: HashMap<&str,&[u64]> = chunk.iter().map(|&(ref value, ref entity_keys)| (&value[..], &entity_keys[..])).collect();
+                let values: Vec<String> = chunk.iter().map(|&(ref value, _)| value.clone()).collect();
+                try!(get_field_values(block, Some(values), lookup_table, connection_pool, |block_values| {
+                    for (value, block_keys) in block_values {
+                        if let Some(entity_keys) = chunk_values.get(&value[..]) {
+                            add_pairs(&mut pairs, entity_keys, &block_keys);
+                        }
+                    }
+
+                    Ok(())
+                }));
+            }
+        }
+    } else {
+        for &(comparator, _) in comparisons.iter() {
+            let local_values = get_local_values(&comparator.field_name, fields);
+            if local_values.is_empty() {
+                continue;
+            }
+
+            //compare the local values with each chunk of the values of every node on the query threads as it is
+            //read, refusing fields too large to compare every pair of values
+            let params: Vec<&str> = comparator.params.iter().map(|x| &x[..]).collect();
+            let mut value_count = 0;
+            try!(get_field_values(&comparator.field_name, None, lookup_table, connection_pool, |chunk| {
+                value_count += chunk.len();
+                if value_count > MAX_UNBLOCKED_VALUES {
+                    return Err(format!("field '{}' holds more than {} values to compare every pair of, add a block to the model", comparator.field_name, MAX_UNBLOCKED_VALUES));
+                }
+
+                let mut field_values = HashMap::new();
+                field_values.insert(comparator.field_name.clone(), chunk.into_iter().collect::<HashMap<String,Vec<u64>>>());
+                for &(ref value, ref entity_keys) in local_values.iter() {
+                    let (filter_type, filter_params, values) = match &comparator.filter_type[..] {
+                        "synonym" => try!(synonyms.expand_filter(&params[..], value, normalizers.pipeline(&comparator.field_name))),
+                        filter_type => (filter_type, params.clone(), vec!(value.clone())),
+                    };
+
+                    for value in values {
+                        let value = normalizers.normalize(&comparator.field_name, &value);
+                        let matched_keys: Vec<u64> = try!(fuzzydb::query::query_field(&[&comparator.field_name[..]], filter_type, filter_params.clone(), &value, comparator.ignore_accents, &field_values, blocking_keys, query_pool)).into_iter().collect();
+                        add_pairs(&mut pairs, entity_keys, &matched_keys);
+                    }
+                }
+
+                Ok(())
+            }));
+        }
+    }
+
+    //every local entity is reported, so that entities without duplicates are given a cluster of their own
+    let mut duplicates: HashMap<u64,Vec<u64>> = entities.read().unwrap().keys().map(|entity_key| (*entity_key, vec!())).collect();
+
+    //fetch the compared fields of every paired entity at once and keep the pairs that match
+    let mut entity_keys: Vec<u64> = pairs.iter().flat_map(|&(a, b)| vec!(a, b)).collect();
+    entity_keys.sort();
+    entity_keys.dedup();
+
+    let mut field_names: Vec<String> = comparisons.iter().map(|&(comparator, _)| comparator.field_name.clone()).collect();
+    field_names.sort();
+    field_names.dedup();
+
+    let paired_entities = try!(get_entities(entity_keys, field_names, lookup_table, connection_pool));
+    for (a, b) in pairs {
+        if let (Some(entity), Some(duplicate)) = (paired_entities.get(&a), paired_entities.get(&b)) {
+            let matched = get_weight(&comparisons, entity, duplicate, normalizers, synonyms).map(|weight| model.classify(weight) == Classification::Match).unwrap_or(false);
+            if matched {
+                //the values paired here may belong to entities stored on any node
+                duplicates.entry(a).or_insert(vec!()).push(b);
+            }
+        }
+    }
+
+    Ok(duplicates.into_iter().collect())
+}
+
+//returns the values of a field stored on this node along with the entities holding each value
+fn get_local_values(field_name: &str, fields: &Arc<RwLock<HashMap<String,HashMap<String,Vec<u64>>>>>) -> Vec<(String,Vec<u64>)> {
+    match fields.read().unwrap().get(field_name) {
+        Some(field_values) => field_values.iter().map(|(value, entity_keys)| (value.clone(), entity_keys.clone())).collect(),
+        None => vec!(),
+    }
+}
+
+//adds every pair of distinct entities taking one entity from each list, with the smaller entity key first
+fn add_pairs(pairs: &mut HashSet<(u64,u64)>, entity_keys: &[u64], other_entity_keys: &[u64]) {
+    for entity_key in entity_keys {
+        for other_entity_key in other_entity_keys {
+            if entity_key < other_entity_key {
+                pairs.insert((*entity_key, *other_entity_key));
+            } else if other_entity_key < entity_key {
+                pairs.insert((*other_entity_key, *entity_key));
+            }
+        }
+    }
+}
+
+//reads the values of a field on every node along with the entities holding each value, or only the given values,
+//passing each chunk to a function as it arrives so that no node's values are ever held at once
+fn get_field_values<F>(field_name: &str, values: Option<Vec<String>>, lookup_table: &Arc<RwLock<BTreeMap<u64,SocketAddrV4>>>, connection_pool: &Arc<ConnectionPool>, mut f: F) -> Result<(),String> where F: FnMut(Vec<(String,Vec<u64>)>) -> Result<(),String> {
+    //each node sends its chunks followed by none once it has sent every value
+    let peer_socket_addrs: Vec<SocketAddrV4> = lookup_table.read().unwrap().values().cloned().collect();
+    let (values_tx, values_rx) = sync_channel::<Result<Option<Vec<(String,Vec<u64>)>>,String>>(peer_socket_addrs.len());
+
+    //send query field values messages to all peers, the lookup table is not held while waiting on them
+    let request_pool = connection_pool.clone();
+    for peer_socket_addr in peer_socket_addrs.iter() {
+        let (field_name, values, peer_socket_addr, connection_pool, values_tx) = (field_name.to_string(), values.clone(), peer_socket_addr.clone(), connection_pool.clone(), values_tx.clone());
+
+        request_pool.execute(move || {
+            //create query field values or query block values message
+            let mut msg_builder = capnp::message::Builder::new_default();
+            {
+                let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+                match values {
+                    Some(values) => {
+                        let mut query_block_values_msg = msg.get_msg_type().init_query_block_values_msg();
+                        query_block_values_msg.set_field_name(&field_name[..]);
+
+                        let mut values_msg = query_block_values_msg.init_values(values.len() as u32);
+                        for (i, value) in values.iter().enumerate() {
+                            values_msg.set(i as u32, &value[..]);
+                        }
                     },
-                    Ok(_) => panic!("Unknown message type"),
-                    Err(capnp::NotInSchema(e)) => panic!("Error capnp::NotInSchema: {}", e),
+                    None => msg.get_msg_type().set_query_field_values_msg(&field_name[..]),
                 }
+            }
 
+            //send the message and pass on field values messages until an empty one
+            let result = connection_pool.open(peer_socket_addr, &msg_builder).and_then(|(mut stream, mut msg_reader)| {
+                loop {
+                    let field_values = {
+                        let msg = try!(msg_reader.get_root::<message_capnp::message::Reader>().map_err(|e| format!("invalid reply from {}: {}", peer_socket_addr, e)));
+                        match msg.get_msg_type().which() {
+                            Ok(FieldValuesMsg(field_values_msg)) => {
+                                let mut field_values = vec!();
+                                for field_value in try!(field_values_msg.map_err(|e| format!("invalid reply from {}: {}", peer_socket_addr, e))).iter() {
+                                    let entity_keys = try!(field_value.get_entity_keys().map_err(|e| format!("invalid reply from {}: {}", peer_socket_addr, e)));
+                                    let value = try!(field_value.get_value().map_err(|e| format!("invalid reply from {}: {}", peer_socket_addr, e)));
+                                    field_values.push((value.to_string(), (0..entity_keys.len()).map(|i| entity_keys.get(i)).collect()));
+                                }
+
+                                field_values
+                            },
+                            Ok(ErrorMsg(error_msg)) => return Err(format!("{}: {}", peer_socket_addr, error_msg.unwrap_or("unknown error"))),
+                            Ok(_) => return Err(format!("unexpected message type from {}", peer_socket_addr)),
+                            Err(capnp::NotInSchema(e)) => return Err(format!("unsupported message type {} from {}", e, peer_socket_addr)),
+                        }
+                    };
+
+                    if field_values.is_empty() {
+                        break;
+                    }
+
+                    //stop reading once the receiver has given up, dropping the connection mid stream
+                    if values_tx.send(Ok(Some(field_values))).is_err() {
+                        return Ok(false);
+                    }
+
+                    msg_reader = try!(capnp::serialize::read_message(&mut stream, ::capnp::message::ReaderOptions::new()).map_err(|e| format!("failed to read from {}: {}", peer_socket_addr, e)));
+                }
+
+                connection_pool.put(peer_socket_addr, stream);
+                Ok(true)
             });
+
+            //the deduplication may have already failed on another node
+            let _ = match result {
+                Ok(true) => values_tx.send(Ok(None)),
+                Ok(false) => Ok(()),
+                Err(e) => values_tx.send(Err(e)),
+            };
+        });
+    }
+
+    //pass on the values of every node until each has sent them all
+    drop(values_tx);
+    let mut node_count = peer_socket_addrs.len();
+    while node_count != 0 {
+        match try!(recv_node_result(&values_rx)) {
+            Some(field_values) => try!(f(field_values)),
+            None => node_count -= 1,
         }
+    }
 
-        //compile set of keys for filter
-        let mut filter_keys = vec!();
-        for _ in 0..lookup_table.len() {
-            let keys = keys_rx.recv().unwrap();
+    Ok(())
+}
 
-            for key in keys {
-                filter_keys.push(key);
+//sends field values messages in chunks, ending with an empty chunk
+fn write_field_values(stream: &mut Stream, field_values: &[(String,Vec<u64>)]) -> Result<(),capnp::Error> {
+    let mut chunks: Vec<&[(String,Vec<u64>)]> = field_values.chunks(FIELD_VALUE_CHUNK_SIZE).collect();
+    chunks.push(&[]);
+    for chunk in chunks {
+        let mut msg_builder = capnp::message::Builder::new_default();
+        {
+            let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+            let mut field_values_msg = msg.get_msg_type().init_field_values_msg(chunk.len() as u32);
+
+            for (i, &(ref value, ref entity_keys)) in chunk.iter().enumerate() {
+                let mut field_value = field_values_msg.borrow().get(i as u32);
+                field_value.set_value(value);
+
+                let mut entity_keys_msg = field_value.init_entity_keys(entity_keys.len() as u32);
+                for (j, entity_key) in entity_keys.iter().enumerate() {
+                    entity_keys_msg.set(j as u32, *entity_key);
+                }
             }
         }
 
-        if i == 0 {
-            //first filter
-            for key in filter_keys.iter() {
-                entity_keys.push(*key);
+        try!(capnp::serialize::write_message(stream, &msg_builder));
+    }
+
+    Ok(())
+}
+
+fn get_candidates(record: &HashMap<String,String>, model: &MatchModel, limit: usize, blocking_keys: &BlockingKeys, normalizers: &Normalizers, synonyms: &Synonyms, lookup_table: &Arc<RwLock<BTreeMap<u64,SocketAddrV4>>>, connection_pool: &Arc<ConnectionPool>) -> Result<Vec<(f64,Classification,HashMap<String,String>,Vec<(String,f64)>)>,String> {
+    //keep the highest weighted candidates
+    let mut candidates = try!(get_candidate_keys(record, model, blocking_keys, normalizers, synonyms, lookup_table, connection_pool));
+    candidates.truncate(limit);
+
+    //fetch candidate entities
+    let mut entities = try!(get_entities(candidates.iter().map(|x| x.1).collect(), vec!(), lookup_table, connection_pool));
+
+    Ok(candidates.into_iter().filter_map(|(weight, entity_key, field_weights)| {
+        entities.remove(&entity_key).map(|entity| (weight, model.classify(weight), entity, field_weights))
+    }).collect())
+}
+
+fn get_duplicates(model_name: &str, lookup_table: &Arc<RwLock<BTreeMap<u64,SocketAddrV4>>>, connection_pool: &Arc<ConnectionPool>) -> Result<Vec<(u64,Vec<u64>)>,String> {
+    //create deduplicate node message
+    let mut msg_builder = capnp::message::Builder::new_default();
+    {
+        let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+        msg.get_msg_type().set_deduplicate_node_msg(model_name);
+    }
+
+    //each node fans out requests of its own while handling the message, so it is sent to every
+    //node from this thread rather than from request threads the handlers may need
+    let peer_socket_addrs: Vec<SocketAddrV4> = lookup_table.read().unwrap().values().cloned().collect();
+    let results = connection_pool.request_all(&peer_socket_addrs, &msg_builder);
+
+    //compile duplicates from every node
+    let mut duplicates = vec!();
+    for (peer_socket_addr, result) in peer_socket_addrs.iter().zip(results) {
+        let msg_reader = try!(result);
+        let msg = try!(msg_reader.get_root::<message_capnp::message::Reader>().map_err(|e| format!("invalid reply from {}: {}", peer_socket_addr, e)));
+
+        //parse out message
+        match msg.get_msg_type().which() {
+            Ok(DuplicatesMsg(duplicates_msg)) => {
+                let duplicates_msg = try!(duplicates_msg.map_err(|e| format!("invalid reply from {}: {}", peer_socket_addr, e)));
+                for entity_duplicates in duplicates_msg.iter() {
+                    let duplicate_keys_msg = try!(entity_duplicates.get_duplicate_keys().map_err(|e| format!("invalid reply from {}: {}", peer_socket_addr, e)));
+                    let mut duplicate_keys = Vec::new();
+                    for i in 0..duplicate_keys_msg.len() {
+                        duplicate_keys.push(duplicate_keys_msg.get(i));
+                    }
+
+                    duplicates.push((entity_duplicates.get_entity_key(), duplicate_keys));
+                }
+            },
+            Ok(ErrorMsg(error_msg)) => return Err(format!("{}: {}", peer_socket_addr, error_msg.unwrap_or("unknown error"))),
+            Ok(_) => return Err(format!("unexpected message type from {}", peer_socket_addr)),
+            Err(capnp::NotInSchema(e)) => return Err(format!("unsupported message type {} from {}", e, peer_socket_addr)),
+        }
+    }
+
+    Ok(duplicates)
+}
+
+fn get_cluster_ids(duplicates: Vec<(u64,Vec<u64>)>) -> HashMap<u64,u64> {
+    //union entities with their duplicates, where the root of each set is its smallest entity key
+    let mut parents = HashMap::new();
+    for (entity_key, duplicate_keys) in duplicates {
+        parents.entry(entity_key).or_insert(entity_key);
+        for duplicate_key in duplicate_keys {
+            parents.entry(duplicate_key).or_insert(duplicate_key);
+
+            let (root, duplicate_root) = (find_root(&mut parents, entity_key), find_root(&mut parents, duplicate_key));
+            if root < duplicate_root {
+                parents.insert(duplicate_root, root);
+            } else if duplicate_root < root {
+                parents.insert(root, duplicate_root);
             }
-        } else {
-            //compute intersection with our running entity keys
-            entity_keys = entity_keys.iter().filter(|x| filter_keys.contains(x)).map(|x| *x).collect();
         }
+    }
 
-        //if no tokens then no need to loop through more filters
-        if entity_keys.is_empty() {
+    //the cluster id of each entity is the root of its set
+    let entity_keys: Vec<u64> = parents.keys().cloned().collect();
+    entity_keys.into_iter().map(|entity_key| (entity_key, find_root(&mut parents, entity_key))).collect()
+}
+
+fn find_root(parents: &mut HashMap<u64,u64>, entity_key: u64) -> u64 {
+    let parent = parents[&entity_key];
+    if parent == entity_key {
+        return entity_key;
+    }
+
+    let root = find_root(parents, parent);
+    parents.insert(entity_key, root);
+    root
+}
+
+fn write_cluster_ids(cluster_ids: &HashMap<u64,u64>, lookup_table: &Arc<RwLock<BTreeMap<u64,SocketAddrV4>>>, connection_pool: &Arc<ConnectionPool>) -> Result<(),String> {
+    let lookup_table = lookup_table.read().unwrap();
+    let mut streams = HashMap::new();
+    let mut result = Ok(());
+    for (entity_key, cluster_id) in cluster_ids.iter() {
+        let cluster_id = format!("{}", cluster_id);
+
+        //create write entity message
+        let mut msg_builder = capnp::message::Builder::new_default();
+        {
+            let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+            let mut write_entity_msg = msg.get_msg_type().init_write_entity_msg();
+            write_entity_msg.set_entity_key(*entity_key);
+
+            let mut field = write_entity_msg.init_fields(1).get(0);
+            field.set_name(CLUSTER_ID_FIELD);
+            field.set_value(&cluster_id[..]);
+        }
+
+        //send write entity message
+        let socket_addr = rustdht::service::lookup(&lookup_table, *entity_key).unwrap();
+        result = write_stream_msg(&mut streams, socket_addr, &msg_builder, connection_pool);
+        if result.is_err() {
             break;
         }
+
+        //compute hash of field value
+        let mut hasher = SipHasher::new();
+        (&cluster_id[..]).hash(&mut hasher);
+        let field_hash = hasher.finish();
+
+        //create write field message
+        let mut msg_builder = capnp::message::Builder::new_default();
+        {
+            let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+            let mut write_field_msg = msg.get_msg_type().init_write_field_msg();
+            write_field_msg.set_entity_key(*entity_key);
+
+            let mut field = write_field_msg.init_field();
+            field.set_name(CLUSTER_ID_FIELD);
+            field.set_value(&cluster_id[..]);
+        }
+
+        //send write field message
+        let socket_addr = rustdht::service::lookup(&lookup_table, field_hash).unwrap();
+        result = write_stream_msg(&mut streams, socket_addr, &msg_builder, connection_pool);
+        if result.is_err() {
+            break;
+        }
+    }
+
+    //close all streams, including those opened before a node failed
+    close_write_streams(streams, connection_pool);
+    result
+}
+
+//replies to the hello message of a refused connection with the reason it was refused, the peer is given a
+//second to reply so that the listener is never held up for long
+fn refuse_connection(stream: TcpStream, transport: &Transport, error: &str) -> Result<(),String> {
+    let timeout = Some(Duration::from_secs(1));
+    if let Err(e) = stream.set_read_timeout(timeout).and_then(|_| stream.set_write_timeout(timeout)) {
+        return Err(format!("failed to set timeout: {}", e));
+    }
+
+    let mut stream = try!(transport.accept(stream));
+    if let Err(e) = capnp::serialize::read_message(&mut stream, ::capnp::message::ReaderOptions::new()) {
+        return Err(format!("failed to read hello message: {}", e));
+    }
+
+    capnp::serialize::write_message(&mut stream, &create_error_msg(error)).map_err(|e| format!("failed to send error message: {}", e))
+}
+
+//returns the protocol version of the peer and, when authentication is enabled, the name and role of its account
+//Reads the hello message of a connection and authenticates it. Without credentials connections are anonymous, apart from
+//nodes presenting the node token when one is set.
+fn accept_hello(stream: &mut Stream, transport: &Transport, authenticator: &Option<Authenticator>, node_token: &Option<String>) -> Result<(u32,Option<(String,Role)>),String> {
+    let msg_reader = match capnp::serialize::read_message(stream, ::capnp::message::ReaderOptions::new()) {
+        Ok(msg_reader) => msg_reader,
+        Err(e) => return Err(format!("failed to read hello message: {}", e)),
+    };
+    let msg = match msg_reader.get_root::<message_capnp::message::Reader>() {
+        Ok(msg) => msg,
+        Err(e) => return Err(format!("failed to read hello message: {}", e)),
+    };
+
+    let result = match msg.get_msg_type().which() {
+        Ok(HelloMsg(hello_msg)) => {
+            protocol::check_version(hello_msg.get_version()).and_then(|_| {
+                let credentials = protocol::get_credentials(hello_msg);
+                if credentials != Credentials::Anonymous && !transport.allows_credentials() {
+                    return Err("credentials are refused without tls".to_string());
+                }
+
+                match (authenticator, node_token) {
+                    (&Some(ref authenticator), _) => authenticator.authenticate(&credentials).map(|account| (hello_msg.get_version(), Some(account))),
+                    (&None, &Some(ref node_token)) if credentials.is_token(node_token) => Ok((hello_msg.get_version(), Some(("node".to_string(), Role::Node)))),
+                    (&None, _) => Ok((hello_msg.get_version(), None)),
+                }
+            })
+        },
+        _ => Err("expected a hello message".to_string()),
+    };
+
+    //reply with our own hello message or the reason the peer was rejected
+    let reply = match result {
+        Ok(_) => protocol::create_hello_msg(&Credentials::Anonymous),
+        Err(ref e) => create_error_msg(e),
+    };
+
+    if let Err(e) = capnp::serialize::write_message(stream, &reply) {
+        return Err(format!("failed to send hello message: {}", e));
     }
 
-    entity_keys
+    result
+}
+
+//returns the permission needed to send a request, none for messages that are never requests
+fn get_required_permission(msg: &message_capnp::message::Reader) -> Option<Permission> {
+    match msg.get_msg_type().which() {
+        Ok(QueryMsg(_)) | Ok(AggregateMsg(_)) | Ok(MatchMsg(_)) | Ok(QueryStatsMsg(_)) => Some(Permission::Read),
+        Ok(InsertEntitiesMsg(_)) => Some(Permission::Load),
+        Ok(DeduplicateMsg(_)) => Some(Permission::Admin),
+        Ok(OpenWriteStreamMsg(_)) | Ok(QueryEntitiesMsg(_)) | Ok(QueryEntityMsg(_)) | Ok(QueryFilterMsg(_)) | Ok(QueryNearestMsg(_))
+            | Ok(QueryGroupCountsMsg(_)) | Ok(DeduplicateNodeMsg(_)) | Ok(QueryNodeStatsMsg(_)) | Ok(QueryFieldValuesMsg(_)) | Ok(QueryBlockValuesMsg(_)) => Some(Permission::Node),
+        _ => None,
+    }
 }
 
-fn get_entities(entity_keyset: Vec<u64>, lookup_table: Arc<RwLock<BTreeMap<u64,SocketAddrV4>>>, entity_tx: Sender<HashMap<String,String>>) {
-    let entity_tx = Arc::new(Mutex::new(entity_tx));
-    for entity_key in entity_keyset {
-        let (lookup_table, entity_tx) = (lookup_table.clone(), entity_tx.clone());
+fn create_error_msg(error: &str) -> capnp::message::Builder<capnp::message::HeapAllocator> {
+    let mut msg_builder = capnp::message::Builder::new_default();
+    {
+        let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+        msg.get_msg_type().set_error_msg(error);
+    }
+
+    msg_builder
+}
+
+//checks every selected field is grouped by, and that each group by field is named once
+fn validate_group_by(field_names: &[String], group_by: &[String]) -> Result<(),String> {
+    if let Some(field_name) = field_names.iter().find(|x| !group_by.contains(x)) {
+        return Err(format!("field '{}' must appear in the GROUP BY clause", field_name));
+    }
+
+    for (i, field_name) in group_by.iter().enumerate() {
+        if field_name.is_empty() {
+            return Err("GROUP BY field names must not be empty".to_string());
+        }
+
+        if group_by[..i].contains(field_name) {
+            return Err(format!("field '{}' appears more than once in the GROUP BY clause", field_name));
+        }
+    }
+
+    Ok(())
+}
 
-        thread::spawn(move || {
-            let lookup_table = lookup_table.read().unwrap();
+fn get_group_counts(entity_keys: Vec<u64>, group_by: Vec<String>, lookup_table: &Arc<RwLock<BTreeMap<u64,SocketAddrV4>>>, connection_pool: &Arc<ConnectionPool>) -> Result<BTreeMap<Vec<String>,u64>,String> {
+    //group entity keys by the node they are stored on
+    let mut node_entity_keys = HashMap::new();
+    {
+        let lookup_table = lookup_table.read().unwrap();
+        for entity_key in entity_keys {
             let socket_addr = rustdht::service::lookup(&lookup_table, entity_key).unwrap();
-            
-            //create query entity message
+            node_entity_keys.entry(socket_addr).or_insert(vec!()).push(entity_key);
+        }
+    }
+
+    //send query group counts messages to each node
+    let (group_counts_tx, group_counts_rx) = channel::<Result<Vec<(Vec<String>,u64)>,String>>();
+    let node_count = node_entity_keys.len();
+    let request_pool = connection_pool.clone();
+    for (socket_addr, entity_keys) in node_entity_keys {
+        let (group_by, connection_pool, group_counts_tx) = (group_by.clone(), connection_pool.clone(), group_counts_tx.clone());
+
+        request_pool.execute(move || {
+            //create query group counts message
             let mut msg_builder = capnp::message::Builder::new_default();
             {
                 let msg = msg_builder.init_root::<message_capnp::message::Builder>();
-                msg.get_msg_type().set_query_entity_msg(entity_key);
-            }
-        
-            //send query entity message
-            let mut stream = TcpStream::connect(socket_addr).unwrap();
-            capnp::serialize::write_message(&mut stream, &msg_builder).unwrap();
-
-            //read entity message
-            let msg_reader = capnp::serialize::read_message(&mut stream, ::capnp::message::ReaderOptions::new()).unwrap();
-            let msg = msg_reader.get_root::<message_capnp::message::Reader>().unwrap();
-
-            //create map and add all fields to it
-            let mut entity = HashMap::new();
-
-            //parse out message
-            match msg.get_msg_type().which() {
-                Ok(EntityMsg(entity_msg)) => {
-                    let fields = entity_msg.unwrap();
-                    for field in fields.iter() {
-                        entity.insert(field.get_name().unwrap().to_string(), field.get_value().unwrap().to_string());
+                let mut query_group_counts_msg = msg.get_msg_type().init_query_group_counts_msg();
+                {
+                    let mut entity_keys_msg = query_group_counts_msg.borrow().init_entity_keys(entity_keys.len() as u32);
+                    for (i, entity_key) in entity_keys.iter().enumerate() {
+                        entity_keys_msg.set(i as u32, *entity_key);
                     }
-                },
-                Ok(_) => panic!("Unknown message type"),
-                Err(capnp::NotInSchema(e)) => panic!("Error capnp::NotInSchema: {}", e),
+                }
+
+                let mut group_by_msg = query_group_counts_msg.init_group_by(group_by.len() as u32);
+                for (i, field_name) in group_by.iter().enumerate() {
+                    group_by_msg.set(i as u32, &field_name[..]);
+                }
+            }
+
+            //send query group counts message and read group counts message
+            let result = connection_pool.request(socket_addr, &msg_builder).and_then(|msg_reader| {
+                let msg = try!(msg_reader.get_root::<message_capnp::message::Reader>().map_err(|e| format!("invalid reply from {}: {}", socket_addr, e)));
+
+                //parse out message
+                match msg.get_msg_type().which() {
+                    Ok(GroupCountsMsg(group_counts_msg)) => {
+                        let mut group_counts = vec!();
+                        for group_count in group_counts_msg.unwrap().iter() {
+                            let values_msg = group_count.get_values().unwrap();
+                            let mut values = Vec::new();
+                            for i in 0..values_msg.len() {
+                                values.push(values_msg.get(i).unwrap().to_string());
+                            }
+
+                            group_counts.push((values, group_count.get_count()));
+                        }
+
+                        Ok(group_counts)
+                    },
+                    Ok(ErrorMsg(error_msg)) => Err(format!("{}: {}", socket_addr, error_msg.unwrap())),
+                    Ok(_) => Err(format!("unexpected message type from {}", socket_addr)),
+                    Err(capnp::NotInSchema(e)) => Err(format!("unsupported message type {} from {}", e, socket_addr)),
+                }
+            });
+
+            //the aggregate may have already failed on another node
+            let _ = group_counts_tx.send(result);
+        });
+    }
+
+    //merge group counts from every node
+    drop(group_counts_tx);
+    let mut group_counts = BTreeMap::new();
+    for _ in 0..node_count {
+        for (values, count) in try!(recv_node_result(&group_counts_rx)) {
+            *group_counts.entry(values).or_insert(0) += count;
+        }
+    }
+
+    Ok(group_counts)
+}
+
+fn create_group_counts_msg(group_counts: &BTreeMap<Vec<String>,u64>) -> capnp::message::Builder<capnp::message::HeapAllocator> {
+    let mut msg_builder = capnp::message::Builder::new_default();
+    {
+        let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+        let mut group_counts_msg = msg.get_msg_type().init_group_counts_msg(group_counts.len() as u32);
+
+        for (i, (values, count)) in group_counts.iter().enumerate() {
+            let mut group_count = group_counts_msg.borrow().get(i as u32);
+            group_count.set_count(*count);
+
+            let mut values_msg = group_count.init_values(values.len() as u32);
+            for (j, value) in values.iter().enumerate() {
+                values_msg.set(j as u32, &value[..]);
+            }
+        }
+    }
+
+    msg_builder
+}
+
+fn get_node_stats(lookup_table: &Arc<RwLock<BTreeMap<u64,SocketAddrV4>>>, connection_pool: &Arc<ConnectionPool>) -> Result<Vec<(u64,u64,u64,u64,u64)>,String> {
+    let (stats_tx, stats_rx) = channel::<Result<Vec<(u64,u64,u64,u64,u64)>,String>>();
+
+    //send query node stats messages to all peers, the lookup table is not held while waiting on them
+    let peer_socket_addrs: Vec<SocketAddrV4> = lookup_table.read().unwrap().values().cloned().collect();
+    let request_pool = connection_pool.clone();
+    for peer_socket_addr in peer_socket_addrs.iter() {
+        let (peer_socket_addr, connection_pool, stats_tx) = (peer_socket_addr.clone(), connection_pool.clone(), stats_tx.clone());
+
+        request_pool.execute(move || {
+            //create query node stats message
+            let mut msg_builder = capnp::message::Builder::new_default();
+            {
+                let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+                msg.get_msg_type().set_query_node_stats_msg(());
             }
 
-            let entity_tx = entity_tx.lock().unwrap();
-            entity_tx.send(entity).unwrap();
+            //send query node stats message and read stats message
+            let result = connection_pool.request(peer_socket_addr, &msg_builder).and_then(|msg_reader| {
+                let msg = try!(msg_reader.get_root::<message_capnp::message::Reader>().map_err(|e| format!("invalid reply from {}: {}", peer_socket_addr, e)));
+
+                //parse out message
+                match msg.get_msg_type().which() {
+                    Ok(StatsMsg(stats_msg)) => {
+                        Ok(stats_msg.unwrap().iter().map(|stats| {
+                            (stats.get_token(), stats.get_cache_hits(), stats.get_cache_misses(), stats.get_cache_entries(), stats.get_cache_bytes())
+                        }).collect())
+                    },
+                    Ok(ErrorMsg(error_msg)) => Err(format!("{}: {}", peer_socket_addr, error_msg.unwrap())),
+                    Ok(_) => Err(format!("unexpected message type from {}", peer_socket_addr)),
+                    Err(capnp::NotInSchema(e)) => Err(format!("unsupported message type {} from {}", e, peer_socket_addr)),
+                }
+            });
+
+            //the stats request may have already failed on another node
+            let _ = stats_tx.send(result);
         });
     }
+
+    //compile stats ordered by token
+    drop(stats_tx);
+    let mut node_stats = vec!();
+    for _ in 0..peer_socket_addrs.len() {
+        node_stats.extend(try!(recv_node_result(&stats_rx)));
+    }
+
+    node_stats.sort();
+    Ok(node_stats)
 }
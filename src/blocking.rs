@@ -0,0 +1,252 @@
+use compare;
+use normalize::Normalizers;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead,BufReader};
+
+#[derive(Clone,Debug,PartialEq)]
+pub enum BlockingFunction {
+    Prefix(usize),
+    Soundex,
+    Suffix(usize),
+    Value,
+    Year,
+}
+
+impl BlockingFunction {
+    pub fn apply(&self, value: &str) -> Option<String> {
+        let key = match *self {
+            BlockingFunction::Prefix(length) => compare::graphemes(value).into_iter().take(length).collect(),
+            BlockingFunction::Soundex => compare::soundex_code(value),
+            BlockingFunction::Suffix(length) => {
+                let graphemes = compare::graphemes(value);
+                graphemes[graphemes.len().saturating_sub(length)..].concat()
+            },
+            BlockingFunction::Value => value.to_string(),
+            BlockingFunction::Year => {
+                //first run of exactly four digits
+                let runs: Vec<&str> = value.split(|c: char| !c.is_digit(10)).collect();
+                runs.into_iter().find(|x| x.len() == 4).unwrap_or("").to_string()
+            },
+        };
+
+        if key.is_empty() { None } else { Some(key) }
+    }
+}
+
+/// A synthetic field computed from the values of other fields, such as the
+/// first three letters of a last name followed by a birth year. Each part
+/// applies a function to the normalized value of one field.
+pub struct BlockingKey {
+    pub name: String,
+    pub parts: Vec<(BlockingFunction,String)>,
+}
+
+impl BlockingKey {
+    /// returns None when any field the key is computed from is missing or
+    /// produces an empty part
+    pub fn compute(&self, entity: &HashMap<String,String>, normalizers: &Normalizers) -> Option<String> {
+        let mut key = String::new();
+        for &(ref function, ref field_name) in self.parts.iter() {
+            let value = match entity.get(field_name) {
+                Some(value) => normalizers.normalize(field_name, value),
+                None => return None,
+            };
+
+            match function.apply(&value) {
+                Some(part) => key.push_str(&part),
+                None => return None,
+            }
+        }
+
+        Some(key)
+    }
+}
+
+/// The field DEDUPLICATE writes the cluster of each entity to.
+pub static CLUSTER_ID_FIELD: &'static str = "cluster_id";
+
+/// The blocking keys computed for every entity when it is inserted and
+/// indexed as fields of their own. Keys are read from files of the form
+/// below, where each part is one of 'value', 'prefix(n)', 'suffix(n)',
+/// 'soundex' or 'year' applied to a field.
+///
+/// ```text
+/// last_name_dob = prefix(last_name,3) + year(dob)
+/// last_name_soundex = soundex(last_name)
+/// ```
+pub struct BlockingKeys {
+    keys: Vec<BlockingKey>,
+}
+
+impl BlockingKeys {
+    pub fn new() -> BlockingKeys {
+        BlockingKeys {
+            keys: Vec::new(),
+        }
+    }
+
+    pub fn from_file(filename: &str) -> Result<BlockingKeys,String> {
+        let file = match File::open(filename) {
+            Ok(file) => file,
+            Err(e) => return Err(format!("failed to open blocking keys file '{}': {}", filename, e)),
+        };
+
+        BlockingKeys::parse(BufReader::new(file), filename)
+    }
+
+    //parses the lines of a blocking keys file, where the filename is only used in errors
+    fn parse<R: BufRead>(reader: R, filename: &str) -> Result<BlockingKeys,String> {
+        let mut blocking_keys = BlockingKeys::new();
+        for (i, line) in reader.lines().enumerate() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => return Err(format!("failed to read blocking keys file '{}': {}", filename, e)),
+            };
+
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("#") {
+                continue;
+            }
+
+            let mut split = line.splitn(2, '=');
+            let (name, definition) = match (split.next(), split.next()) {
+                (Some(name), Some(definition)) => (name.trim(), definition.trim()),
+                _ => return Err(format!("invalid blocking key definition on line {} of '{}'", i + 1, filename)),
+            };
+
+            let mut parts = vec!();
+            for part in definition.split('+').map(|x| x.trim()) {
+                parts.push(try!(parse_part(part, i, filename)));
+            }
+
+            blocking_keys.keys.push(BlockingKey {
+                name: name.to_string(),
+                parts: parts,
+            });
+        }
+
+        Ok(blocking_keys)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&BlockingKey> {
+        self.keys.iter().find(|x| x.name == name)
+    }
+
+    /// returns whether the node computes the values of a field itself, as
+    /// it does for blocking keys and cluster ids, rather than taking them
+    /// from inserted entities
+    pub fn is_synthetic_field(&self, field_name: &str) -> bool {
+        field_name == CLUSTER_ID_FIELD || self.get(field_name).is_some()
+    }
+
+    /// returns the name and value of every blocking key of the entity
+    pub fn compute(&self, entity: &HashMap<String,String>, normalizers: &Normalizers) -> Vec<(String,String)> {
+        self.keys.iter()
+            .filter_map(|blocking_key| blocking_key.compute(entity, normalizers).map(|value| (blocking_key.name.clone(), value)))
+            .collect()
+    }
+}
+
+fn parse_part(part: &str, line: usize, filename: &str) -> Result<(BlockingFunction,String),String> {
+    let (open, close) = match (part.find('('), part.rfind(')')) {
+        (Some(open), Some(close)) if open < close => (open, close),
+        _ => return Err(format!("invalid blocking key part '{}' on line {} of '{}'", part, line + 1, filename)),
+    };
+
+    let params: Vec<&str> = part[open+1..close].split(',').map(|x| x.trim()).collect();
+    let field_name = params[0].to_string();
+    if field_name.is_empty() {
+        return Err(format!("blocking key part '{}' has no field on line {} of '{}'", part, line + 1, filename));
+    }
+
+    let length = match params.get(1) {
+        Some(length) => match length.parse::<usize>() {
+            Ok(length) => Some(length),
+            Err(_) => return Err(format!("invalid length '{}' on line {} of '{}'", length, line + 1, filename)),
+        },
+        None => None,
+    };
+
+    let function = match (part[..open].trim(), length) {
+        ("prefix", Some(length)) => BlockingFunction::Prefix(length),
+        ("soundex", None) => BlockingFunction::Soundex,
+        ("suffix", Some(length)) => BlockingFunction::Suffix(length),
+        ("value", None) => BlockingFunction::Value,
+        ("year", None) => BlockingFunction::Year,
+        (name, _) => return Err(format!("invalid blocking function '{}' on line {} of '{}'", name, line + 1, filename)),
+    };
+
+    Ok((function, field_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use normalize::Normalizers;
+
+    use std::collections::HashMap;
+    use std::io::Cursor;
+
+    fn parse(definitions: &str) -> Result<BlockingKeys,String> {
+        BlockingKeys::parse(Cursor::new(definitions.as_bytes()), "test.keys")
+    }
+
+    fn create_entity(fields: &[(&str, &str)]) -> HashMap<String,String> {
+        fields.iter().map(|&(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn parse_parts() {
+        let blocking_keys = parse("# keys\n\nlast_name_dob = prefix(last_name,3) + year(dob)\nsoundex = soundex(last_name)\ntail = suffix(last_name, 2)\nfull = value(first_name) + value(last_name)\n").unwrap();
+        assert_eq!(blocking_keys.get("last_name_dob").unwrap().parts, vec!((BlockingFunction::Prefix(3), "last_name".to_string()), (BlockingFunction::Year, "dob".to_string())));
+        assert_eq!(blocking_keys.get("soundex").unwrap().parts, vec!((BlockingFunction::Soundex, "last_name".to_string())));
+        assert_eq!(blocking_keys.get("tail").unwrap().parts, vec!((BlockingFunction::Suffix(2), "last_name".to_string())));
+        assert_eq!(blocking_keys.get("full").unwrap().parts, vec!((BlockingFunction::Value, "first_name".to_string()), (BlockingFunction::Value, "last_name".to_string())));
+        assert!(blocking_keys.get("missing").is_none());
+    }
+
+    #[test]
+    fn parse_invalid_parts() {
+        assert!(parse("last_name prefix(last_name,3)\n").is_err());
+        assert!(parse("key = prefix(last_name)\n").is_err());
+        assert!(parse("key = prefix(last_name,three)\n").is_err());
+        assert!(parse("key = soundex(last_name,3)\n").is_err());
+        assert!(parse("key = initials(last_name)\n").is_err());
+        assert!(parse("key = value()\n").is_err());
+        assert!(parse("key = value(last_name) +\n").is_err());
+        assert!(parse("key = year dob\n").is_err());
+    }
+
+    #[test]
+    fn apply_functions() {
+        assert_eq!(BlockingFunction::Prefix(3).apply("rammer"), Some("ram".to_string()));
+        assert_eq!(BlockingFunction::Prefix(3).apply("m\u{fc}ller"), Some("m\u{fc}l".to_string()));
+        assert_eq!(BlockingFunction::Prefix(3).apply(""), None);
+        assert_eq!(BlockingFunction::Suffix(2).apply("rammer"), Some("er".to_string()));
+        assert_eq!(BlockingFunction::Suffix(10).apply("rammer"), Some("rammer".to_string()));
+        assert_eq!(BlockingFunction::Soundex.apply("robert"), Some("R163".to_string()));
+        assert_eq!(BlockingFunction::Soundex.apply("\u{65e5}\u{672c}"), None);
+        assert_eq!(BlockingFunction::Value.apply("rammer"), Some("rammer".to_string()));
+        assert_eq!(BlockingFunction::Year.apply("03/14/1987"), Some("1987".to_string()));
+        assert_eq!(BlockingFunction::Year.apply("1987-03-14"), Some("1987".to_string()));
+        assert_eq!(BlockingFunction::Year.apply("03/14/87"), None);
+    }
+
+    #[test]
+    fn compute_keys() {
+        let blocking_keys = parse("last_name_dob = prefix(last_name,3) + year(dob)\nsoundex = soundex(last_name)\n").unwrap();
+        let normalizers = Normalizers::new();
+
+        let entity = create_entity(&[("last_name", "Rammer"), ("dob", "03/14/1987")]);
+        assert_eq!(blocking_keys.compute(&entity, &normalizers), vec!(("last_name_dob".to_string(), "ram1987".to_string()), ("soundex".to_string(), "R560".to_string())));
+
+        //keys missing a field or producing an empty part are left out
+        let entity = create_entity(&[("last_name", "Rammer")]);
+        assert_eq!(blocking_keys.compute(&entity, &normalizers), vec!(("soundex".to_string(), "R560".to_string())));
+
+        let entity = create_entity(&[("last_name", "Rammer"), ("dob", "unknown")]);
+        assert!(blocking_keys.get("last_name_dob").unwrap().compute(&entity, &normalizers).is_none());
+    }
+}
@@ -0,0 +1,302 @@
+use std::collections::{BTreeMap,HashMap,HashSet};
+use std::mem;
+
+#[derive(Clone,Eq,Hash,PartialEq)]
+struct FilterKey {
+    field_names: Vec<String>,
+    filter_type: String,
+    params: Vec<String>,
+    value: String,
+    match_tokens: bool,
+    ignore_accents: bool,
+}
+
+struct CacheEntry {
+    entity_keys: Vec<u64>,
+    size: usize,
+    tick: u64,
+}
+
+/// A least recently used cache of filter results, keyed on the field
+/// names, filter type, parameters, value and whether tokens were matched
+/// or accents ignored.
+/// Entries are indexed by each field they were computed over so that they
+/// may be dropped when that field changes. Entries computed over every
+/// field are dropped when any field changes.
+pub struct FilterCache {
+    capacity: usize,
+    size: usize,
+    tick: u64,
+    entries: HashMap<FilterKey,CacheEntry>,
+    fields: HashMap<String,HashSet<FilterKey>>,
+    all_fields: HashSet<FilterKey>,
+    lru: BTreeMap<u64,FilterKey>,
+    hits: u64,
+    misses: u64,
+}
+
+impl FilterCache {
+    /// capacity is the approximate memory budget in bytes, where a
+    /// capacity of 0 disables caching
+    pub fn new(capacity: usize) -> FilterCache {
+        FilterCache {
+            capacity: capacity,
+            size: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            fields: HashMap::new(),
+            all_fields: HashSet::new(),
+            lru: BTreeMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn get(&mut self, field_names: &[&str], filter_type: &str, params: &[&str], value: &str, match_tokens: bool, ignore_accents: bool) -> Option<HashSet<u64>> {
+        let filter_key = create_filter_key(field_names, filter_type, params, value, match_tokens, ignore_accents);
+        self.tick += 1;
+
+        match self.entries.get_mut(&filter_key) {
+            Some(entry) => {
+                //move entry to the back of the lru order
+                self.lru.remove(&entry.tick);
+                entry.tick = self.tick;
+                self.lru.insert(self.tick, filter_key);
+
+                self.hits += 1;
+                Some(entry.entity_keys.iter().cloned().collect())
+            },
+            None => {
+                self.misses += 1;
+                None
+            },
+        }
+    }
+
+    pub fn insert(&mut self, field_names: &[&str], filter_type: &str, params: &[&str], value: &str, match_tokens: bool, ignore_accents: bool, entity_keys: &HashSet<u64>) {
+        let filter_key = create_filter_key(field_names, filter_type, params, value, match_tokens, ignore_accents);
+        let size = mem::size_of::<CacheEntry>() + mem::size_of::<FilterKey>()
+            + field_names.iter().fold(0, |sum, field_name| sum + field_name.len()) * 2
+            + filter_type.len() + value.len()
+            + params.iter().fold(0, |sum, param| sum + param.len())
+            + entity_keys.len() * mem::size_of::<u64>();
+
+        if size > self.capacity {
+            return;
+        }
+
+        //remove an existing entry and evict until the new entry fits
+        self.remove(&filter_key);
+        while self.size + size > self.capacity {
+            let filter_key = self.lru.values().next().unwrap().clone();
+            self.remove(&filter_key);
+        }
+
+        //index entry by the fields it was computed over
+        if field_names.is_empty() {
+            self.all_fields.insert(filter_key.clone());
+        }
+
+        for field_name in field_names {
+            let filter_keys = self.fields.entry(field_name.to_string()).or_insert(HashSet::new());
+            filter_keys.insert(filter_key.clone());
+        }
+
+        self.tick += 1;
+        self.lru.insert(self.tick, filter_key.clone());
+        self.entries.insert(filter_key, CacheEntry {
+            entity_keys: entity_keys.iter().cloned().collect(),
+            size: size,
+            tick: self.tick,
+        });
+
+        self.size += size;
+    }
+
+    /// drop all cached results computed over the given field
+    pub fn invalidate_field(&mut self, field_name: &str) {
+        let mut filter_keys: Vec<FilterKey> = self.all_fields.iter().cloned().collect();
+        if let Some(field_filter_keys) = self.fields.get(field_name) {
+            filter_keys.extend(field_filter_keys.iter().cloned());
+        }
+
+        for filter_key in filter_keys {
+            self.remove(&filter_key);
+        }
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn remove(&mut self, filter_key: &FilterKey) {
+        let entry = match self.entries.remove(filter_key) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        self.lru.remove(&entry.tick);
+        self.size -= entry.size;
+
+        //remove entry from the field indices
+        if filter_key.field_names.is_empty() {
+            self.all_fields.remove(filter_key);
+        }
+
+        for field_name in filter_key.field_names.iter() {
+            let mut is_empty = false;
+            if let Some(filter_keys) = self.fields.get_mut(field_name) {
+                filter_keys.remove(filter_key);
+                is_empty = filter_keys.is_empty();
+            }
+
+            if is_empty {
+                self.fields.remove(field_name);
+            }
+        }
+    }
+}
+
+fn create_filter_key(field_names: &[&str], filter_type: &str, params: &[&str], value: &str, match_tokens: bool, ignore_accents: bool) -> FilterKey {
+    FilterKey {
+        field_names: field_names.iter().map(|field_name| field_name.to_string()).collect(),
+        filter_type: filter_type.to_string(),
+        params: params.iter().map(|param| param.to_string()).collect(),
+        value: value.to_string(),
+        match_tokens: match_tokens,
+        ignore_accents: ignore_accents,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashSet;
+    use std::mem;
+
+    fn keys(entity_keys: &[u64]) -> HashSet<u64> {
+        entity_keys.iter().cloned().collect()
+    }
+
+    //the size accounted for a levenshtein filter with a single parameter
+    fn entry_size(field_names: &[&str], value: &str, entity_key_count: usize) -> usize {
+        mem::size_of::<CacheEntry>() + mem::size_of::<FilterKey>()
+            + field_names.iter().fold(0, |sum, field_name| sum + field_name.len()) * 2
+            + "levenshtein".len() + value.len() + 1
+            + entity_key_count * mem::size_of::<u64>()
+    }
+
+    fn insert(cache: &mut FilterCache, field_names: &[&str], value: &str, entity_keys: &[u64]) {
+        cache.insert(field_names, "levenshtein", &["1"], value, false, false, &keys(entity_keys));
+    }
+
+    fn get(cache: &mut FilterCache, field_names: &[&str], value: &str) -> Option<HashSet<u64>> {
+        cache.get(field_names, "levenshtein", &["1"], value, false, false)
+    }
+
+    #[test]
+    fn hits_and_misses() {
+        let mut cache = FilterCache::new(1024 * 1024);
+        assert_eq!(get(&mut cache, &["last_name"], "rammer"), None);
+        insert(&mut cache, &["last_name"], "rammer", &[1, 2]);
+        assert_eq!(get(&mut cache, &["last_name"], "rammer"), Some(keys(&[1, 2])));
+
+        //every part of the filter is part of the key
+        assert_eq!(get(&mut cache, &["first_name"], "rammer"), None);
+        assert_eq!(get(&mut cache, &["last_name"], "rammor"), None);
+        assert_eq!(cache.get(&["last_name"], "levenshtein", &["2"], "rammer", false, false), None);
+        assert_eq!(cache.get(&["last_name"], "damerau_levenshtein", &["1"], "rammer", false, false), None);
+        assert_eq!(cache.get(&["last_name"], "levenshtein", &["1"], "rammer", true, false), None);
+        assert_eq!(cache.get(&["last_name"], "levenshtein", &["1"], "rammer", false, true), None);
+
+        assert_eq!(cache.hits(), 1);
+        assert_eq!(cache.misses(), 7);
+    }
+
+    #[test]
+    fn byte_accounting() {
+        let mut cache = FilterCache::new(1024 * 1024);
+        insert(&mut cache, &["last_name"], "rammer", &[1, 2, 3]);
+        assert_eq!(cache.size(), entry_size(&["last_name"], "rammer", 3));
+
+        insert(&mut cache, &["first_name", "last_name"], "dan", &[]);
+        assert_eq!(cache.size(), entry_size(&["last_name"], "rammer", 3) + entry_size(&["first_name", "last_name"], "dan", 0));
+
+        //replacing an entry accounts for the new entry alone
+        insert(&mut cache, &["last_name"], "rammer", &[1]);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.size(), entry_size(&["last_name"], "rammer", 1) + entry_size(&["first_name", "last_name"], "dan", 0));
+
+        cache.invalidate_field("last_name");
+        assert_eq!(cache.len(), 0);
+        assert_eq!(cache.size(), 0);
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let size = entry_size(&["last_name"], "a", 1);
+        let mut cache = FilterCache::new(size * 2);
+        insert(&mut cache, &["last_name"], "a", &[1]);
+        insert(&mut cache, &["last_name"], "b", &[2]);
+
+        //reading a moves it to the back, so b is evicted for c
+        assert!(get(&mut cache, &["last_name"], "a").is_some());
+        insert(&mut cache, &["last_name"], "c", &[3]);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.size(), size * 2);
+        assert!(get(&mut cache, &["last_name"], "b").is_none());
+        assert!(get(&mut cache, &["last_name"], "a").is_some());
+        assert!(get(&mut cache, &["last_name"], "c").is_some());
+
+        //an entry larger than the capacity is never cached and evicts nothing
+        let entity_keys: Vec<u64> = (0..size as u64).collect();
+        insert(&mut cache, &["last_name"], "d", &entity_keys);
+        assert_eq!(cache.len(), 2);
+        assert!(get(&mut cache, &["last_name"], "d").is_none());
+    }
+
+    #[test]
+    fn disabled_without_capacity() {
+        let mut cache = FilterCache::new(0);
+        insert(&mut cache, &["last_name"], "rammer", &[1]);
+        assert_eq!(cache.len(), 0);
+        assert_eq!(cache.size(), 0);
+        assert!(get(&mut cache, &["last_name"], "rammer").is_none());
+    }
+
+    #[test]
+    fn invalidate_on_write() {
+        let mut cache = FilterCache::new(1024 * 1024);
+        insert(&mut cache, &["first_name"], "dan", &[1]);
+        insert(&mut cache, &["last_name"], "rammer", &[1]);
+        insert(&mut cache, &["first_name", "last_name"], "rammer", &[1]);
+        insert(&mut cache, &[], "rammer", &[1]);
+
+        //a write to a field drops the entries computed over it and over every field
+        cache.invalidate_field("first_name");
+        assert!(get(&mut cache, &["first_name"], "dan").is_none());
+        assert!(get(&mut cache, &["first_name", "last_name"], "rammer").is_none());
+        assert!(get(&mut cache, &[], "rammer").is_none());
+        assert!(get(&mut cache, &["last_name"], "rammer").is_some());
+        assert_eq!(cache.size(), entry_size(&["last_name"], "rammer", 1));
+
+        //fields nothing was computed over drop only the entries over every field
+        insert(&mut cache, &[], "rammer", &[1]);
+        cache.invalidate_field("dob");
+        assert!(get(&mut cache, &[], "rammer").is_none());
+        assert!(get(&mut cache, &["last_name"], "rammer").is_some());
+    }
+}
@@ -0,0 +1,585 @@
+use capnp;
+use capnp::message::{Builder,HeapAllocator,Reader,ReaderOptions};
+use capnp::serialize::OwnedSegments;
+
+use auth::Credentials;
+use message_capnp;
+use message_capnp::message::msg_type::{CandidatesMsg,EntitiesMsg,ErrorMsg,GroupCountsMsg,QueryResultsHeaderMsg,QueryResultsTrailerMsg,ResultMsg,StatsMsg};
+use model::Classification;
+use parser;
+use parser::Command::{Aggregate,Query};
+use parser::Filter;
+use protocol;
+use protocol::HandshakeError;
+use transport::{Stream,Transport};
+
+use nom;
+
+use std::error;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc,Mutex};
+
+/// The reasons a request to the cluster may fail.
+#[derive(Debug)]
+pub enum Error {
+    /// connecting to the node, or sending or reading a message, failed
+    Io(String),
+    /// the node replied with an unexpected message or speaks an incompatible protocol version
+    Protocol(String),
+    /// the node replied with an error message
+    Server(String),
+    /// the node rejected the connection, usually for the credentials presented
+    Auth(String),
+    /// the query is invalid and was never sent
+    Query(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "io error: {}", e),
+            Error::Protocol(ref e) => write!(f, "protocol error: {}", e),
+            Error::Server(ref e) => write!(f, "{}", e),
+            Error::Auth(ref e) => write!(f, "authentication failed: {}", e),
+            Error::Query(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Io(ref e) | Error::Protocol(ref e) | Error::Server(ref e) | Error::Auth(ref e) | Error::Query(ref e) => e,
+        }
+    }
+}
+
+/// The fields of an entity in the order they were returned.
+pub type Entity = Vec<(String,String)>;
+
+/// An entity scored against a record by a model, along with the weight each
+/// compared field added to the total.
+pub struct Candidate {
+    pub weight: f64,
+    pub classification: Classification,
+    pub entity: Entity,
+    pub scores: Vec<(String,f64)>,
+}
+
+/// Filter cache statistics of a single node.
+pub struct NodeStats {
+    pub token: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub cache_entries: u64,
+    pub cache_bytes: u64,
+}
+
+/// A select query built from typed filters rather than query text, where no
+/// selected fields returns every field.
+///
+/// ```ignore
+/// let query = QueryBuilder::new()
+///     .select("first_name")
+///     .filter(Filter::new("levenshtein", "rammer").field("last_name").param("1"));
+/// ```
+pub struct QueryBuilder {
+    pub field_names: Vec<String>,
+    pub filters: Vec<Filter>,
+}
+
+impl QueryBuilder {
+    pub fn new() -> QueryBuilder {
+        QueryBuilder {
+            field_names: Vec::new(),
+            filters: Vec::new(),
+        }
+    }
+
+    pub fn select(mut self, field_name: &str) -> QueryBuilder {
+        self.field_names.push(field_name.to_string());
+        self
+    }
+
+    pub fn filter(mut self, filter: Filter) -> QueryBuilder {
+        self.filters.push(filter);
+        self
+    }
+}
+
+/// The entities of a select query, read in chunks as the nodes storing them
+/// reply. The totals of the query are available once every chunk is read.
+pub struct QueryResults {
+    stream: Stream,
+    matched: u64,
+    totals: Option<(u64,u64)>,
+    done: bool,
+}
+
+impl QueryResults {
+    /// returns the number of entity keys matching the filters
+    pub fn matched(&self) -> u64 {
+        self.matched
+    }
+
+    /// returns the number of entities returned and the duration of the query
+    /// in milliseconds on the server, once every chunk has been read
+    pub fn totals(&self) -> Option<(u64,u64)> {
+        self.totals
+    }
+}
+
+impl Iterator for QueryResults {
+    type Item = Result<Vec<Entity>,Error>;
+
+    fn next(&mut self) -> Option<Result<Vec<Entity>,Error>> {
+        if self.done {
+            return None;
+        }
+
+        let msg_reader = match read_message(&mut self.stream) {
+            Ok(msg_reader) => msg_reader,
+            Err(e) => {
+                self.done = true;
+                return Some(Err(e));
+            },
+        };
+        let msg = match msg_reader.get_root::<message_capnp::message::Reader>() {
+            Ok(msg) => msg,
+            Err(e) => {
+                self.done = true;
+                return Some(Err(invalid_reply(e)));
+            },
+        };
+
+        //every message other than a valid chunk of entities ends the results
+        self.done = true;
+        match msg.get_msg_type().which() {
+            Ok(EntitiesMsg(entities_msg)) => {
+                let entities = entities_msg.map_err(invalid_reply).and_then(|entities_msg| {
+                    entities_msg.iter().map(|entity| entity.get_fields().map_err(invalid_reply).and_then(get_fields)).collect()
+                });
+
+                self.done = entities.is_err();
+                Some(entities)
+            },
+            Ok(QueryResultsTrailerMsg(trailer_msg)) => {
+                self.totals = Some((trailer_msg.get_entity_count(), trailer_msg.get_duration()));
+                None
+            },
+            Ok(ErrorMsg(error_msg)) => Some(Err(server_error(error_msg))),
+            _ => Some(Err(Error::Protocol("unexpected message type returned".to_string()))),
+        }
+    }
+}
+
+/// A connection to a fuzzydb cluster through one of its nodes. Requests
+/// reuse the connection left idle by the last completed request, starting
+/// with the one opened by connect, and otherwise open their own connection
+/// trying each address the node resolved to in turn, so a client may be
+/// cloned and used from several threads at once.
+#[derive(Clone)]
+pub struct Client {
+    host_addrs: Vec<SocketAddr>,
+    transport: Arc<Transport>,
+    credentials: Credentials,
+    batch_size: usize,
+    idle_stream: Arc<Mutex<Option<Stream>>>,
+}
+
+impl Client {
+    /// Checks that the node speaks a compatible protocol and accepts the
+    /// credentials before returning a client for it, where the node may be
+    /// reached at any of the addresses given.
+    pub fn connect(host_addrs: &[SocketAddr], transport: Arc<Transport>, credentials: Credentials) -> Result<Client,Error> {
+        let client = Client {
+            host_addrs: host_addrs.to_vec(),
+            transport: transport,
+            credentials: credentials,
+            batch_size: 250,
+            idle_stream: Arc::new(Mutex::new(None)),
+        };
+
+        //keep the handshake connection for the first request
+        let stream = try!(client.open());
+        client.release(stream);
+        Ok(client)
+    }
+
+    /// sets the number of entities sent in each insert message
+    pub fn set_batch_size(&mut self, batch_size: usize) {
+        self.batch_size = if batch_size == 0 { 1 } else { batch_size };
+    }
+
+    /// inserts every entity in batches, returning the number inserted
+    pub fn insert_entities<I: IntoIterator<Item=Entity>>(&self, entities: I) -> Result<usize,Error> {
+        let mut entity_count = 0;
+        let mut batch = Vec::new();
+        for entity in entities {
+            batch.push(entity);
+            if batch.len() == self.batch_size {
+                try!(self.insert_batch(&batch));
+                entity_count += batch.len();
+                batch.clear();
+            }
+        }
+
+        if batch.len() != 0 {
+            try!(self.insert_batch(&batch));
+            entity_count += batch.len();
+        }
+
+        Ok(entity_count)
+    }
+
+    /// runs the text of a select query and returns every entity it matches
+    pub fn query(&self, query: &str) -> Result<Vec<Entity>,Error> {
+        match try!(parse(query)) {
+            Query(field_names, filters) => self.collect(try!(self.select(&field_names, &filters))),
+            _ => Err(Error::Query("only select queries are supported".to_string())),
+        }
+    }
+
+    /// runs a typed select query and returns every entity it matches
+    pub fn run(&self, query: &QueryBuilder) -> Result<Vec<Entity>,Error> {
+        self.collect(try!(self.select(&query.field_names, &query.filters)))
+    }
+
+    /// starts a select query, returning the entities as they are streamed
+    pub fn select(&self, field_names: &[String], filters: &[Filter]) -> Result<QueryResults,Error> {
+        let mut msg_builder = capnp::message::Builder::new_default();
+        {
+            let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+            let mut query_msg = msg.get_msg_type().init_query_msg();
+            set_filters(query_msg.borrow().init_filters(filters.len() as u32), filters);
+
+            let mut field_names_msg = query_msg.init_field_names(field_names.len() as u32);
+            for (i, field_name) in field_names.iter().enumerate() {
+                field_names_msg.set(i as u32, &field_name[..]);
+            }
+        }
+
+        //send query message and read the results header, the stream is then owned by the results
+        let (stream, msg_reader) = try!(self.exchange(&msg_builder));
+        let matched = {
+            let msg = try!(msg_reader.get_root::<message_capnp::message::Reader>().map_err(invalid_reply));
+            match msg.get_msg_type().which() {
+                Ok(QueryResultsHeaderMsg(entity_key_count)) => entity_key_count,
+                Ok(ErrorMsg(error_msg)) => return Err(server_error(error_msg)),
+                _ => return Err(Error::Protocol("unexpected message type returned".to_string())),
+            }
+        };
+
+        Ok(QueryResults {
+            stream: stream,
+            matched: matched,
+            totals: None,
+            done: false,
+        })
+    }
+
+    /// Counts the entities matching the filters grouped by the values of the
+    /// group by fields, where no fields counts every entity as one group.
+    /// Every selected field must be one of the group by fields.
+    pub fn aggregate(&self, field_names: &[String], filters: &[Filter], group_by: &[String]) -> Result<Vec<(Vec<String>,u64)>,Error> {
+        if let Some(field_name) = field_names.iter().find(|x| !group_by.contains(x)) {
+            return Err(Error::Query(format!("field '{}' must appear in the GROUP BY clause", field_name)));
+        }
+
+        let mut msg_builder = capnp::message::Builder::new_default();
+        {
+            let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+            let mut aggregate_msg = msg.get_msg_type().init_aggregate_msg();
+            set_filters(aggregate_msg.borrow().init_filters(filters.len() as u32), filters);
+
+            {
+                let mut group_by_msg = aggregate_msg.borrow().init_group_by(group_by.len() as u32);
+                for (i, field_name) in group_by.iter().enumerate() {
+                    group_by_msg.set(i as u32, &field_name[..]);
+                }
+            }
+
+            let mut field_names_msg = aggregate_msg.init_field_names(field_names.len() as u32);
+            for (i, field_name) in field_names.iter().enumerate() {
+                field_names_msg.set(i as u32, &field_name[..]);
+            }
+        }
+
+        let msg_reader = try!(self.request(&msg_builder));
+        let msg = try!(msg_reader.get_root::<message_capnp::message::Reader>().map_err(invalid_reply));
+        match msg.get_msg_type().which() {
+            Ok(GroupCountsMsg(group_counts_msg)) => get_group_counts(try!(group_counts_msg.map_err(invalid_reply))),
+            Ok(ErrorMsg(error_msg)) => Err(server_error(error_msg)),
+            _ => Err(Error::Protocol("unexpected message type returned".to_string())),
+        }
+    }
+
+    /// runs the text of a select count query, returning its group counts
+    pub fn query_counts(&self, query: &str) -> Result<Vec<(Vec<String>,u64)>,Error> {
+        match try!(parse(query)) {
+            Aggregate(field_names, filters, group_by) => self.aggregate(&field_names, &filters, &group_by),
+            _ => Err(Error::Query("only select count queries are supported".to_string())),
+        }
+    }
+
+    /// scores entities against the record using a model, returning up to
+    /// limit candidates where a limit of zero uses the server default
+    pub fn match_record(&self, fields: &[(String,String)], model: &str, limit: u32) -> Result<Vec<Candidate>,Error> {
+        let mut msg_builder = capnp::message::Builder::new_default();
+        {
+            let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+            let mut match_msg = msg.get_msg_type().init_match_msg();
+            match_msg.set_model(model);
+            match_msg.set_limit(limit);
+
+            let mut fields_msg = match_msg.init_fields(fields.len() as u32);
+            for (i, &(ref name, ref value)) in fields.iter().enumerate() {
+                let mut field = fields_msg.borrow().get(i as u32);
+                field.set_name(&name[..]);
+                field.set_value(&value[..]);
+            }
+        }
+
+        let msg_reader = try!(self.request(&msg_builder));
+        let msg = try!(msg_reader.get_root::<message_capnp::message::Reader>().map_err(invalid_reply));
+        match msg.get_msg_type().which() {
+            Ok(CandidatesMsg(candidates_msg)) => {
+                let mut candidates = vec!();
+                for candidate in try!(candidates_msg.map_err(invalid_reply)).iter() {
+                    let classification = match candidate.get_classification() {
+                        Ok(message_capnp::Classification::Match) => Classification::Match,
+                        Ok(message_capnp::Classification::Possible) => Classification::Possible,
+                        Ok(message_capnp::Classification::NonMatch) => Classification::NonMatch,
+                        Err(capnp::NotInSchema(e)) => return Err(Error::Protocol(format!("unknown classification {}", e))),
+                    };
+
+                    //nodes without the field_scores capability return no scores
+                    let scores = match candidate.get_scores() {
+                        Ok(scores) => try!(scores.iter().map(|score| score.get_name().map(|name| (name.to_string(), score.get_score())).map_err(invalid_reply)).collect::<Result<Vec<_>,Error>>()),
+                        Err(_) => vec!(),
+                    };
+
+                    candidates.push(Candidate {
+                        weight: candidate.get_weight(),
+                        classification: classification,
+                        entity: try!(candidate.get_fields().map_err(invalid_reply).and_then(get_fields)),
+                        scores: scores,
+                    });
+                }
+
+                Ok(candidates)
+            },
+            Ok(ErrorMsg(error_msg)) => Err(server_error(error_msg)),
+            _ => Err(Error::Protocol("unexpected message type returned".to_string())),
+        }
+    }
+
+    /// groups every stored entity with the entities it matches using a model,
+    /// returning the cluster id and size of each group of duplicates
+    pub fn deduplicate(&self, model: &str) -> Result<Vec<(String,u64)>,Error> {
+        let mut msg_builder = capnp::message::Builder::new_default();
+        {
+            let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+            msg.get_msg_type().set_deduplicate_msg(model);
+        }
+
+        let msg_reader = try!(self.request(&msg_builder));
+        let msg = try!(msg_reader.get_root::<message_capnp::message::Reader>().map_err(invalid_reply));
+        match msg.get_msg_type().which() {
+            Ok(GroupCountsMsg(group_counts_msg)) => {
+                Ok(try!(get_group_counts(try!(group_counts_msg.map_err(invalid_reply)))).into_iter().map(|(values, count)| (values.into_iter().next().unwrap_or(String::new()), count)).collect())
+            },
+            Ok(ErrorMsg(error_msg)) => Err(server_error(error_msg)),
+            _ => Err(Error::Protocol("unexpected message type returned".to_string())),
+        }
+    }
+
+    /// returns the filter cache statistics of every node
+    pub fn stats(&self) -> Result<Vec<NodeStats>,Error> {
+        let mut msg_builder = capnp::message::Builder::new_default();
+        {
+            let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+            msg.get_msg_type().set_query_stats_msg(());
+        }
+
+        let msg_reader = try!(self.request(&msg_builder));
+        let msg = try!(msg_reader.get_root::<message_capnp::message::Reader>().map_err(invalid_reply));
+        match msg.get_msg_type().which() {
+            Ok(StatsMsg(stats_msg)) => {
+                Ok(try!(stats_msg.map_err(invalid_reply)).iter().map(|stats| NodeStats {
+                    token: stats.get_token(),
+                    cache_hits: stats.get_cache_hits(),
+                    cache_misses: stats.get_cache_misses(),
+                    cache_entries: stats.get_cache_entries(),
+                    cache_bytes: stats.get_cache_bytes(),
+                }).collect())
+            },
+            Ok(ErrorMsg(error_msg)) => Err(server_error(error_msg)),
+            _ => Err(Error::Protocol("unexpected message type returned".to_string())),
+        }
+    }
+
+    fn insert_batch(&self, entities: &[Entity]) -> Result<(),Error> {
+        let mut msg_builder = capnp::message::Builder::new_default();
+        {
+            let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+            let mut insert_entities_msg = msg.get_msg_type().init_insert_entities_msg(entities.len() as u32);
+
+            for (i, entity) in entities.iter().enumerate() {
+                let entity_msg = insert_entities_msg.borrow().get(i as u32);
+                let mut fields = entity_msg.init_fields(entity.len() as u32);
+
+                for (j, &(ref name, ref value)) in entity.iter().enumerate() {
+                    let mut field = fields.borrow().get(j as u32);
+                    field.set_name(&name[..]);
+                    field.set_value(&value[..]);
+                }
+            }
+        }
+
+        let msg_reader = try!(self.request(&msg_builder));
+        let msg = try!(msg_reader.get_root::<message_capnp::message::Reader>().map_err(invalid_reply));
+        match msg.get_msg_type().which() {
+            Ok(ResultMsg(true)) => Ok(()),
+            Ok(ResultMsg(false)) => Err(Error::Server("failed to insert entities".to_string())),
+            Ok(ErrorMsg(error_msg)) => Err(server_error(error_msg)),
+            _ => Err(Error::Protocol("unexpected message type returned".to_string())),
+        }
+    }
+
+    fn collect(&self, results: QueryResults) -> Result<Vec<Entity>,Error> {
+        let mut entities = vec!();
+        for chunk in results {
+            entities.extend(try!(chunk));
+        }
+
+        Ok(entities)
+    }
+
+    //opens a connection to the host, every connection starts with a protocol handshake presenting our credentials.
+    //addresses are tried in turn until one connects, while a handshake rejected by the node fails immediately
+    fn open(&self) -> Result<Stream,Error> {
+        if self.credentials != Credentials::Anonymous && !self.transport.allows_credentials() {
+            return Err(Error::Auth("refusing to send credentials without tls".to_string()));
+        }
+
+        let mut error = Error::Io("the host has no addresses".to_string());
+        for host_addr in self.host_addrs.iter() {
+            let mut stream = match self.transport.connect(*host_addr) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error = Error::Io(e);
+                    continue;
+                },
+            };
+
+            match protocol::handshake(&mut stream, &self.credentials) {
+                Ok(_) => return Ok(stream),
+                Err(HandshakeError::Io(e)) => error = Error::Io(e),
+                Err(HandshakeError::Incompatible(e)) => return Err(Error::Protocol(e)),
+                Err(HandshakeError::Rejected(e)) => return Err(Error::Auth(e)),
+            }
+        }
+
+        Err(error)
+    }
+
+    //sends the message and reads the reply on the idle connection when there is one. the node may have closed an idle
+    //connection, so a failure on one is retried once on a new connection
+    fn exchange(&self, msg_builder: &Builder<HeapAllocator>) -> Result<(Stream,Reader<OwnedSegments>),Error> {
+        let idle_stream = self.idle_stream.lock().ok().and_then(|mut idle_stream| idle_stream.take());
+        if let Some(mut stream) = idle_stream {
+            match write_message(&mut stream, msg_builder).and_then(|_| read_message(&mut stream)) {
+                Ok(msg_reader) => return Ok((stream, msg_reader)),
+                Err(Error::Io(_)) => {},
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut stream = try!(self.open());
+        try!(write_message(&mut stream, msg_builder));
+        let msg_reader = try!(read_message(&mut stream));
+        Ok((stream, msg_reader))
+    }
+
+    //keeps a connection with no reply left to read for the next request, unless another connection is already idle
+    fn release(&self, stream: Stream) {
+        if let Ok(mut idle_stream) = self.idle_stream.lock() {
+            if idle_stream.is_none() {
+                *idle_stream = Some(stream);
+            }
+        }
+    }
+
+    fn request(&self, msg_builder: &Builder<HeapAllocator>) -> Result<Reader<OwnedSegments>,Error> {
+        let (stream, msg_reader) = try!(self.exchange(msg_builder));
+        self.release(stream);
+        Ok(msg_reader)
+    }
+}
+
+fn parse(query: &str) -> Result<parser::Command,Error> {
+    match parser::cmd(query.trim().as_bytes()) {
+        nom::IResult::Done(bytes, cmd) => if bytes.len() == 0 { Ok(cmd) } else { Err(Error::Query(format!("invalid query '{}'", query))) },
+        _ => Err(Error::Query(format!("invalid query '{}'", query))),
+    }
+}
+
+fn set_filters(mut filters_msg: capnp::struct_list::Builder<message_capnp::filter::Owned>, filters: &[Filter]) {
+    for (i, filter) in filters.iter().enumerate() {
+        let mut query_filter = filters_msg.borrow().get(i as u32);
+        query_filter.set_filter_type(&filter.filter_type[..]);
+        query_filter.set_value(&filter.value[..]);
+        query_filter.set_match_tokens(filter.match_tokens);
+        query_filter.set_ignore_accents(filter.ignore_accents);
+
+        {
+            let mut filter_field_names = query_filter.borrow().init_field_names(filter.field_names.len() as u32);
+            for (j, field_name) in filter.field_names.iter().enumerate() {
+                filter_field_names.set(j as u32, &field_name[..]);
+            }
+        }
+
+        let mut filter_params = query_filter.init_params(filter.params.len() as u32);
+        for (j, param) in filter.params.iter().enumerate() {
+            filter_params.set(j as u32, &param[..]);
+        }
+    }
+}
+
+fn get_fields(fields: capnp::struct_list::Reader<message_capnp::field::Owned>) -> Result<Entity,Error> {
+    fields.iter().map(|field| {
+        let name = try!(field.get_name().map_err(invalid_reply));
+        let value = try!(field.get_value().map_err(invalid_reply));
+        Ok((name.to_string(), value.to_string()))
+    }).collect()
+}
+
+fn get_group_counts(group_counts: capnp::struct_list::Reader<message_capnp::group_count::Owned>) -> Result<Vec<(Vec<String>,u64)>,Error> {
+    group_counts.iter().map(|group_count| {
+        let values = try!(group_count.get_values().map_err(invalid_reply));
+        let values = try!((0..values.len()).map(|i| values.get(i).map(|value| value.to_string()).map_err(invalid_reply)).collect::<Result<Vec<String>,Error>>());
+        Ok((values, group_count.get_count()))
+    }).collect()
+}
+
+//a message that can not be read is a protocol error rather than a reason to panic
+fn invalid_reply(e: capnp::Error) -> Error {
+    Error::Protocol(format!("invalid reply: {}", e))
+}
+
+fn server_error(error_msg: capnp::Result<capnp::text::Reader>) -> Error {
+    match error_msg {
+        Ok(error_msg) => Error::Server(error_msg.to_string()),
+        Err(e) => invalid_reply(e),
+    }
+}
+
+fn write_message(stream: &mut Stream, msg_builder: &Builder<HeapAllocator>) -> Result<(),Error> {
+    capnp::serialize::write_message(stream, msg_builder).map_err(|e| Error::Io(format!("failed to send message: {}", e)))
+}
+
+fn read_message(stream: &mut Stream) -> Result<Reader<OwnedSegments>,Error> {
+    capnp::serialize::read_message(stream, ReaderOptions::new()).map_err(|e| Error::Io(format!("failed to read message: {}", e)))
+}
@@ -0,0 +1,259 @@
+extern crate ruzzy;
+extern crate unicode_normalization;
+extern crate unicode_segmentation;
+
+use self::unicode_normalization::UnicodeNormalization;
+use self::unicode_segmentation::UnicodeSegmentation;
+
+use normalize::Normalizer;
+
+use std::char;
+use std::collections::HashMap;
+
+//the comparisons below operate on the grapheme clusters of the nfc form of
+//each value so that a character written with combining marks is equal to
+//its precomposed form
+
+/// Splits the nfc form of a value into its user-perceived characters.
+pub fn graphemes(value: &str) -> Vec<String> {
+    let value: String = value.nfc().collect();
+    UnicodeSegmentation::graphemes(&value[..], true).map(|x| x.to_string()).collect()
+}
+
+/// Returns the number of user-perceived characters in a value.
+pub fn length(value: &str) -> usize {
+    let value: String = value.nfc().collect();
+    UnicodeSegmentation::graphemes(&value[..], true).count()
+}
+
+/// Compares the nfc forms of two values.
+pub fn equality(a: &str, b: &str) -> bool {
+    a == b || a.nfc().eq(b.nfc())
+}
+
+/// Removes accents so that values may be compared accent insensitively.
+pub fn strip_accents(value: &str) -> String {
+    Normalizer::StripAccents.apply(value)
+}
+
+//ruzzy compares values character by character, so each distinct grapheme of
+//the two values is encoded as a single character, ascii while there are few
+//enough of them and private use characters beyond, and ruzzy compares those
+fn encode(a: &str, b: &str) -> (String,String) {
+    let mut codes: HashMap<String,char> = HashMap::new();
+    let mut encode_value = |value: &str| -> String {
+        graphemes(value).into_iter().map(|grapheme| {
+            let code_count = codes.len() as u32;
+            *codes.entry(grapheme).or_insert_with(|| {
+                let code = if code_count < 128 { code_count } else { 0xF0000 + code_count - 128 };
+                char::from_u32(code).unwrap_or('\u{fffd}')
+            })
+        }).collect()
+    };
+
+    let a = encode_value(a);
+    (a, encode_value(b))
+}
+
+pub fn damerau_levenshtein(a: &str, b: &str) -> u16 {
+    let (a, b) = encode(a, b);
+    ruzzy::damerau_levenshtein::compare(&a, &b)
+}
+
+pub fn jaro(a: &str, b: &str) -> f64 {
+    let (a, b) = encode(a, b);
+    ruzzy::jaro::compare(&a, &b)
+}
+
+pub fn jaro_winkler(a: &str, b: &str, scaling_factor: f32) -> f64 {
+    let (a, b) = encode(a, b);
+    ruzzy::jaro_winkler::compare(&a, &b, scaling_factor)
+}
+
+pub fn levenshtein(a: &str, b: &str) -> u16 {
+    let (a, b) = encode(a, b);
+    ruzzy::levenshtein::compare(&a, &b)
+}
+
+pub fn ngram(a: &str, b: &str, ngram_size: usize) -> f64 {
+    let (a, b) = encode(a, b);
+    ruzzy::ngram::compare(&a, &b, ngram_size)
+}
+
+/// Compares the soundex codes of the values with their accents removed,
+/// since the codes are only defined for latin letters.
+pub fn soundex(a: &str, b: &str) -> bool {
+    ruzzy::soundex::compare(&strip_accents(a), &strip_accents(b))
+}
+
+/// Encodes the letters of a value as its american soundex code, ignoring
+/// accents and characters outside of the latin alphabet.
+pub fn soundex_code(value: &str) -> String {
+    let letters: Vec<char> = strip_accents(value).chars().filter(|c| (*c as u32) < 128 && c.is_alphabetic()).flat_map(|c| c.to_uppercase()).collect();
+    if letters.is_empty() {
+        return String::new();
+    }
+
+    let code = |c: char| match c {
+        'B' | 'F' | 'P' | 'V' => '1',
+        'C' | 'G' | 'J' | 'K' | 'Q' | 'S' | 'X' | 'Z' => '2',
+        'D' | 'T' => '3',
+        'L' => '4',
+        'M' | 'N' => '5',
+        'R' => '6',
+        _ => '0',
+    };
+
+    let mut key = letters[0].to_string();
+    let mut last_code = code(letters[0]);
+    for c in letters[1..].iter() {
+        let c_code = code(*c);
+        if c_code != '0' && c_code != last_code {
+            key.push(c_code);
+            if key.len() == 4 {
+                break;
+            }
+        }
+
+        //h and w do not separate letters with the same code
+        if *c != 'H' && *c != 'W' {
+            last_code = c_code;
+        }
+    }
+
+    while key.len() < 4 {
+        key.push('0');
+    }
+
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //the same name written with a precomposed e acute and with a combining acute accent
+    static PRECOMPOSED: &'static str = "jos\u{e9}";
+    static COMBINING: &'static str = "jose\u{301}";
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 0.001, "expected {} but got {}", expected, actual);
+    }
+
+    #[test]
+    fn graphemes_count_user_perceived_characters() {
+        assert_eq!(length("rammer"), 6);
+        assert_eq!(length("m\u{fc}ller"), 6);
+        assert_eq!(length("\u{65e5}\u{672c}\u{8a9e}"), 3);
+        assert_eq!(length(PRECOMPOSED), 4);
+        assert_eq!(length(COMBINING), 4);
+        assert_eq!(graphemes(COMBINING), graphemes(PRECOMPOSED));
+    }
+
+    #[test]
+    fn equality_compares_nfc_forms() {
+        assert!(equality("rammer", "rammer"));
+        assert!(!equality("rammer", "rammor"));
+        assert!(equality("m\u{fc}ller", "m\u{fc}ller"));
+        assert!(equality(PRECOMPOSED, COMBINING));
+        assert!(!equality(PRECOMPOSED, "jose"));
+        assert!(equality(&strip_accents(PRECOMPOSED)[..], "jose"));
+        assert!(equality(&strip_accents(COMBINING)[..], "jose"));
+    }
+
+    #[test]
+    fn levenshtein_ascii() {
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+        assert_eq!(levenshtein("rammer", "rammer"), 0);
+        assert_eq!(levenshtein("", "abc"), 3);
+        assert_eq!(levenshtein("flaw", "lawn"), 2);
+    }
+
+    #[test]
+    fn levenshtein_multi_byte() {
+        assert_eq!(levenshtein("m\u{fc}ller", "muller"), 1);
+        assert_eq!(levenshtein("m\u{fc}ller", "m\u{fc}ler"), 1);
+        assert_eq!(levenshtein("\u{65e5}\u{672c}\u{8a9e}", "\u{65e5}\u{672c}"), 1);
+        assert_eq!(levenshtein(PRECOMPOSED, COMBINING), 0);
+        assert_eq!(levenshtein(COMBINING, "jose"), 1);
+    }
+
+    #[test]
+    fn damerau_levenshtein_ascii() {
+        assert_eq!(damerau_levenshtein("kitten", "sitting"), 3);
+        assert_eq!(damerau_levenshtein("abcd", "acbd"), 1);
+        assert_eq!(damerau_levenshtein("", "abc"), 3);
+    }
+
+    #[test]
+    fn damerau_levenshtein_multi_byte() {
+        assert_eq!(damerau_levenshtein("m\u{fc}ller", "mlu\u{308}ler"), 1);
+        assert_eq!(damerau_levenshtein("\u{e9}a", "a\u{e9}"), 1);
+        assert_eq!(damerau_levenshtein(PRECOMPOSED, COMBINING), 0);
+        assert_eq!(damerau_levenshtein("jo\u{301}se", "jose\u{301}"), 2);
+    }
+
+    #[test]
+    fn jaro_ascii() {
+        assert_close(jaro("martha", "marhta"), 0.944);
+        assert_close(jaro("dixon", "dicksonx"), 0.767);
+        assert_close(jaro("jellyfish", "smellyfish"), 0.896);
+        assert_close(jaro("rammer", "rammer"), 1.0);
+        assert_close(jaro("abc", "xyz"), 0.0);
+    }
+
+    #[test]
+    fn jaro_multi_byte() {
+        assert_close(jaro("m\u{e4}rtha", "m\u{e4}rhta"), 0.944);
+        assert_close(jaro(PRECOMPOSED, COMBINING), 1.0);
+        assert_close(jaro(COMBINING, "jose"), jaro("josx", "jose"));
+    }
+
+    #[test]
+    fn jaro_winkler_ascii() {
+        assert_close(jaro_winkler("martha", "marhta", 0.1), 0.961);
+        assert_close(jaro_winkler("dixon", "dicksonx", 0.1), 0.813);
+        assert_close(jaro_winkler("dwayne", "duane", 0.1), 0.840);
+    }
+
+    #[test]
+    fn jaro_winkler_multi_byte() {
+        assert_close(jaro_winkler("m\u{e4}rtha", "m\u{e4}rhta", 0.1), 0.961);
+        assert_close(jaro_winkler(PRECOMPOSED, COMBINING, 0.1), 1.0);
+        assert_close(jaro_winkler(COMBINING, "jose", 0.1), jaro_winkler("josx", "jose", 0.1));
+    }
+
+    #[test]
+    fn ngram_ascii() {
+        assert_close(ngram("rammer", "rammer", 2), 1.0);
+        assert!(ngram("night", "nacht", 2) < ngram("night", "nicht", 2));
+        assert_close(ngram("abcd", "wxyz", 2), 0.0);
+    }
+
+    #[test]
+    fn ngram_multi_byte() {
+        //multi-byte graphemes score as single characters do
+        assert_close(ngram("n\u{ee}ght", "n\u{e4}cht", 2), ngram("night", "nacht", 2));
+        assert_close(ngram("\u{65e5}\u{672c}\u{8a9e}", "\u{65e5}\u{672c}", 2), ngram("abc", "ab", 2));
+        assert_close(ngram(PRECOMPOSED, COMBINING, 2), 1.0);
+    }
+
+    #[test]
+    fn soundex_ascii() {
+        assert_eq!(soundex_code("robert"), "R163");
+        assert_eq!(soundex_code("rupert"), "R163");
+        assert_eq!(soundex_code("tymczak"), "T522");
+        assert_eq!(soundex_code("pfister"), "P236");
+        assert_eq!(soundex_code("ashcraft"), "A261");
+        assert!(soundex("robert", "rupert"));
+        assert!(!soundex("robert", "rammer"));
+        assert!(!soundex("", ""));
+    }
+
+    #[test]
+    fn soundex_multi_byte() {
+        assert_eq!(soundex_code("m\u{fc}ller"), soundex_code("muller"));
+        assert_eq!(soundex_code(PRECOMPOSED), soundex_code(COMBINING));
+        assert_eq!(soundex_code("\u{65e5}\u{672c}"), "");
+    }
+}
@@ -0,0 +1,202 @@
+use capnp;
+use capnp::message::{Allocator,Builder,Reader,ReaderOptions};
+use capnp::serialize::OwnedSegments;
+
+use auth::Credentials;
+use pool::ThreadPool;
+use protocol;
+use transport::{Stream,Transport};
+
+use std::collections::HashMap;
+use std::net::{SocketAddr,SocketAddrV4};
+use std::sync::{Arc,Mutex};
+use std::time::{Duration,Instant};
+
+//seconds a connection may sit idle in the pool before it is closed
+static IDLE_TIMEOUT_SECS: u64 = 30;
+
+/// Keeps connections to other nodes open between requests. A connection
+/// carries a single request at a time, since messages carry no request id
+/// to multiplex requests over a shared connection, and it is returned to the pool once its response has
+/// been read, so each peer has at most max_idle idle connections while
+/// concurrent requests open as many as they need. Every idle connection
+/// holds a connection thread on its peer, so connections idle for longer
+/// than IDLE_TIMEOUT_SECS are closed. Requests sent to several peers at
+/// once run on a fixed number of request threads shared by every
+/// connection on the node, so fanning out never starts threads of its own.
+/// Requests whose handlers fan out to other nodes in turn are sent with
+/// request_all on the calling thread instead, since a request thread
+/// waiting on such a handler may hold the very thread it needs.
+pub struct ConnectionPool {
+    max_idle: usize,
+    idle: Mutex<HashMap<SocketAddrV4,Vec<(Stream,Instant)>>>,
+    transport: Arc<Transport>,
+    credentials: Credentials,
+    request_pool: ThreadPool,
+}
+
+impl ConnectionPool {
+    pub fn new(max_idle: usize, request_threads: usize, transport: Arc<Transport>, credentials: Credentials) -> ConnectionPool {
+        ConnectionPool {
+            max_idle: max_idle,
+            idle: Mutex::new(HashMap::new()),
+            transport: transport,
+            credentials: credentials,
+            request_pool: ThreadPool::new(request_threads),
+        }
+    }
+
+    /// Runs a job on the request threads, queueing it while they are all
+    /// busy. Jobs should only wait on peers, a job waiting on another job
+    /// may never run.
+    pub fn execute<F>(&self, f: F) where F: FnOnce() + Send + 'static {
+        self.request_pool.execute(f);
+    }
+
+    /// returns an idle connection to the peer or opens and handshakes a new one
+    pub fn get(&self, socket_addr: SocketAddrV4) -> Result<Stream,String> {
+        match self.take_idle(socket_addr) {
+            Some(stream) => Ok(stream),
+            None => self.connect(socket_addr),
+        }
+    }
+
+    /// returns a connection with no request in progress to the pool
+    pub fn put(&self, socket_addr: SocketAddrV4, stream: Stream) {
+        let mut idle = self.idle.lock().unwrap();
+
+        //close the connections of every peer that have been idle too long
+        for (_, streams) in idle.iter_mut() {
+            streams.retain(|&(_, idle_since)| !expired(idle_since));
+        }
+
+        let streams = idle.entry(socket_addr).or_insert(vec!());
+        if streams.len() < self.max_idle {
+            streams.push((stream, Instant::now()));
+        }
+    }
+
+    /// Sends a message to the peer and reads its response. A pooled
+    /// connection the peer has since closed is replaced by a new connection
+    /// before the request fails.
+    pub fn request<A: Allocator>(&self, socket_addr: SocketAddrV4, msg_builder: &Builder<A>) -> Result<Reader<OwnedSegments>,String> {
+        let (stream, msg_reader) = try!(self.open(socket_addr, msg_builder));
+        self.put(socket_addr, stream);
+        Ok(msg_reader)
+    }
+
+    /// Sends a message that starts a longer exchange, such as a write
+    /// stream, and reads its response. The connection is handed to the
+    /// caller, who may put it back once the exchange is complete.
+    pub fn open<A: Allocator>(&self, socket_addr: SocketAddrV4, msg_builder: &Builder<A>) -> Result<(Stream,Reader<OwnedSegments>),String> {
+        //a pooled connection failing means the peer has likely restarted, so
+        //the other idle connections to it are dropped and the message is
+        //sent once more on a new connection
+        if let Some(stream) = self.take_idle(socket_addr) {
+            match send(stream, socket_addr, msg_builder) {
+                Ok(result) => return Ok(result),
+                Err(_) => {
+                    self.idle.lock().unwrap().remove(&socket_addr);
+                },
+            }
+        }
+
+        let stream = try!(self.connect(socket_addr));
+        send(stream, socket_addr, msg_builder)
+    }
+
+    /// Sends a message to every peer before reading any response, so the
+    /// peers handle it at once while the calling thread reads each response
+    /// in turn. This never uses the request threads.
+    pub fn request_all<A: Allocator>(&self, socket_addrs: &[SocketAddrV4], msg_builder: &Builder<A>) -> Vec<Result<Reader<OwnedSegments>,String>> {
+        let streams: Vec<Result<(Stream,bool),String>> = socket_addrs.iter().map(|socket_addr| self.write(*socket_addr, msg_builder)).collect();
+
+        let mut results = vec!();
+        for (socket_addr, stream) in socket_addrs.iter().zip(streams) {
+            let result = stream.and_then(|(stream, pooled)| {
+                match read(stream, *socket_addr) {
+                    //a pooled connection failing is retried once on a new connection as in open
+                    Err(_) if pooled => {
+                        self.idle.lock().unwrap().remove(socket_addr);
+                        let stream = try!(self.connect(*socket_addr));
+                        send(stream, *socket_addr, msg_builder)
+                    },
+                    result => result,
+                }
+            });
+
+            results.push(result.map(|(stream, msg_reader)| {
+                self.put(*socket_addr, stream);
+                msg_reader
+            }));
+        }
+
+        results
+    }
+
+    //writes a message on an idle connection, or on a new connection when the peer has none or the
+    //idle connection fails, and returns the connection with whether it came from the pool
+    fn write<A: Allocator>(&self, socket_addr: SocketAddrV4, msg_builder: &Builder<A>) -> Result<(Stream,bool),String> {
+        if let Some(mut stream) = self.take_idle(socket_addr) {
+            if capnp::serialize::write_message(&mut stream, msg_builder).is_ok() {
+                return Ok((stream, true));
+            }
+
+            self.idle.lock().unwrap().remove(&socket_addr);
+        }
+
+        let mut stream = try!(self.connect(socket_addr));
+        match capnp::serialize::write_message(&mut stream, msg_builder) {
+            Ok(_) => Ok((stream, false)),
+            Err(e) => Err(format!("failed to write to {}: {}", socket_addr, e)),
+        }
+    }
+
+    fn connect(&self, socket_addr: SocketAddrV4) -> Result<Stream,String> {
+        if self.credentials != Credentials::Anonymous && !self.transport.allows_credentials() {
+            return Err(format!("refusing to send credentials to {} without tls", socket_addr));
+        }
+
+        let mut stream = try!(self.transport.connect(SocketAddr::V4(socket_addr)));
+
+        match protocol::handshake(&mut stream, &self.credentials) {
+            Ok(_) => Ok(stream),
+            Err(e) => Err(format!("failed handshake with {}: {}", socket_addr, e)),
+        }
+    }
+
+    fn take_idle(&self, socket_addr: SocketAddrV4) -> Option<Stream> {
+        let mut idle = self.idle.lock().unwrap();
+        let streams = match idle.get_mut(&socket_addr) {
+            Some(streams) => streams,
+            None => return None,
+        };
+
+        while let Some((stream, idle_since)) = streams.pop() {
+            if !expired(idle_since) {
+                return Some(stream);
+            }
+        }
+
+        None
+    }
+}
+
+fn send<A: Allocator>(mut stream: Stream, socket_addr: SocketAddrV4, msg_builder: &Builder<A>) -> Result<(Stream,Reader<OwnedSegments>),String> {
+    if let Err(e) = capnp::serialize::write_message(&mut stream, msg_builder) {
+        return Err(format!("failed to write to {}: {}", socket_addr, e));
+    }
+
+    read(stream, socket_addr)
+}
+
+fn read(mut stream: Stream, socket_addr: SocketAddrV4) -> Result<(Stream,Reader<OwnedSegments>),String> {
+    match capnp::serialize::read_message(&mut stream, ReaderOptions::new()) {
+        Ok(msg_reader) => Ok((stream, msg_reader)),
+        Err(e) => Err(format!("failed to read from {}: {}", socket_addr, e)),
+    }
+}
+
+fn expired(idle_since: Instant) -> bool {
+    idle_since.elapsed() >= Duration::from_secs(IDLE_TIMEOUT_SECS)
+}
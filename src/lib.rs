@@ -1,5 +1,17 @@
+pub mod auth;
+pub mod blocking;
+pub mod cache;
+pub mod client;
+pub mod compare;
+pub mod connection;
+pub mod model;
+pub mod normalize;
 pub mod parser;
+pub mod pool;
+pub mod protocol;
 pub mod query;
+pub mod synonym;
+pub mod transport;
 
 extern crate capnp;
 pub mod message_capnp {
@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::fs::{self,File};
+use std::io::{BufRead,BufReader};
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Classification {
+    Match,
+    Possible,
+    NonMatch,
+}
+
+/// Compares a single field using one of the filter types in query.rs,
+/// adding the agreement weight to a candidate when the filter matches and
+/// the disagreement weight when it does not. Filter types prefixed with
+/// 'noaccent:' compare values without their accents.
+pub struct Comparator {
+    pub field_name: String,
+    pub filter_type: String,
+    pub params: Vec<String>,
+    pub ignore_accents: bool,
+    pub agreement_weight: f64,
+    pub disagreement_weight: f64,
+}
+
+/// A record matching model made up of field comparators and the weight
+/// thresholds used to classify candidates. Models are read from files of
+/// the form below, where comparator weights are either Fellegi-Sunter m
+/// and u probabilities or a simple weight with an optional disagreement
+/// weight. Candidates may be restricted to entities sharing a value of at
+/// least one of the listed blocking keys with the record.
+///
+/// ```text
+/// block = last_name_dob
+/// first_name = jaro_winkler(0.1,0.85) m=0.9 u=0.05
+/// last_name = soundex() m=0.95 u=0.02
+/// dob = equality() weight=4 disagreement=-2
+/// match_threshold = 8
+/// possible_threshold = 3
+/// ```
+pub struct MatchModel {
+    pub blocks: Vec<String>,
+    pub comparators: Vec<Comparator>,
+    pub match_threshold: f64,
+    pub possible_threshold: f64,
+}
+
+impl MatchModel {
+    pub fn from_file(filename: &str) -> Result<MatchModel,String> {
+        let file = match File::open(filename) {
+            Ok(file) => file,
+            Err(e) => return Err(format!("failed to open model file '{}': {}", filename, e)),
+        };
+
+        MatchModel::parse(BufReader::new(file), filename)
+    }
+
+    //parses the lines of a model file, where the filename is only used in errors
+    fn parse<R: BufRead>(reader: R, filename: &str) -> Result<MatchModel,String> {
+        let mut model = MatchModel {
+            blocks: Vec::new(),
+            comparators: Vec::new(),
+            match_threshold: 0.0,
+            possible_threshold: 0.0,
+        };
+
+        for (i, line) in reader.lines().enumerate() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => return Err(format!("failed to read model file '{}': {}", filename, e)),
+            };
+
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("#") {
+                continue;
+            }
+
+            let mut split = line.splitn(2, '=');
+            let (name, definition) = match (split.next(), split.next()) {
+                (Some(name), Some(definition)) => (name.trim(), definition.trim()),
+                _ => return Err(format!("invalid model definition on line {} of '{}'", i + 1, filename)),
+            };
+
+            match name {
+                "block" => model.blocks.push(definition.to_string()),
+                "match_threshold" => model.match_threshold = try!(parse_f64(definition, i, filename)),
+                "possible_threshold" => model.possible_threshold = try!(parse_f64(definition, i, filename)),
+                _ => model.comparators.push(try!(parse_comparator(name, definition, i, filename))),
+            }
+        }
+
+        if model.possible_threshold > model.match_threshold {
+            return Err(format!("possible_threshold is greater than match_threshold in '{}'", filename));
+        }
+
+        Ok(model)
+    }
+
+    pub fn classify(&self, weight: f64) -> Classification {
+        if weight >= self.match_threshold {
+            Classification::Match
+        } else if weight >= self.possible_threshold {
+            Classification::Possible
+        } else {
+            Classification::NonMatch
+        }
+    }
+}
+
+/// The models available to MATCH commands, by name.
+pub struct MatchModels {
+    models: HashMap<String,MatchModel>,
+}
+
+impl MatchModels {
+    pub fn new() -> MatchModels {
+        MatchModels {
+            models: HashMap::new(),
+        }
+    }
+
+    /// Loads every '.model' file in the directory as a model named after
+    /// the file, so 'person.model' is the 'person' model.
+    pub fn from_dir(dirname: &str) -> Result<MatchModels,String> {
+        let entries = match fs::read_dir(dirname) {
+            Ok(entries) => entries,
+            Err(e) => return Err(format!("failed to read model directory '{}': {}", dirname, e)),
+        };
+
+        let mut models = MatchModels::new();
+        for entry in entries {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(e) => return Err(format!("failed to read model directory '{}': {}", dirname, e)),
+            };
+
+            if path.extension().and_then(|x| x.to_str()) != Some("model") {
+                continue;
+            }
+
+            let name = match path.file_stem().and_then(|x| x.to_str()) {
+                Some(name) => name.to_string(),
+                None => continue,
+            };
+
+            let model = try!(MatchModel::from_file(&path.to_string_lossy()));
+            models.models.insert(name, model);
+        }
+
+        Ok(models)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&MatchModel> {
+        self.models.get(name)
+    }
+}
+
+fn parse_comparator(field_name: &str, definition: &str, line: usize, filename: &str) -> Result<Comparator,String> {
+    //split filter definition from weights
+    let (open, close) = match (definition.find('('), definition.find(')')) {
+        (Some(open), Some(close)) if open < close => (open, close),
+        _ => return Err(format!("invalid comparator on line {} of '{}'", line + 1, filename)),
+    };
+
+    let filter_type = definition[..open].trim();
+    let (filter_type, ignore_accents) = if filter_type.starts_with("noaccent:") {
+        (filter_type["noaccent:".len()..].to_string(), true)
+    } else {
+        (filter_type.to_string(), false)
+    };
+
+    let params = definition[open+1..close].split(',').map(|x| x.trim().to_string()).filter(|x| !x.is_empty()).collect();
+
+    let (mut m, mut u, mut weight, mut disagreement) = (None, None, None, None);
+    for weight_definition in definition[close+1..].split_whitespace() {
+        let mut split = weight_definition.splitn(2, '=');
+        let (name, value) = match (split.next(), split.next()) {
+            (Some(name), Some(value)) => (name, try!(parse_f64(value, line, filename))),
+            _ => return Err(format!("invalid weight '{}' on line {} of '{}'", weight_definition, line + 1, filename)),
+        };
+
+        match name {
+            "m" => m = Some(value),
+            "u" => u = Some(value),
+            "weight" => weight = Some(value),
+            "disagreement" => disagreement = Some(value),
+            _ => return Err(format!("unknown weight '{}' on line {} of '{}'", name, line + 1, filename)),
+        }
+    }
+
+    //compute agreement and disagreement weights
+    let (agreement_weight, disagreement_weight) = match (m, u, weight) {
+        (Some(m), Some(u), None) => {
+            if m <= 0.0 || m >= 1.0 || u <= 0.0 || u >= 1.0 {
+                return Err(format!("m and u must be between 0 and 1 on line {} of '{}'", line + 1, filename));
+            }
+
+            ((m / u).log2(), ((1.0 - m) / (1.0 - u)).log2())
+        },
+        (None, None, Some(weight)) => (weight, disagreement.unwrap_or(0.0)),
+        _ => return Err(format!("comparator requires either m and u or weight on line {} of '{}'", line + 1, filename)),
+    };
+
+    Ok(Comparator {
+        field_name: field_name.to_string(),
+        filter_type: filter_type,
+        params: params,
+        ignore_accents: ignore_accents,
+        agreement_weight: agreement_weight,
+        disagreement_weight: disagreement_weight,
+    })
+}
+
+//weights and thresholds must be finite so that candidate weights can always be ordered
+fn parse_f64(value: &str, line: usize, filename: &str) -> Result<f64,String> {
+    match value.trim().parse::<f64>() {
+        Ok(value) if value.is_finite() => Ok(value),
+        _ => Err(format!("invalid number '{}' on line {} of '{}'", value, line + 1, filename)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    fn parse(model: &str) -> Result<MatchModel,String> {
+        MatchModel::parse(Cursor::new(model.as_bytes()), "test.model")
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 0.001, "expected {} but got {}", expected, actual);
+    }
+
+    #[test]
+    fn parse_model() {
+        let model = parse("# person model\nblock = last_name_dob\n\nfirst_name = noaccent:jaro_winkler(0.1, 0.85) m=0.9 u=0.05\ndob = equality() weight=4 disagreement=-2\nmatch_threshold = 8\npossible_threshold = 3\n").unwrap();
+        assert_eq!(model.blocks, vec!("last_name_dob".to_string()));
+        assert_eq!(model.match_threshold, 8.0);
+        assert_eq!(model.possible_threshold, 3.0);
+        assert_eq!(model.comparators.len(), 2);
+
+        let first_name = &model.comparators[0];
+        assert_eq!(first_name.field_name, "first_name");
+        assert_eq!(first_name.filter_type, "jaro_winkler");
+        assert_eq!(first_name.params, vec!("0.1".to_string(), "0.85".to_string()));
+        assert!(first_name.ignore_accents);
+        assert_close(first_name.agreement_weight, (0.9f64 / 0.05).log2());
+        assert_close(first_name.disagreement_weight, (0.1f64 / 0.95).log2());
+
+        let dob = &model.comparators[1];
+        assert_eq!(dob.filter_type, "equality");
+        assert!(dob.params.is_empty());
+        assert!(!dob.ignore_accents);
+        assert_eq!(dob.agreement_weight, 4.0);
+        assert_eq!(dob.disagreement_weight, -2.0);
+    }
+
+    #[test]
+    fn parse_invalid_model() {
+        assert!(parse("first_name jaro(0.8) weight=1\n").is_err());
+        assert!(parse("first_name = jaro weight=1\n").is_err());
+        assert!(parse("first_name = jaro(0.8) weight=one\n").is_err());
+        assert!(parse("first_name = jaro(0.8) scale=1\n").is_err());
+        assert!(parse("first_name = jaro(0.8)\n").is_err());
+        assert!(parse("first_name = jaro(0.8) m=0.9 weight=1\n").is_err());
+        assert!(parse("match_threshold = high\n").is_err());
+    }
+
+    #[test]
+    fn reject_non_finite_weights() {
+        assert!(parse("first_name = jaro(0.8) weight=inf\n").is_err());
+        assert!(parse("first_name = jaro(0.8) weight=1 disagreement=-inf\n").is_err());
+        assert!(parse("first_name = jaro(0.8) m=NaN u=0.1\n").is_err());
+        assert!(parse("match_threshold = NaN\n").is_err());
+        assert!(parse("possible_threshold = -inf\n").is_err());
+    }
+
+    #[test]
+    fn validate_m_and_u() {
+        assert!(parse("first_name = jaro(0.8) m=0.9 u=0.1\n").is_ok());
+        assert!(parse("first_name = jaro(0.8) m=1 u=0.1\n").is_err());
+        assert!(parse("first_name = jaro(0.8) m=0.9 u=0\n").is_err());
+        assert!(parse("first_name = jaro(0.8) m=-0.1 u=0.1\n").is_err());
+        assert!(parse("first_name = jaro(0.8) m=0.9\n").is_err());
+    }
+
+    #[test]
+    fn thresholds() {
+        assert!(parse("match_threshold = 3\npossible_threshold = 8\n").is_err());
+
+        let model = parse("match_threshold = 8\npossible_threshold = 3\n").unwrap();
+        assert_eq!(model.classify(8.0), Classification::Match);
+        assert_eq!(model.classify(12.5), Classification::Match);
+        assert_eq!(model.classify(7.9), Classification::Possible);
+        assert_eq!(model.classify(3.0), Classification::Possible);
+        assert_eq!(model.classify(-1.0), Classification::NonMatch);
+    }
+}
@@ -0,0 +1,217 @@
+extern crate unicode_normalization;
+
+use self::unicode_normalization::UnicodeNormalization;
+use self::unicode_normalization::char::is_combining_mark;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead,BufReader};
+use std::str::FromStr;
+
+#[derive(Clone,Debug,PartialEq)]
+pub enum Normalizer {
+    CollapseWhitespace,
+    Lowercase,
+    Nfkc,
+    RemovePunctuation,
+    StripAccents,
+}
+
+impl Normalizer {
+    pub fn apply(&self, value: &str) -> String {
+        match *self {
+            Normalizer::CollapseWhitespace => value.split_whitespace().collect::<Vec<&str>>().join(" "),
+            Normalizer::Lowercase => value.to_lowercase(),
+            Normalizer::Nfkc => value.nfkc().collect(),
+            Normalizer::RemovePunctuation => value.chars().filter(|c| c.is_alphanumeric() || c.is_whitespace()).collect(),
+            Normalizer::StripAccents => value.nfd().filter(|c| !is_combining_mark(*c)).nfc().collect(),
+        }
+    }
+}
+
+impl FromStr for Normalizer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Normalizer,String> {
+        match s {
+            "collapse_whitespace" => Ok(Normalizer::CollapseWhitespace),
+            "lowercase" => Ok(Normalizer::Lowercase),
+            "nfkc" => Ok(Normalizer::Nfkc),
+            "remove_punctuation" => Ok(Normalizer::RemovePunctuation),
+            "strip_accents" => Ok(Normalizer::StripAccents),
+            _ => Err(format!("unknown normalizer '{}'", s)),
+        }
+    }
+}
+
+/// The normalization pipelines applied to field values, both when they
+/// are written and when they are queried. Fields without a pipeline of
+/// their own use the default pipeline.
+pub struct Normalizers {
+    default: Vec<Normalizer>,
+    fields: HashMap<String,Vec<Normalizer>>,
+}
+
+impl Normalizers {
+    pub fn new() -> Normalizers {
+        Normalizers {
+            default: vec!(Normalizer::Lowercase),
+            fields: HashMap::new(),
+        }
+    }
+
+    /// Reads pipelines from a file with one 'field_name = normalizer, ...'
+    /// line per field, where a field name of '*' sets the default pipeline.
+    /// Blank lines and lines starting with '#' are ignored.
+    pub fn from_file(filename: &str) -> Result<Normalizers,String> {
+        let file = match File::open(filename) {
+            Ok(file) => file,
+            Err(e) => return Err(format!("failed to open normalizers file '{}': {}", filename, e)),
+        };
+
+        Normalizers::parse(BufReader::new(file), filename)
+    }
+
+    //parses the lines of a normalizers file, where the filename is only used in errors
+    fn parse<R: BufRead>(reader: R, filename: &str) -> Result<Normalizers,String> {
+        let mut normalizers = Normalizers::new();
+        for (i, line) in reader.lines().enumerate() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => return Err(format!("failed to read normalizers file '{}': {}", filename, e)),
+            };
+
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("#") {
+                continue;
+            }
+
+            let mut split = line.splitn(2, '=');
+            let (field_name, pipeline) = match (split.next(), split.next()) {
+                (Some(field_name), Some(pipeline)) => (field_name.trim(), pipeline.trim()),
+                _ => return Err(format!("invalid normalizers definition on line {}: '{}'", i + 1, line)),
+            };
+
+            let mut normalizer_list = vec!();
+            for name in pipeline.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+                normalizer_list.push(try!(Normalizer::from_str(name)));
+            }
+
+            normalizers.set(field_name, normalizer_list);
+        }
+
+        Ok(normalizers)
+    }
+
+    pub fn set(&mut self, field_name: &str, normalizer_list: Vec<Normalizer>) {
+        match field_name {
+            "*" => self.default = normalizer_list,
+            _ => { self.fields.insert(field_name.to_string(), normalizer_list); },
+        }
+    }
+
+    pub fn normalize(&self, field_name: &str, value: &str) -> String {
+        apply_pipeline(self.pipeline(field_name), value)
+    }
+
+    /// returns the normalizers applied to values of the field in order
+    pub fn pipeline(&self, field_name: &str) -> &[Normalizer] {
+        self.fields.get(field_name).unwrap_or(&self.default)
+    }
+}
+
+/// Applies each normalizer of a pipeline in turn.
+pub fn apply_pipeline(normalizer_list: &[Normalizer], value: &str) -> String {
+    normalizer_list.iter().fold(value.to_string(), |value, normalizer| normalizer.apply(&value))
+}
+
+/// Splits a normalized value into its distinct words.
+pub fn tokenize(value: &str) -> Vec<String> {
+    let mut tokens: Vec<String> = value.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(|token| token.to_string())
+        .collect();
+
+    tokens.sort();
+    tokens.dedup();
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    fn parse(definitions: &str) -> Result<Normalizers,String> {
+        Normalizers::parse(Cursor::new(definitions.as_bytes()), "test.normalizers")
+    }
+
+    #[test]
+    fn collapse_whitespace() {
+        assert_eq!(Normalizer::CollapseWhitespace.apply("  daniel \t rammer\n"), "daniel rammer");
+        assert_eq!(Normalizer::CollapseWhitespace.apply("rammer"), "rammer");
+    }
+
+    #[test]
+    fn lowercase() {
+        assert_eq!(Normalizer::Lowercase.apply("Daniel RAMMER"), "daniel rammer");
+        assert_eq!(Normalizer::Lowercase.apply("M\u{dc}LLER"), "m\u{fc}ller");
+    }
+
+    #[test]
+    fn nfkc() {
+        assert_eq!(Normalizer::Nfkc.apply("jose\u{301}"), "jos\u{e9}");
+        assert_eq!(Normalizer::Nfkc.apply("\u{fb01}sher"), "fisher");
+        assert_eq!(Normalizer::Nfkc.apply("\u{ff32}ammer"), "Rammer");
+    }
+
+    #[test]
+    fn remove_punctuation() {
+        assert_eq!(Normalizer::RemovePunctuation.apply("o'brien-smith, jr."), "obriensmith jr");
+        assert_eq!(Normalizer::RemovePunctuation.apply("m\u{fc}ller"), "m\u{fc}ller");
+    }
+
+    #[test]
+    fn strip_accents() {
+        assert_eq!(Normalizer::StripAccents.apply("jos\u{e9}"), "jose");
+        assert_eq!(Normalizer::StripAccents.apply("jose\u{301}"), "jose");
+        assert_eq!(Normalizer::StripAccents.apply("M\u{fc}ller"), "Muller");
+    }
+
+    #[test]
+    fn normalizer_names() {
+        assert_eq!(Normalizer::from_str("collapse_whitespace"), Ok(Normalizer::CollapseWhitespace));
+        assert_eq!(Normalizer::from_str("lowercase"), Ok(Normalizer::Lowercase));
+        assert_eq!(Normalizer::from_str("nfkc"), Ok(Normalizer::Nfkc));
+        assert_eq!(Normalizer::from_str("remove_punctuation"), Ok(Normalizer::RemovePunctuation));
+        assert_eq!(Normalizer::from_str("strip_accents"), Ok(Normalizer::StripAccents));
+        assert!(Normalizer::from_str("uppercase").is_err());
+    }
+
+    #[test]
+    fn default_pipeline() {
+        let normalizers = Normalizers::new();
+        assert_eq!(normalizers.normalize("last_name", "Rammer"), "rammer");
+    }
+
+    #[test]
+    fn parse_pipelines() {
+        let normalizers = parse("# pipelines\n\n* = collapse_whitespace\nlast_name = lowercase, strip_accents, remove_punctuation\nid =\n").unwrap();
+        assert_eq!(normalizers.normalize("first_name", " Daniel  Lee "), "Daniel Lee");
+        assert_eq!(normalizers.normalize("last_name", "M\u{fc}ller-Smith"), "mullersmith");
+        assert_eq!(normalizers.normalize("id", " A-1 "), " A-1 ");
+    }
+
+    #[test]
+    fn parse_invalid_pipelines() {
+        assert!(parse("last_name lowercase\n").is_err());
+        assert!(parse("last_name = lowercase, uppercase\n").is_err());
+    }
+
+    #[test]
+    fn tokenize_values() {
+        assert_eq!(tokenize("smith-jones smith"), vec!("jones".to_string(), "smith".to_string()));
+        assert!(tokenize(" - ").is_empty());
+    }
+}
@@ -1,27 +1,127 @@
-use nom::{alphanumeric,space};
+use nom;
+use nom::{digit,space,ErrorKind,IResult};
 use std;
 
+#[derive(Debug,PartialEq)]
 pub enum Command {
+    Aggregate( Vec<String>, Vec<Filter>, Vec<String> ),
+    Deduplicate( String ),
     Exit,
     Help,
     Load( String ),
+    Match( Vec<(String,String)>, String, Option<u32> ),
+    MatchFile( String, String, String, Option<u32> ),
     Query( Vec<String>, Vec<Filter> ),
+    Stats,
 }
 
+#[derive(Debug,PartialEq)]
 pub struct Filter {
-    pub field_name: String,
+    pub field_names: Vec<String>,
     pub filter_type: String,
     pub params: Vec<String>,
     pub value: String,
+    pub match_tokens: bool,
+    pub ignore_accents: bool,
+}
+
+impl Filter {
+    /// Creates a filter applied to every field, which may be narrowed to
+    /// named fields using field.
+    pub fn new(filter_type: &str, value: &str) -> Filter {
+        Filter {
+            field_names: Vec::new(),
+            filter_type: filter_type.to_string(),
+            params: Vec::new(),
+            value: value.to_string(),
+            match_tokens: false,
+            ignore_accents: false,
+        }
+    }
+
+    pub fn field(mut self, field_name: &str) -> Filter {
+        self.field_names.push(field_name.to_string());
+        self
+    }
+
+    pub fn param(mut self, param: &str) -> Filter {
+        self.params.push(param.to_string());
+        self
+    }
+
+    pub fn match_tokens(mut self) -> Filter {
+        self.match_tokens = true;
+        self
+    }
+
+    pub fn ignore_accents(mut self) -> Filter {
+        self.ignore_accents = true;
+        self
+    }
 }
 
 named!(
     pub cmd<Command>,
     alt!(
-        exit
+        aggregate
+      | deduplicate
+      | exit
       | help
       | load
+      | match_file
+      | match_record
       | query
+      | stats
+    )
+);
+
+named!(
+    pub aggregate<Command>,
+    chain!(
+        tag!("SELECT") ~
+        space ~
+        field_names: many0!(
+            chain!(
+                field_name: id ~
+                opt!(space) ~
+                tag!(",") ~
+                opt!(space),
+                || field_name
+            )
+        ) ~
+        tag!("COUNT(*)") ~
+        space ~
+        tag!("WHERE") ~
+        space ~
+        filters: filters ~
+        group_by: opt!(
+            chain!(
+                space ~
+                tag!("GROUP BY") ~
+                space ~
+                field_names: field_names,
+                || field_names
+            )
+        ),
+        || {
+            Command::Aggregate(
+                field_names,
+                filters,
+                group_by.unwrap_or(vec!()),
+            )
+        }
+    )
+);
+
+named!(
+    pub deduplicate<Command>,
+    chain!(
+        tag!("DEDUPLICATE") ~
+        space ~
+        tag!("USING") ~
+        space ~
+        model: unquoted_id,
+        || { Command::Deduplicate(model) }
     )
 );
 
@@ -94,14 +194,61 @@ named!(
 named!(
     pub filter<Filter>,
     chain!(
-        field_name: id ~
+        field_names: filter_field_names ~
         space ~
         tag!("~") ~
+        match_tokens: opt!(
+            alt!(
+                tag!("token:") => { |_| true }
+                | tag!("value:") => { |_| false }
+            )
+        ) ~
+        ignore_accents: opt!(tag!("noaccent:")) ~
         filter_type: unquoted_id ~
         params : filter_params ~
         space ~
         value: id,
-        || Filter { field_name: field_name, filter_type: filter_type, params: params,  value: value }
+        || Filter { field_names: field_names, filter_type: filter_type, params: params,  value: value, match_tokens: match_tokens.unwrap_or(false), ignore_accents: ignore_accents.is_some() }
+    )
+);
+
+named!(
+    pub filter_field_names<Vec<String> >,
+    alt!(
+        tag!("*") => { |_| Vec::new() }
+        | chain!(
+            tag!("ANY(") ~
+            opt!(space) ~
+            field_names: field_names ~
+            opt!(space) ~
+            tag!(")"),
+            || field_names
+        )
+        | id => { |field_name| vec!(field_name) }
+    )
+);
+
+named!(
+    pub filters<Vec<Filter> >,
+    chain!(
+        f: filter ~
+        filters: many0!(
+            chain!(
+                space ~
+                tag!("AND") ~
+                space ~
+                f: filter,
+                || { f }
+            )
+        ),
+        || {
+            let mut rtn_filters = vec!(f);
+            for filter in filters {
+                rtn_filters.push(filter);
+            }
+
+            rtn_filters
+        }
     )
 );
 
@@ -120,6 +267,98 @@ named!(
     )
 );
 
+named!(
+    pub match_field<(String,String)>,
+    chain!(
+        field_name: id ~
+        opt!(space) ~
+        tag!(":") ~
+        opt!(space) ~
+        value: id,
+        || (field_name, value)
+    )
+);
+
+named!(
+    pub match_file<Command>,
+    chain!(
+        tag!("MATCH") ~
+        space ~
+        tag!("FILE") ~
+        space ~
+        input: filename ~
+        space ~
+        tag!("USING") ~
+        space ~
+        model: unquoted_id ~
+        space ~
+        tag!("OUTPUT") ~
+        space ~
+        output: filename ~
+        limit: opt!(
+            chain!(
+                space ~
+                tag!("LIMIT") ~
+                space ~
+                limit: number,
+                || limit
+            )
+        ),
+        || { Command::MatchFile(input, model, output, limit) }
+    )
+);
+
+named!(
+    pub match_record<Command>,
+    chain!(
+        tag!("MATCH") ~
+        space ~
+        tag!("{") ~
+        opt!(space) ~
+        f: match_field ~
+        fields: many0!(
+            chain!(
+                opt!(space) ~
+                tag!(",") ~
+                opt!(space) ~
+                f: match_field,
+                || f
+            )
+        ) ~
+        opt!(space) ~
+        tag!("}") ~
+        space ~
+        tag!("USING") ~
+        space ~
+        model: unquoted_id ~
+        limit: opt!(
+            chain!(
+                space ~
+                tag!("LIMIT") ~
+                space ~
+                limit: number,
+                || limit
+            )
+        ),
+        || {
+            let mut rtn_fields = vec!(f);
+            for field in fields {
+                rtn_fields.push(field);
+            }
+
+            Command::Match(rtn_fields, model, limit)
+        }
+    )
+);
+
+named!(
+    pub number<u32>,
+    map_res!(
+        map_res!(digit, std::str::from_utf8),
+        std::str::FromStr::from_str
+    )
+);
+
 named!(
     pub filter_params<Vec<String> >,
     chain!(
@@ -165,25 +404,11 @@ named!(
         space ~
         tag!("WHERE") ~
         space ~
-        f: filter ~
-        filters: many0!(
-            chain!(
-                space ~
-                tag!("AND") ~
-                space ~
-                f: filter,
-                || { f }
-            )
-        ),
+        filters: filters,
         || {
-            let mut rtn_filters = vec!(f);
-            for filter in filters {
-                rtn_filters.push(filter);
-            }
-
             Command::Query(
                 field_names,
-                rtn_filters,
+                filters,
             )
         }
     )
@@ -214,6 +439,14 @@ named!(
     )
 );
 
+named!(
+    pub stats<Command>,
+    chain!(
+        tag!("STATS"),
+        || { Command::Stats }
+    )
+);
+
 named!(
     pub unquoted_id<String>,
     chain!(
@@ -236,3 +469,167 @@ named!(
         }
     )
 );
+
+//recognizes letters and digits of any script, where nom's alphanumeric only recognizes ascii. every
+//character outside of ascii other than whitespace is accepted so combining marks stay with their letters
+fn alphanumeric(input: &[u8]) -> IResult<&[u8],&[u8]> {
+    let text = match std::str::from_utf8(input) {
+        Ok(text) => text,
+        Err(e) => std::str::from_utf8(&input[..e.valid_up_to()]).unwrap_or(""),
+    };
+
+    let length = text.char_indices()
+        .find(|&(_, c)| !(c.is_alphanumeric() || ((c as u32) >= 128 && !c.is_whitespace())))
+        .map(|(i, _)| i)
+        .unwrap_or(text.len());
+
+    match length {
+        0 => IResult::Error(nom::Err::Position(ErrorKind::AlphaNumeric, input)),
+        _ => IResult::Done(&input[length..], &input[..length]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //parses a whole command, failing on trailing input
+    fn parse(input: &str) -> Command {
+        match cmd(input.as_bytes()) {
+            IResult::Done(rest, command) => {
+                assert!(rest.is_empty(), "unparsed input '{}'", String::from_utf8_lossy(rest));
+                command
+            },
+            _ => panic!("failed to parse '{}'", input),
+        }
+    }
+
+    fn create_filter(field_names: Vec<&str>, filter_type: &str, params: Vec<&str>, value: &str) -> Filter {
+        Filter {
+            field_names: field_names.iter().map(|x| x.to_string()).collect(),
+            filter_type: filter_type.to_string(),
+            params: params.iter().map(|x| x.to_string()).collect(),
+            value: value.to_string(),
+            match_tokens: false,
+            ignore_accents: false,
+        }
+    }
+
+    fn strings(values: Vec<&str>) -> Vec<String> {
+        values.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn non_ascii_ids() {
+        assert_eq!(unquoted_id(b"m\xc3\xbcller"), IResult::Done(&b""[..], "m\u{fc}ller".to_string()));
+        assert_eq!(unquoted_id("jose\u{301} rest".as_bytes()), IResult::Done(&b" rest"[..], "jose\u{301}".to_string()));
+        assert_eq!(quoted_id("\"\u{65e5}\u{672c} \u{8a9e}\"".as_bytes()), IResult::Done(&b""[..], "\u{65e5}\u{672c} \u{8a9e}".to_string()));
+        assert_eq!(filename("d\u{e9}j\u{e0}/vu.csv".as_bytes()), IResult::Done(&b""[..], "d\u{e9}j\u{e0}/vu.csv".to_string()));
+        assert!(unquoted_id(b" robert").is_err());
+    }
+
+    #[test]
+    fn count() {
+        assert_eq!(parse("SELECT COUNT(*) WHERE last_name ~soundex() smith"),
+            Command::Aggregate(vec!(), vec!(create_filter(vec!("last_name"), "soundex", vec!(), "smith")), vec!()));
+        assert_eq!(parse("SELECT COUNT(*) WHERE last_name ~levenshtein(1) smith AND state ~equality() ne"),
+            Command::Aggregate(vec!(), vec!(create_filter(vec!("last_name"), "levenshtein", vec!("1"), "smith"), create_filter(vec!("state"), "equality", vec!(), "ne")), vec!()));
+    }
+
+    #[test]
+    fn group_by() {
+        assert_eq!(parse("SELECT state, COUNT(*) WHERE last_name ~soundex() smith GROUP BY state"),
+            Command::Aggregate(strings(vec!("state")), vec!(create_filter(vec!("last_name"), "soundex", vec!(), "smith")), strings(vec!("state"))));
+        assert_eq!(parse("SELECT state,city, COUNT(*) WHERE last_name ~soundex() smith GROUP BY state, city"),
+            Command::Aggregate(strings(vec!("state", "city")), vec!(create_filter(vec!("last_name"), "soundex", vec!(), "smith")), strings(vec!("state", "city"))));
+
+        //selected fields missing from the group by clause are rejected by the client and the server, not the parser
+        assert_eq!(parse("SELECT city, COUNT(*) WHERE last_name ~soundex() smith GROUP BY state"),
+            Command::Aggregate(strings(vec!("city")), vec!(create_filter(vec!("last_name"), "soundex", vec!(), "smith")), strings(vec!("state"))));
+
+        //a select without COUNT(*) is a plain query
+        assert_eq!(parse("SELECT state WHERE last_name ~soundex() smith"),
+            Command::Query(strings(vec!("state")), vec!(create_filter(vec!("last_name"), "soundex", vec!(), "smith"))));
+    }
+
+    #[test]
+    fn any_field_filters() {
+        assert_eq!(parse("SELECT * WHERE ANY(first_name, last_name) ~levenshtein(1) rammer"),
+            Command::Query(vec!(), vec!(create_filter(vec!("first_name", "last_name"), "levenshtein", vec!("1"), "rammer"))));
+        assert_eq!(parse("SELECT first_name WHERE ANY( first_name,last_name ) ~soundex() rammer"),
+            Command::Query(strings(vec!("first_name")), vec!(create_filter(vec!("first_name", "last_name"), "soundex", vec!(), "rammer"))));
+
+        //a star filters every field inserted by clients
+        assert_eq!(parse("SELECT * WHERE * ~equality() rammer AND last_name ~jaro(0.9) rammer"),
+            Command::Query(vec!(), vec!(create_filter(vec!(), "equality", vec!(), "rammer"), create_filter(vec!("last_name"), "jaro", vec!("0.9"), "rammer"))));
+    }
+
+    #[test]
+    fn token_filters() {
+        let mut token_filter = create_filter(vec!("company"), "levenshtein", vec!("1"), "acme");
+        token_filter.match_tokens = true;
+        assert_eq!(parse("SELECT * WHERE company ~token:levenshtein(1) acme"), Command::Query(vec!(), vec!(token_filter)));
+
+        //value: matches whole values, as filters without a prefix do
+        assert_eq!(parse("SELECT * WHERE company ~value:levenshtein(1) acme"),
+            Command::Query(vec!(), vec!(create_filter(vec!("company"), "levenshtein", vec!("1"), "acme"))));
+    }
+
+    #[test]
+    fn synonym_filters() {
+        assert_eq!(parse("SELECT * WHERE first_name ~synonym(nicknames) bill"),
+            Command::Query(vec!(), vec!(create_filter(vec!("first_name"), "synonym", vec!("nicknames"), "bill"))));
+        assert_eq!(parse("SELECT * WHERE first_name ~synonym(nicknames,levenshtein,1) bill"),
+            Command::Query(vec!(), vec!(create_filter(vec!("first_name"), "synonym", vec!("nicknames", "levenshtein", "1"), "bill"))));
+    }
+
+    #[test]
+    fn match_record() {
+        let fields = vec!(("first_name".to_string(), "daniel".to_string()), ("last_name".to_string(), "rammer".to_string()));
+        assert_eq!(parse("MATCH {first_name: daniel, last_name: rammer} USING person"), Command::Match(fields.clone(), "person".to_string(), None));
+        assert_eq!(parse("MATCH { first_name:daniel ,last_name : rammer } USING person LIMIT 5"), Command::Match(fields, "person".to_string(), Some(5)));
+        assert_eq!(parse("MATCH {\"first name\": \"dan rammer\"} USING person"),
+            Command::Match(vec!(("first name".to_string(), "dan rammer".to_string())), "person".to_string(), None));
+    }
+
+    #[test]
+    fn match_file() {
+        assert_eq!(parse("MATCH FILE people.csv USING person OUTPUT results.csv"),
+            Command::MatchFile("people.csv".to_string(), "person".to_string(), "results.csv".to_string(), None));
+        assert_eq!(parse("MATCH FILE data/people-2017.csv USING person OUTPUT /tmp/results_1.csv LIMIT 3"),
+            Command::MatchFile("data/people-2017.csv".to_string(), "person".to_string(), "/tmp/results_1.csv".to_string(), Some(3)));
+    }
+
+    #[test]
+    fn deduplicate() {
+        assert_eq!(parse("DEDUPLICATE USING person"), Command::Deduplicate("person".to_string()));
+        assert!(cmd(b"DEDUPLICATE person").is_err());
+    }
+
+    #[test]
+    fn nearest_filters() {
+        assert_eq!(parse("SELECT * WHERE last_name ~nearest(levenshtein,5) rammer"),
+            Command::Query(vec!(), vec!(create_filter(vec!("last_name"), "nearest", vec!("levenshtein", "5"), "rammer"))));
+        assert_eq!(parse("SELECT * WHERE first_name ~nearest(jaro_winkler, 10, 0.1) danny"),
+            Command::Query(vec!(), vec!(create_filter(vec!("first_name"), "nearest", vec!("jaro_winkler", "10", "0.1"), "danny"))));
+    }
+
+    #[test]
+    fn noaccent_filters() {
+        let mut accent_filter = create_filter(vec!("first_name"), "equality", vec!(), "jos\u{e9}");
+        accent_filter.ignore_accents = true;
+        assert_eq!(parse("SELECT * WHERE first_name ~noaccent:equality() jos\u{e9}"), Command::Query(vec!(), vec!(accent_filter)));
+
+        //token: comes before noaccent: when both are given
+        let mut token_filter = create_filter(vec!("company"), "levenshtein", vec!("1"), "soci\u{e9}t\u{e9}");
+        token_filter.match_tokens = true;
+        token_filter.ignore_accents = true;
+        assert_eq!(parse("SELECT * WHERE company ~token:noaccent:levenshtein(1) soci\u{e9}t\u{e9}"), Command::Query(vec!(), vec!(token_filter)));
+        assert!(cmd("SELECT * WHERE company ~noaccent:token:levenshtein(1) acme".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn stats() {
+        assert_eq!(parse("STATS"), Command::Stats);
+    }
+}
@@ -0,0 +1,53 @@
+extern crate rayon;
+
+use self::rayon::ThreadPoolBuilder;
+
+/// A fixed size pool of worker threads. A single pool is meant to be
+/// shared (through an Arc) by every connection on a node so that the
+/// number of threads doing work is bounded regardless of how many
+/// requests are in flight.
+pub struct ThreadPool {
+    pool: rayon::ThreadPool,
+}
+
+impl ThreadPool {
+    pub fn new(size: usize) -> ThreadPool {
+        let size = if size == 0 { 1 } else { size };
+
+        //keep the worker alive if a job panics rather than aborting the process
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(size)
+            .panic_handler(|_| {})
+            .build()
+            .unwrap();
+
+        ThreadPool {
+            pool: pool,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.pool.current_num_threads()
+    }
+
+    pub fn execute<F>(&self, f: F) where F: FnOnce() + Send + 'static {
+        self.pool.spawn(f);
+    }
+
+    /// Runs every job on the pool and blocks until all of them have
+    /// completed, returning their results in order. Because the call does
+    /// not return before the jobs finish they may borrow from the caller,
+    /// and a job that panics panics the caller once every job is done.
+    pub fn scoped<'a, F, R>(&self, jobs: Vec<F>) -> Vec<R> where F: FnOnce() -> R + Send + 'a, R: Send + 'a {
+        let mut results: Vec<Option<R>> = jobs.iter().map(|_| None).collect();
+        self.pool.scope(|scope| {
+            for (job, result) in jobs.into_iter().zip(results.iter_mut()) {
+                scope.spawn(move |_| {
+                    *result = Some(job());
+                });
+            }
+        });
+
+        results.into_iter().map(|result| result.unwrap()).collect()
+    }
+}
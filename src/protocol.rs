@@ -0,0 +1,271 @@
+use capnp;
+use capnp::message::{Builder,HeapAllocator,ReaderOptions};
+
+use auth::Credentials;
+use message_capnp;
+use message_capnp::message::msg_type::{ErrorMsg,HelloMsg};
+
+use std::fmt;
+use std::io::{Read,Write};
+
+/// Version of the protocol spoken by this build. It is incremented whenever
+/// a change to message.capnp or to the order of messages in an exchange
+/// cannot be understood by the previous release.
+pub static PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest protocol version of a peer this build still understands.
+pub static MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional features of this build, advertised to peers so that newer
+/// features may be added without incrementing the protocol version.
+pub static CAPABILITIES: &'static [&'static str] = &["authentication", "batched_entities", "deduplicate", "field_scores", "nearest_filters", "streamed_results"];
+
+/// returns an error describing why a peer speaking the version cannot be served
+pub fn check_version(version: u32) -> Result<(),String> {
+    if version < MIN_PROTOCOL_VERSION {
+        Err(format!("protocol version {} is older than the oldest supported version {}", version, MIN_PROTOCOL_VERSION))
+    } else if version > PROTOCOL_VERSION {
+        Err(format!("protocol version {} is newer than the supported version {}", version, PROTOCOL_VERSION))
+    } else {
+        Ok(())
+    }
+}
+
+pub fn create_hello_msg(credentials: &Credentials) -> Builder<HeapAllocator> {
+    let mut msg_builder = capnp::message::Builder::new_default();
+    {
+        let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+        let mut hello_msg = msg.get_msg_type().init_hello_msg();
+        hello_msg.set_version(PROTOCOL_VERSION);
+        match *credentials {
+            Credentials::Anonymous => {},
+            Credentials::Password(ref username, ref password) => {
+                hello_msg.set_username(username);
+                hello_msg.set_password(password);
+            },
+            Credentials::Token(ref token) => hello_msg.set_token(token),
+        }
+
+        let mut capabilities = hello_msg.init_capabilities(CAPABILITIES.len() as u32);
+        for (i, capability) in CAPABILITIES.iter().enumerate() {
+            capabilities.set(i as u32, capability);
+        }
+    }
+
+    msg_builder
+}
+
+/// returns the credentials presented in a hello message
+pub fn get_credentials(hello_msg: message_capnp::message::msg_type::hello_msg::Reader) -> Credentials {
+    let (username, password, token) = (hello_msg.get_username().unwrap_or(""), hello_msg.get_password().unwrap_or(""), hello_msg.get_token().unwrap_or(""));
+    if !token.is_empty() {
+        Credentials::Token(token.to_string())
+    } else if !username.is_empty() {
+        Credentials::Password(username.to_string(), password.to_string())
+    } else {
+        Credentials::Anonymous
+    }
+}
+
+/// The reasons a handshake may fail.
+#[derive(Debug)]
+pub enum HandshakeError {
+    /// sending or reading the hello messages failed
+    Io(String),
+    /// the peer speaks a protocol version we do not support
+    Incompatible(String),
+    /// the peer replied with an error, for credentials or a version it does not accept
+    Rejected(String),
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HandshakeError::Io(ref e) | HandshakeError::Incompatible(ref e) => write!(f, "{}", e),
+            HandshakeError::Rejected(ref e) => write!(f, "peer rejected connection: {}", e),
+        }
+    }
+}
+
+/// Sends a hello message with our credentials on a newly opened connection
+/// and reads the peer's reply, returning the capabilities of the peer. Peers
+/// reply with an error message and close the connection when they do not
+/// support our version or do not accept our credentials.
+pub fn handshake<S: Read + Write>(stream: &mut S, credentials: &Credentials) -> Result<Vec<String>,HandshakeError> {
+    if let Err(e) = capnp::serialize::write_message(stream, &create_hello_msg(credentials)) {
+        return Err(HandshakeError::Io(format!("failed to send hello message: {}", e)));
+    }
+
+    let msg_reader = match capnp::serialize::read_message(stream, ReaderOptions::new()) {
+        Ok(msg_reader) => msg_reader,
+        Err(e) => return Err(HandshakeError::Io(format!("failed to read hello message: {}", e))),
+    };
+
+    let msg = match msg_reader.get_root::<message_capnp::message::Reader>() {
+        Ok(msg) => msg,
+        Err(e) => return Err(HandshakeError::Incompatible(format!("failed to parse hello message: {}", e))),
+    };
+
+    match msg.get_msg_type().which() {
+        Ok(HelloMsg(hello_msg)) => {
+            try!(check_version(hello_msg.get_version()).map_err(HandshakeError::Incompatible));
+
+            let mut capabilities = vec!();
+            if let Ok(capabilities_msg) = hello_msg.get_capabilities() {
+                for i in 0..capabilities_msg.len() {
+                    if let Ok(capability) = capabilities_msg.get(i) {
+                        capabilities.push(capability.to_string());
+                    }
+                }
+            }
+
+            Ok(capabilities)
+        },
+        Ok(ErrorMsg(error_msg)) => Err(HandshakeError::Rejected(error_msg.unwrap_or("unknown error").to_string())),
+        _ => Err(HandshakeError::Incompatible("peer replied to hello message with an unexpected message".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use auth::Credentials;
+
+    use capnp;
+    use capnp::message::{Builder,HeapAllocator,ReaderOptions};
+    use message_capnp;
+    use message_capnp::message::msg_type::HelloMsg;
+
+    use std::io::{self,Cursor,Read,Write};
+
+    //a connection whose peer has already written its replies
+    struct MemoryStream {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl MemoryStream {
+        fn new(reply: Option<Builder<HeapAllocator>>) -> MemoryStream {
+            let mut input = vec!();
+            if let Some(reply) = reply {
+                capnp::serialize::write_message(&mut input, &reply).unwrap();
+            }
+
+            MemoryStream {
+                input: Cursor::new(input),
+                output: vec!(),
+            }
+        }
+    }
+
+    impl Read for MemoryStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MemoryStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn create_versioned_hello_msg(version: u32) -> Builder<HeapAllocator> {
+        let mut msg_builder = capnp::message::Builder::new_default();
+        {
+            let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+            let mut hello_msg = msg.get_msg_type().init_hello_msg();
+            hello_msg.set_version(version);
+
+            let mut capabilities = hello_msg.init_capabilities(CAPABILITIES.len() as u32);
+            for (i, capability) in CAPABILITIES.iter().enumerate() {
+                capabilities.set(i as u32, capability);
+            }
+        }
+
+        msg_builder
+    }
+
+    fn create_error_msg(error: &str) -> Builder<HeapAllocator> {
+        let mut msg_builder = capnp::message::Builder::new_default();
+        {
+            let msg = msg_builder.init_root::<message_capnp::message::Builder>();
+            msg.get_msg_type().set_error_msg(error);
+        }
+
+        msg_builder
+    }
+
+    #[test]
+    fn check_version_accepts_supported_range() {
+        assert!(check_version(MIN_PROTOCOL_VERSION).is_ok());
+        assert!(check_version(PROTOCOL_VERSION).is_ok());
+    }
+
+    #[test]
+    fn check_version_rejects_older_and_newer() {
+        assert!(check_version(MIN_PROTOCOL_VERSION - 1).is_err());
+        assert!(check_version(PROTOCOL_VERSION + 1).is_err());
+    }
+
+    #[test]
+    fn hello_msg_carries_credentials() {
+        for credentials in vec!(Credentials::Anonymous, Credentials::Password("daniel".to_string(), "secret".to_string()), Credentials::Token("token".to_string())) {
+            let mut bytes = vec!();
+            capnp::serialize::write_message(&mut bytes, &create_hello_msg(&credentials)).unwrap();
+
+            let msg_reader = capnp::serialize::read_message(&mut Cursor::new(bytes), ReaderOptions::new()).unwrap();
+            let msg = msg_reader.get_root::<message_capnp::message::Reader>().unwrap();
+            match msg.get_msg_type().which() {
+                Ok(HelloMsg(hello_msg)) => {
+                    assert_eq!(hello_msg.get_version(), PROTOCOL_VERSION);
+                    assert_eq!(get_credentials(hello_msg), credentials);
+                },
+                _ => panic!("expected a hello message"),
+            }
+        }
+    }
+
+    #[test]
+    fn handshake_accepts_supported_versions() {
+        for version in MIN_PROTOCOL_VERSION..PROTOCOL_VERSION + 1 {
+            let mut stream = MemoryStream::new(Some(create_versioned_hello_msg(version)));
+            let capabilities = handshake(&mut stream, &Credentials::Anonymous).unwrap();
+            assert_eq!(capabilities, CAPABILITIES.iter().map(|x| x.to_string()).collect::<Vec<String>>());
+            assert!(!stream.output.is_empty());
+        }
+    }
+
+    #[test]
+    fn handshake_rejects_unsupported_versions() {
+        for version in vec!(MIN_PROTOCOL_VERSION - 1, PROTOCOL_VERSION + 1) {
+            let mut stream = MemoryStream::new(Some(create_versioned_hello_msg(version)));
+            match handshake(&mut stream, &Credentials::Anonymous) {
+                Err(HandshakeError::Incompatible(_)) => {},
+                result => panic!("expected an incompatible version but got {:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn handshake_reports_rejection() {
+        let mut stream = MemoryStream::new(Some(create_error_msg("invalid credentials")));
+        match handshake(&mut stream, &Credentials::Token("token".to_string())) {
+            Err(HandshakeError::Rejected(e)) => assert_eq!(e, "invalid credentials"),
+            result => panic!("expected a rejection but got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn handshake_reports_closed_connection() {
+        let mut stream = MemoryStream::new(None);
+        match handshake(&mut stream, &Credentials::Anonymous) {
+            Err(HandshakeError::Io(_)) => {},
+            result => panic!("expected an io error but got {:?}", result),
+        }
+    }
+}
@@ -1,92 +1,487 @@
-extern crate ruzzy;
+use blocking::BlockingKeys;
+use compare;
+use pool::ThreadPool;
 
+use std::cmp::Ordering;
 use std::collections::{HashMap,HashSet};
+use std::str::FromStr;
 
-pub fn query_field(field_name: &str, filter_type: &str, params: Vec<&str>, field_value: &str, fields: &HashMap<String,HashMap<String,Vec<u64>>>) -> HashSet<u64> {
-    let mut entity_keys = HashSet::new();
-    if fields.contains_key(&field_name[..]) {
-        let field_values = fields.get(&field_name[..]).unwrap();
+//smallest number of field values worth handing to a single worker
+static MIN_CHUNK_SIZE: usize = 512;
+
+/// A filter type with its parameters parsed, used to compare two values
+/// outside of a field scan.
+pub enum Comparison {
+    DamerauLevenshtein(u16),
+    Equality,
+    Jaro(f64),
+    JaroWinkler(f32,f64),
+    Levenshtein(u16),
+    Ngram(usize,f64),
+    Soundex,
+}
 
-        //match comparator type
+impl Comparison {
+    pub fn parse(filter_type: &str, params: &[&str]) -> Result<Comparison,String> {
         match filter_type {
-            "damerau_levenshtein" => {
-                let max_distance = params[0].parse::<u16>().unwrap();
-
-                for (value, entity_key_list) in field_values.iter() {
-                    if ruzzy::damerau_levenshtein::compare(value, field_value) <= max_distance {
-                        for entity_key in entity_key_list {
-                            entity_keys.insert(*entity_key);
-                        }
-                    }
-                }
-            },
-            "equality" => {
-                for (value, entity_key_list) in field_values.iter() {
-                    if value == field_value {
-                        for entity_key in entity_key_list {
-                            entity_keys.insert(*entity_key);
-                        }
+            "damerau_levenshtein" => Ok(Comparison::DamerauLevenshtein(try!(parse_param(filter_type, params, 0)))),
+            "equality" => Ok(Comparison::Equality),
+            "jaro" => Ok(Comparison::Jaro(try!(parse_param(filter_type, params, 0)))),
+            "jaro_winkler" => Ok(Comparison::JaroWinkler(try!(parse_param(filter_type, params, 0)), try!(parse_param(filter_type, params, 1)))),
+            "levenshtein" => Ok(Comparison::Levenshtein(try!(parse_param(filter_type, params, 0)))),
+            "ngram" => Ok(Comparison::Ngram(try!(parse_param(filter_type, params, 0)), try!(parse_param(filter_type, params, 1)))),
+            "soundex" => Ok(Comparison::Soundex),
+            _ => Err(format!("unknown filter type '{}'", filter_type)),
+        }
+    }
+
+    /// returns true when a stored value matches the queried field value
+    pub fn is_match(&self, value: &str, field_value: &str) -> bool {
+        match *self {
+            Comparison::DamerauLevenshtein(max_distance) => compare::damerau_levenshtein(value, field_value) <= max_distance,
+            Comparison::Equality => compare::equality(value, field_value),
+            Comparison::Jaro(min_score) => compare::jaro(value, field_value) >= min_score,
+            Comparison::JaroWinkler(scaling_factor, min_score) => compare::jaro_winkler(value, field_value, scaling_factor) >= min_score,
+            Comparison::Levenshtein(max_distance) => compare::levenshtein(value, field_value) <= max_distance,
+            Comparison::Ngram(ngram_size, min_score) => compare::ngram(value, field_value, ngram_size) >= min_score,
+            Comparison::Soundex => compare::soundex(value, field_value),
+        }
+    }
+}
+
+fn parse_param<T: FromStr>(filter_type: &str, params: &[&str], index: usize) -> Result<T,String> {
+    match params.get(index) {
+        Some(param) => param.trim().parse::<T>().map_err(|_| format!("invalid parameter '{}' for filter type '{}'", param, filter_type)),
+        None => Err(format!("filter type '{}' requires at least {} parameters", filter_type, index + 1)),
+    }
+}
+
+/// Evaluates a filter over each of the given fields, or over every field
+/// inserted by clients when field_names is empty, and returns the union of
+/// matching entity keys.
+/// Values are compared without their accents when ignore_accents is set.
+/// The filter type and its parameters are checked before any value is
+/// scanned, returning an error when they are invalid.
+pub fn query_field(field_names: &[&str], filter_type: &str, params: Vec<&str>, field_value: &str, ignore_accents: bool, fields: &HashMap<String,HashMap<String,Vec<u64>>>, blocking_keys: &BlockingKeys, pool: &ThreadPool) -> Result<HashSet<u64>,String> {
+    let comparison = try!(Comparison::parse(filter_type, &params[..]));
+
+    let mut entity_keys = HashSet::new();
+    let stripped_field_value;
+    let field_value = if ignore_accents {
+        stripped_field_value = compare::strip_accents(field_value);
+        &stripped_field_value[..]
+    } else {
+        field_value
+    };
+
+    //collect the value dictionaries of all selected fields, where no fields skips blocking keys and cluster ids
+    let mut field_values_list = vec!();
+    if field_names.is_empty() {
+        field_values_list.extend(fields.iter().filter(|&(field_name, _)| !blocking_keys.is_synthetic_field(field_name)).map(|(_, field_values)| field_values));
+    } else {
+        for field_name in field_names {
+            if let Some(field_values) = fields.get(*field_name) {
+                field_values_list.push(field_values);
+            }
+        }
+    }
+
+    match comparison {
+        //exact values are looked up rather than scanned
+        Comparison::Equality if !ignore_accents => {
+            for field_values in field_values_list.iter() {
+                if let Some(entity_key_list) = field_values.get(field_value) {
+                    for entity_key in entity_key_list {
+                        entity_keys.insert(*entity_key);
                     }
                 }
+            }
+        },
+        _ => {
+            scan_field_values(&field_values_list, pool, ignore_accents, &mut entity_keys, |value| {
+                comparison.is_match(value, field_value)
+            });
+        },
+    }
+
+    Ok(entity_keys)
+}
+
+/// The metrics values may be ranked by with their parameters parsed.
+enum Metric {
+    Levenshtein(bool),
+    Jaro(bool,f32),
+    Ngram(usize),
+}
+
+impl Metric {
+    //parses a metric whose parameters are optional, jaro_winkler defaults to a scaling factor of 0.1 and ngram to bigrams
+    fn parse(metric: &str, params: &[&str]) -> Result<Metric,String> {
+        match metric {
+            "damerau_levenshtein" => Ok(Metric::Levenshtein(true)),
+            "levenshtein" => Ok(Metric::Levenshtein(false)),
+            "jaro" | "jaro_winkler" => {
+                let scaling_factor = if params.is_empty() { 0.1 } else { try!(parse_param(metric, params, 0)) };
+                Ok(Metric::Jaro(metric == "jaro_winkler", scaling_factor))
             },
-            "jaro" => {
-                let min_score = params[0].parse::<f64>().unwrap();
-
-                for (value, entity_key_list) in field_values.iter() {
-                    if ruzzy::jaro::compare(value, field_value) >= min_score {
-                        for entity_key in entity_key_list {
-                            entity_keys.insert(*entity_key);
-                        }
-                    }
+            "ngram" => Ok(Metric::Ngram(if params.is_empty() { 2 } else { try!(parse_param(metric, params, 0)) })),
+            _ => Err(format!("unknown nearest metric '{}'", metric)),
+        }
+    }
+}
+
+/// Splits the parameters of a nearest filter into its metric, the number of
+/// values to return and the metric parameters, returning an error when the
+/// count is missing or not a positive number, or the metric is invalid.
+pub fn parse_nearest_params<'a>(params: &[&'a str]) -> Result<(&'a str,usize,Vec<&'a str>),String> {
+    let metric = params.get(0).cloned().unwrap_or("");
+    let k = match params.get(1).map(|x| x.parse::<usize>()) {
+        Some(Ok(k)) if k > 0 => k,
+        Some(_) => return Err(format!("nearest count '{}' is not a positive number", params[1])),
+        None => return Err("nearest filters require a metric and a count".to_string()),
+    };
+
+    let metric_params = if params.len() > 2 { params[2..].to_vec() } else { vec!() };
+    try!(Metric::parse(metric, &metric_params[..]));
+    Ok((metric, k, metric_params))
+}
+
+/// Finds the k values of the given fields, or of every field inserted by
+/// clients when field_names is empty, that score best against field_value under a
+/// metric. Distances are negated so that higher scores are always better.
+/// Returns each value with its score and entity keys, best first, or an
+/// error when k is zero or the metric or its parameters are invalid.
+pub fn nearest_field_values(field_names: &[&str], metric: &str, k: usize, params: Vec<&str>, field_value: &str, ignore_accents: bool, fields: &HashMap<String,HashMap<String,Vec<u64>>>, blocking_keys: &BlockingKeys, pool: &ThreadPool) -> Result<Vec<(f64,String,Vec<u64>)>,String> {
+    let metric = try!(Metric::parse(metric, &params[..]));
+    if k == 0 {
+        return Err("nearest filters must return at least one value".to_string());
+    }
+
+    let stripped_field_value;
+    let field_value = if ignore_accents {
+        stripped_field_value = compare::strip_accents(field_value);
+        &stripped_field_value[..]
+    } else {
+        field_value
+    };
+
+    //merge the value dictionaries of all selected fields
+    let mut values: HashMap<&str,Vec<u64>> = HashMap::new();
+    for (field_name, field_values) in fields.iter() {
+        if (field_names.is_empty() && blocking_keys.is_synthetic_field(field_name)) || (!field_names.is_empty() && !field_names.contains(&&field_name[..])) {
+            continue;
+        }
+
+        for (value, entity_key_list) in field_values.iter() {
+            values.entry(&value[..]).or_insert(vec!()).extend(entity_key_list.iter().cloned());
+        }
+    }
+
+    if values.is_empty() {
+        return Ok(vec!());
+    }
+
+    //score values returning none when the bound computed from their lengths cannot beat the threshold
+    let field_value_length = compare::length(field_value);
+    let nearest_values = match metric {
+        Metric::Levenshtein(is_damerau) => {
+            scan_nearest_values(&values, k, pool, ignore_accents, |value, threshold| {
+                let value_length = compare::length(value);
+                let bound = -((value_length as f64) - (field_value_length as f64)).abs();
+                if threshold.map(|threshold| bound < threshold).unwrap_or(false) {
+                    return None;
                 }
-            },
-            "jaro_winkler" => {
-                let scaling_factor = params[0].parse::<f32>().unwrap();
-                let min_score = params[1].parse::<f64>().unwrap();
-
-                for (value, entity_key_list) in field_values.iter() {
-                    if ruzzy::jaro_winkler::compare(value, field_value, scaling_factor) >= min_score {
-                        for entity_key in entity_key_list {
-                            entity_keys.insert(*entity_key);
-                        }
-                    }
+
+                let distance = if is_damerau {
+                    compare::damerau_levenshtein(value, field_value)
+                } else {
+                    compare::levenshtein(value, field_value)
+                };
+
+                Some(-(distance as f64))
+            })
+        },
+        Metric::Jaro(is_winkler, scaling_factor) => {
+            scan_nearest_values(&values, k, pool, ignore_accents, |value, threshold| {
+                let value_length = compare::length(value);
+                let min_length = (if value_length < field_value_length { value_length } else { field_value_length }) as f64;
+                let mut bound = if min_length == 0.0 {
+                    0.0
+                } else {
+                    (min_length / value_length as f64 + min_length / field_value_length as f64 + 1.0) / 3.0
+                };
+
+                //winkler scaling boosts by at most four common prefix characters
+                if is_winkler {
+                    bound += 4.0 * scaling_factor as f64 * (1.0 - bound);
                 }
-            },
-            "levenshtein" => {
-                let max_distance = params[0].parse::<u16>().unwrap();
-
-                for (value, entity_key_list) in field_values.iter() {
-                    if ruzzy::levenshtein::compare(value, field_value) <= max_distance {
-                        for entity_key in entity_key_list {
-                            entity_keys.insert(*entity_key);
-                        }
-                    }
+
+                if threshold.map(|threshold| bound < threshold).unwrap_or(false) {
+                    return None;
                 }
-            },
-            "ngram" => {
-                let ngram_size = params[0].parse::<usize>().unwrap();
-                let min_score = params[1].parse::<f64>().unwrap();
-
-                for (value, entity_key_list) in field_values.iter() {
-                    if ruzzy::ngram::compare(value, field_value, ngram_size) >= min_score {
-                        for entity_key in entity_key_list {
-                            entity_keys.insert(*entity_key);
-                        }
+
+                Some(if is_winkler {
+                    compare::jaro_winkler(value, field_value, scaling_factor)
+                } else {
+                    compare::jaro(value, field_value)
+                })
+            })
+        },
+        Metric::Ngram(ngram_size) => {
+            //ngram scores depend on how ruzzy weighs the shared ngrams rather than the lengths alone, so every value is scored
+            scan_nearest_values(&values, k, pool, ignore_accents, |value, _| {
+                Some(compare::ngram(value, field_value, ngram_size))
+            })
+        },
+    };
+
+    Ok(nearest_values.into_iter().map(|(score, value)| (score, value.to_string(), values[&value].clone())).collect())
+}
+
+//orders scored values from best to worst, breaking ties on the value
+fn compare_scored_values(a: &(f64,&str), b: &(f64,&str)) -> Ordering {
+    match b.0.partial_cmp(&a.0) {
+        Some(Ordering::Equal) | None => a.1.cmp(b.1),
+        Some(ordering) => ordering,
+    }
+}
+
+//find the k best scoring values of each chunk on the thread pool, passing the score a value
+//must beat once a chunk holds k values, and merge them into the k best overall
+fn scan_nearest_values<'a,F>(values: &HashMap<&'a str,Vec<u64>>, k: usize, pool: &ThreadPool, ignore_accents: bool, score: F) -> Vec<(f64,&'a str)> where F: Fn(&str, Option<f64>) -> Option<f64> + Sync {
+    let values: Vec<&'a str> = values.keys().cloned().collect();
+
+    let mut chunk_size = (values.len() + pool.size() - 1) / pool.size();
+    if chunk_size < MIN_CHUNK_SIZE {
+        chunk_size = MIN_CHUNK_SIZE;
+    }
+
+    let score = &score;
+    let jobs = values.chunks(chunk_size).map(|chunk| {
+        move || {
+            let mut nearest_values: Vec<(f64,&'a str)> = Vec::with_capacity(k + 1);
+            for value in chunk {
+                let threshold = if nearest_values.len() == k { Some(nearest_values[k - 1].0) } else { None };
+                let stripped_value;
+                let compared_value = if ignore_accents {
+                    stripped_value = compare::strip_accents(value);
+                    &stripped_value[..]
+                } else {
+                    *value
+                };
+
+                if let Some(value_score) = score(compared_value, threshold) {
+                    if threshold.map(|threshold| value_score < threshold).unwrap_or(false) {
+                        continue;
                     }
+
+                    nearest_values.push((value_score, *value));
+                    nearest_values.sort_by(compare_scored_values);
+                    nearest_values.truncate(k);
                 }
-            },
-            "soundex" => {
-                for (value, entity_key_list) in field_values.iter() {
-                    if ruzzy::soundex::compare(value, field_value) {
-                        for entity_key in entity_key_list {
-                            entity_keys.insert(*entity_key);
-                        }
-                    }
+            }
+
+            nearest_values
+        }
+    }).collect::<Vec<_>>();
+
+    let mut nearest_values: Vec<(f64,&'a str)> = pool.scoped(jobs).into_iter().flat_map(|x| x.into_iter()).collect();
+    nearest_values.sort_by(compare_scored_values);
+    nearest_values.truncate(k);
+    nearest_values
+}
+
+//split the values of all fields into chunks and score each chunk on the thread pool
+fn scan_field_values<F>(field_values_list: &[&HashMap<String,Vec<u64>>], pool: &ThreadPool, ignore_accents: bool, entity_keys: &mut HashSet<u64>, is_match: F) where F: Fn(&str) -> bool + Sync {
+    let values: Vec<(&String, &Vec<u64>)> = field_values_list.iter().flat_map(|field_values| field_values.iter()).collect();
+    if values.is_empty() {
+        return;
+    }
+
+    let mut chunk_size = (values.len() + pool.size() - 1) / pool.size();
+    if chunk_size < MIN_CHUNK_SIZE {
+        chunk_size = MIN_CHUNK_SIZE;
+    }
+
+    let is_match = &is_match;
+    let jobs = values.chunks(chunk_size).map(|chunk| {
+        move || {
+            let mut keys = vec!();
+            for &(value, entity_key_list) in chunk {
+                let stripped_value;
+                let value = if ignore_accents {
+                    stripped_value = compare::strip_accents(value);
+                    &stripped_value[..]
+                } else {
+                    &value[..]
+                };
+
+                if is_match(value) {
+                    keys.extend(entity_key_list.iter().cloned());
                 }
-            },
-            _ => println!("Unknown filter type {}", filter_type),
+            }
+
+            keys
         }
+    }).collect::<Vec<_>>();
+
+    for keys in pool.scoped(jobs) {
+        for entity_key in keys {
+            entity_keys.insert(entity_key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blocking::CLUSTER_ID_FIELD;
+    use pool::ThreadPool;
+
+    use std::collections::{HashMap,HashSet};
+
+    //entity 1 is stored with a precomposed e acute, entity 2 with a combining accent
+    fn create_fields() -> HashMap<String,HashMap<String,Vec<u64>>> {
+        let mut field_values = HashMap::new();
+        for &(value, entity_key) in [("jos\u{e9}", 1), ("jose\u{301}", 2), ("jose", 3), ("m\u{fc}ller", 4), ("\u{65e5}\u{672c}\u{8a9e}", 5), ("robert", 6)].iter() {
+            field_values.insert(value.to_string(), vec!(entity_key));
+        }
+
+        let mut fields = HashMap::new();
+        fields.insert("name".to_string(), field_values);
+        fields
+    }
+
+    fn query(filter_type: &str, params: Vec<&str>, value: &str, ignore_accents: bool) -> Vec<u64> {
+        let (fields, pool) = (create_fields(), ThreadPool::new(2));
+        let entity_keys: HashSet<u64> = query_field(&["name"], filter_type, params, value, ignore_accents, &fields, &BlockingKeys::new(), &pool).unwrap();
+        let mut entity_keys: Vec<u64> = entity_keys.into_iter().collect();
+        entity_keys.sort();
+        entity_keys
     }
 
-    entity_keys
+    #[test]
+    fn equality() {
+        assert_eq!(query("equality", vec!(), "robert", false), vec!(6));
+        assert_eq!(query("equality", vec!(), "jos\u{e9}", false), vec!(1));
+        assert_eq!(query("equality", vec!(), "jos\u{e9}", true), vec!(1, 2, 3));
+        assert_eq!(query("equality", vec!(), "jose\u{301}", true), vec!(1, 2, 3));
+    }
+
+    #[test]
+    fn levenshtein() {
+        assert_eq!(query("levenshtein", vec!("0"), "jos\u{e9}", false), vec!(1, 2));
+        assert_eq!(query("levenshtein", vec!("1"), "jose", false), vec!(1, 2, 3));
+        assert_eq!(query("levenshtein", vec!("1"), "muller", false), vec!(4));
+        assert_eq!(query("levenshtein", vec!("1"), "\u{65e5}\u{672c}", false), vec!(5));
+        assert_eq!(query("levenshtein", vec!("1"), "rupert", false), vec!());
+    }
+
+    #[test]
+    fn damerau_levenshtein() {
+        assert_eq!(query("damerau_levenshtein", vec!("0"), "jose\u{301}", false), vec!(1, 2));
+        assert_eq!(query("damerau_levenshtein", vec!("1"), "mu\u{308}lelr", false), vec!(4));
+        assert_eq!(query("damerau_levenshtein", vec!("1"), "rboert", false), vec!(6));
+    }
+
+    #[test]
+    fn jaro() {
+        assert_eq!(query("jaro", vec!("0.99"), "jos\u{e9}", false), vec!(1, 2));
+        assert_eq!(query("jaro", vec!("0.99"), "jos\u{e9}", true), vec!(1, 2, 3));
+        assert_eq!(query("jaro", vec!("0.85"), "m\u{fc}lelr", false), vec!(4));
+    }
+
+    #[test]
+    fn jaro_winkler() {
+        assert_eq!(query("jaro_winkler", vec!("0.1", "0.99"), "jose\u{301}", false), vec!(1, 2));
+        assert_eq!(query("jaro_winkler", vec!("0.1", "0.9"), "m\u{fc}lelr", false), vec!(4));
+        assert_eq!(query("jaro_winkler", vec!("0.1", "0.9"), "robret", false), vec!(6));
+    }
+
+    #[test]
+    fn ngram() {
+        assert_eq!(query("ngram", vec!("2", "1.0"), "jos\u{e9}", false), vec!(1, 2));
+        assert_eq!(query("ngram", vec!("2", "0.5"), "\u{65e5}\u{672c}", false), vec!(5));
+        assert_eq!(query("ngram", vec!("2", "0.5"), "m\u{fc}ler", false), vec!(4));
+    }
+
+    #[test]
+    fn soundex() {
+        assert_eq!(query("soundex", vec!(), "rupert", false), vec!(6));
+        assert_eq!(query("soundex", vec!(), "muller", false), vec!(4));
+        assert_eq!(query("soundex", vec!(), "jose\u{301}", false), vec!(1, 2, 3));
+    }
+
+    #[test]
+    fn comparison() {
+        assert!(Comparison::parse("jaro_winkler", &["0.1", "0.9"]).unwrap().is_match("robret", "robert"));
+        assert!(Comparison::parse("levenshtein", &["1"]).unwrap().is_match("m\u{fc}ller", "mu\u{308}ler"));
+        assert!(Comparison::parse("jaro_winkler", &["0.1"]).is_err());
+        assert!(Comparison::parse("levenshtein", &["one"]).is_err());
+        assert!(Comparison::parse("nearest", &[]).is_err());
+    }
+
+    #[test]
+    fn nearest() {
+        let (fields, pool) = (create_fields(), ThreadPool::new(2));
+        let nearest_values = nearest_field_values(&["name"], "levenshtein", 1, vec!(), "m\u{fc}lle", false, &fields, &BlockingKeys::new(), &pool).unwrap();
+        assert_eq!(nearest_values.len(), 1);
+        assert_eq!(nearest_values[0].1, "m\u{fc}ller");
+        assert_eq!(nearest_values[0].0, -1.0);
+
+        assert!(nearest_field_values(&["name"], "jaro_winkler", 1, vec!("high"), "robert", false, &fields, &BlockingKeys::new(), &pool).is_err());
+        assert!(nearest_field_values(&["name"], "hamming", 1, vec!(), "robert", false, &fields, &BlockingKeys::new(), &pool).is_err());
+        assert!(nearest_field_values(&["name"], "levenshtein", 0, vec!(), "robert", false, &fields, &BlockingKeys::new(), &pool).is_err());
+    }
+
+    #[test]
+    fn nearest_ngram() {
+        let (fields, pool) = (create_fields(), ThreadPool::new(2));
+        let nearest_values = nearest_field_values(&["name"], "ngram", 1, vec!("2"), "m\u{fc}ller", false, &fields, &BlockingKeys::new(), &pool).unwrap();
+        assert_eq!(nearest_values.len(), 1);
+        assert_eq!(nearest_values[0].1, "m\u{fc}ller");
+        assert_eq!(nearest_values[0].0, 1.0);
+
+        let nearest_values = nearest_field_values(&["name"], "ngram", 2, vec!("2"), "robbert", false, &fields, &BlockingKeys::new(), &pool).unwrap();
+        assert_eq!(nearest_values[0].1, "robert");
+    }
+
+    #[test]
+    fn nearest_params() {
+        assert_eq!(parse_nearest_params(&["levenshtein", "5"]).unwrap(), ("levenshtein", 5, vec!()));
+        assert_eq!(parse_nearest_params(&["jaro_winkler", "10", "0.2"]).unwrap(), ("jaro_winkler", 10, vec!("0.2")));
+        assert!(parse_nearest_params(&["levenshtein"]).is_err());
+        assert!(parse_nearest_params(&["levenshtein", "0"]).is_err());
+        assert!(parse_nearest_params(&["levenshtein", "five"]).is_err());
+        assert!(parse_nearest_params(&["hamming", "5"]).is_err());
+        assert!(parse_nearest_params(&[]).is_err());
+    }
+
+    #[test]
+    fn skip_synthetic_fields() {
+        let (mut fields, pool, blocking_keys) = (create_fields(), ThreadPool::new(2), BlockingKeys::new());
+        let mut cluster_ids = HashMap::new();
+        cluster_ids.insert("robert".to_string(), vec!(7));
+        fields.insert(CLUSTER_ID_FIELD.to_string(), cluster_ids);
+
+        //cluster ids are only queried when named
+        let entity_keys: Vec<u64> = query_field(&[], "equality", vec!(), "robert", false, &fields, &blocking_keys, &pool).unwrap().into_iter().collect();
+        assert_eq!(entity_keys, vec!(6));
+        let entity_keys: Vec<u64> = query_field(&["cluster_id"], "equality", vec!(), "robert", false, &fields, &blocking_keys, &pool).unwrap().into_iter().collect();
+        assert_eq!(entity_keys, vec!(7));
+
+        let nearest_values = nearest_field_values(&[], "levenshtein", 1, vec!(), "robert", false, &fields, &blocking_keys, &pool).unwrap();
+        assert_eq!(nearest_values[0].2, vec!(6));
+    }
+
+    #[test]
+    fn invalid_params() {
+        let (fields, pool) = (create_fields(), ThreadPool::new(2));
+        assert!(query_field(&["name"], "levenshtein", vec!(), "robert", false, &fields, &BlockingKeys::new(), &pool).is_err());
+        assert!(query_field(&["name"], "ngram", vec!("2", "most"), "robert", false, &fields, &BlockingKeys::new(), &pool).is_err());
+        assert!(query_field(&["name"], "phonetic", vec!(), "robert", false, &fields, &BlockingKeys::new(), &pool).is_err());
+
+        //params are checked even when there are no values to scan
+        assert!(query_field(&["missing"], "jaro", vec!(), "robert", false, &fields, &BlockingKeys::new(), &pool).is_err());
+    }
 }
@@ -0,0 +1,231 @@
+extern crate csv;
+
+use normalize;
+use normalize::Normalizer;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::sync::RwLock;
+
+/// A dictionary of equivalent values, such as a name and its nicknames.
+/// Each row of the csv file it is loaded from defines one group of
+/// equivalent values, and a value may belong to more than one group.
+/// Values are matched to the members of a group after both are normalized
+/// by the pipeline of the field being queried.
+pub struct SynonymDictionary {
+    groups: Vec<Vec<String>>,
+    //the groups of each normalized member, built the first time a pipeline is used
+    members: RwLock<Vec<(Vec<Normalizer>,HashMap<String,Vec<usize>>)>>,
+}
+
+impl SynonymDictionary {
+    pub fn from_file(filename: &str) -> Result<SynonymDictionary,String> {
+        let reader = match csv::Reader::from_file(filename) {
+            Ok(reader) => reader,
+            Err(e) => return Err(format!("failed to open synonym file '{}': {}", filename, e)),
+        };
+
+        SynonymDictionary::parse(reader, filename)
+    }
+
+    //reads the groups of a csv reader, where the filename is only used in errors
+    fn parse<R: Read>(reader: csv::Reader<R>, filename: &str) -> Result<SynonymDictionary,String> {
+        let mut reader = reader.has_headers(false).flexible(true);
+        let mut groups = Vec::new();
+        for record in reader.records() {
+            let record = match record {
+                Ok(record) => record,
+                Err(e) => return Err(format!("failed to read synonym file '{}': {}", filename, e)),
+            };
+
+            let group: Vec<String> = record.iter().map(|x| x.trim().to_string()).filter(|x| !x.is_empty()).collect();
+            if group.len() >= 2 {
+                groups.push(group);
+            }
+        }
+
+        Ok(SynonymDictionary {
+            groups: groups,
+            members: RwLock::new(Vec::new()),
+        })
+    }
+
+    /// returns the value along with every value it shares a group with,
+    /// comparing the value and the members normalized by the pipeline
+    pub fn expand(&self, value: &str, normalizer_list: &[Normalizer]) -> Vec<String> {
+        let value = value.trim();
+        let mut values = vec!(value.to_string());
+        for group_index in self.get_group_indices(&normalize::apply_pipeline(normalizer_list, value), normalizer_list) {
+            for member in self.groups[group_index].iter() {
+                if !values.contains(member) {
+                    values.push(member.clone());
+                }
+            }
+        }
+
+        values
+    }
+
+    fn get_group_indices(&self, normalized_value: &str, normalizer_list: &[Normalizer]) -> Vec<usize> {
+        {
+            let members_list = self.members.read().unwrap();
+            if let Some(&(_, ref members)) = members_list.iter().find(|x| &x.0[..] == normalizer_list) {
+                return members.get(normalized_value).cloned().unwrap_or(vec!());
+            }
+        }
+
+        //index the members under the pipeline, a pipeline indexed meanwhile by another thread is kept
+        let mut members = HashMap::new();
+        for (i, group) in self.groups.iter().enumerate() {
+            for member in group.iter() {
+                let group_indices = members.entry(normalize::apply_pipeline(normalizer_list, member)).or_insert(vec!());
+                if !group_indices.contains(&i) {
+                    group_indices.push(i);
+                }
+            }
+        }
+
+        let group_indices = members.get(normalized_value).cloned().unwrap_or(vec!());
+        let mut members_list = self.members.write().unwrap();
+        if !members_list.iter().any(|x| &x.0[..] == normalizer_list) {
+            members_list.push((normalizer_list.to_vec(), members));
+        }
+
+        group_indices
+    }
+}
+
+/// The synonym dictionaries available to '~synonym' filters, by name.
+pub struct Synonyms {
+    dictionaries: HashMap<String,SynonymDictionary>,
+}
+
+impl Synonyms {
+    pub fn new() -> Synonyms {
+        Synonyms {
+            dictionaries: HashMap::new(),
+        }
+    }
+
+    /// Loads every '.csv' file in the directory as a dictionary named
+    /// after the file, so 'nicknames.csv' is the 'nicknames' dictionary.
+    pub fn from_dir(dirname: &str) -> Result<Synonyms,String> {
+        let entries = match fs::read_dir(dirname) {
+            Ok(entries) => entries,
+            Err(e) => return Err(format!("failed to read synonym directory '{}': {}", dirname, e)),
+        };
+
+        let mut synonyms = Synonyms::new();
+        for entry in entries {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(e) => return Err(format!("failed to read synonym directory '{}': {}", dirname, e)),
+            };
+
+            if path.extension().and_then(|x| x.to_str()) != Some("csv") {
+                continue;
+            }
+
+            let name = match path.file_stem().and_then(|x| x.to_str()) {
+                Some(name) => name.to_string(),
+                None => continue,
+            };
+
+            let dictionary = try!(SynonymDictionary::from_file(&path.to_string_lossy()));
+            synonyms.dictionaries.insert(name, dictionary);
+        }
+
+        Ok(synonyms)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&SynonymDictionary> {
+        self.dictionaries.get(name)
+    }
+
+    /// Returns the filter type and parameters a '~synonym(dictionary
+    /// [,filter_type[,params...]])' filter applies to each expanded value,
+    /// where the filter type defaults to equality, or an error when the
+    /// dictionary is unknown.
+    pub fn filter<'a>(&self, params: &[&'a str]) -> Result<(&'a str, Vec<&'a str>),String> {
+        match params.first() {
+            Some(name) if self.get(name).is_some() => {},
+            Some(name) => return Err(format!("unknown synonym dictionary '{}'", name)),
+            None => return Err("synonym filters require a dictionary".to_string()),
+        }
+
+        let filter_type = if params.len() > 1 { params[1] } else { "equality" };
+        let filter_params = if params.len() > 2 { params[2..].to_vec() } else { vec!() };
+        Ok((filter_type, filter_params))
+    }
+
+    /// Rewrites a synonym filter into the filter applied to each expanded
+    /// value, matching synonyms under the pipeline of the queried field.
+    /// Returns the filter type, its parameters and the values.
+    pub fn expand_filter<'a>(&self, params: &[&'a str], value: &str, normalizer_list: &[Normalizer]) -> Result<(&'a str, Vec<&'a str>, Vec<String>),String> {
+        let (filter_type, filter_params) = try!(self.filter(params));
+        let values = self.get(params[0]).unwrap().expand(value, normalizer_list);
+        Ok((filter_type, filter_params, values))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use normalize::Normalizer;
+
+    use std::io::Cursor;
+
+    fn parse(rows: &str) -> Result<SynonymDictionary,String> {
+        SynonymDictionary::parse(csv::Reader::from_reader(Cursor::new(rows.as_bytes().to_vec())), "test.csv")
+    }
+
+    fn synonyms(rows: &str) -> Synonyms {
+        let mut synonyms = Synonyms::new();
+        synonyms.dictionaries.insert("nicknames".to_string(), parse(rows).unwrap());
+        synonyms
+    }
+
+    #[test]
+    fn parse_groups() {
+        let dictionary = parse("Robert, Bob ,Rob\nWilliam,Bill,\nSolo\n\nRobert,Bobby\n").unwrap();
+        assert_eq!(dictionary.groups, vec!(
+            vec!("Robert".to_string(), "Bob".to_string(), "Rob".to_string()),
+            vec!("William".to_string(), "Bill".to_string()),
+            vec!("Robert".to_string(), "Bobby".to_string()),
+        ));
+    }
+
+    #[test]
+    fn expand_values() {
+        let dictionary = parse("robert,bob,rob\nwilliam,bill\nrobert,bobby\n").unwrap();
+        assert_eq!(dictionary.expand("bill", &[]), vec!("bill", "william"));
+        assert_eq!(dictionary.expand(" robert ", &[]), vec!("robert", "bob", "rob", "bobby"));
+        assert_eq!(dictionary.expand("bob", &[]), vec!("bob", "robert", "rob"));
+        assert_eq!(dictionary.expand("alice", &[]), vec!("alice"));
+    }
+
+    #[test]
+    fn expand_with_pipeline() {
+        let dictionary = parse("Jos\u{e9},Pepe\nWilliam,Bill\n").unwrap();
+
+        //without normalizers only the exact value matches
+        assert_eq!(dictionary.expand("BILL", &[]), vec!("BILL"));
+        assert_eq!(dictionary.expand("BILL", &[Normalizer::Lowercase]), vec!("BILL", "William", "Bill"));
+
+        //the field pipeline decides which forms match, rather than always lowercasing
+        assert_eq!(dictionary.expand("jose", &[Normalizer::Lowercase]), vec!("jose"));
+        assert_eq!(dictionary.expand("jose", &[Normalizer::Lowercase, Normalizer::StripAccents]), vec!("jose", "Jos\u{e9}", "Pepe"));
+        assert_eq!(dictionary.expand("pepe", &[Normalizer::Lowercase, Normalizer::StripAccents]), vec!("pepe", "Jos\u{e9}", "Pepe"));
+    }
+
+    #[test]
+    fn expand_filter() {
+        let synonyms = synonyms("william,bill\n");
+        assert_eq!(synonyms.expand_filter(&["nicknames"], "bill", &[]).unwrap(), ("equality", vec!(), vec!("bill".to_string(), "william".to_string())));
+        assert_eq!(synonyms.expand_filter(&["nicknames", "levenshtein", "1"], "bill", &[]).unwrap(), ("levenshtein", vec!("1"), vec!("bill".to_string(), "william".to_string())));
+        assert!(synonyms.expand_filter(&["surnames"], "bill", &[]).is_err());
+        assert!(synonyms.expand_filter(&[], "bill", &[]).is_err());
+    }
+}
@@ -0,0 +1,296 @@
+extern crate openssl;
+
+use self::openssl::ssl::{SslAcceptor,SslConnector,SslFiletype,SslMethod,SslStream,SslVerifyMode};
+
+use std::io;
+use std::io::{Read,Write};
+use std::net::{SocketAddr,TcpStream,ToSocketAddrs};
+
+/// A connection to a client or node, encrypted when TLS is configured.
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(SslStream<TcpStream>),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut stream) => stream.read(buf),
+            Stream::Tls(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut stream) => stream.write(buf),
+            Stream::Tls(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Plain(ref mut stream) => stream.flush(),
+            Stream::Tls(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+/// Opens and accepts connections, wrapping them in TLS when certificates are
+/// configured. Peers are addressed by ip address so their certificates must
+/// list that address as a subject alternative name. Credentials are only
+/// sent or accepted over TLS unless insecure authentication is allowed.
+pub struct Transport {
+    acceptor: Option<SslAcceptor>,
+    connector: Option<SslConnector>,
+    allow_insecure_auth: bool,
+}
+
+impl Transport {
+    pub fn plain() -> Transport {
+        Transport {
+            acceptor: None,
+            connector: None,
+            allow_insecure_auth: false,
+        }
+    }
+
+    /// Accepts TLS connections using the certificate chain and private key
+    /// files, which are also presented when connecting to other nodes. Peer
+    /// certificates are verified against the ca file, or the system roots
+    /// when none is given, and incoming connections must present a
+    /// certificate signed by the ca when mutual is set.
+    pub fn server(cert_file: &str, key_file: &str, ca_file: Option<&str>, mutual: bool) -> Result<Transport,String> {
+        let mut acceptor = match SslAcceptor::mozilla_intermediate(SslMethod::tls()) {
+            Ok(acceptor) => acceptor,
+            Err(e) => return Err(format!("failed to create tls acceptor: {}", e)),
+        };
+
+        if let Err(e) = acceptor.set_certificate_chain_file(cert_file) {
+            return Err(format!("failed to load certificate file '{}': {}", cert_file, e));
+        }
+
+        if let Err(e) = acceptor.set_private_key_file(key_file, SslFiletype::PEM) {
+            return Err(format!("failed to load private key file '{}': {}", key_file, e));
+        }
+
+        if let Err(e) = acceptor.check_private_key() {
+            return Err(format!("private key '{}' does not match certificate '{}': {}", key_file, cert_file, e));
+        }
+
+        if mutual {
+            let ca_file = match ca_file {
+                Some(ca_file) => ca_file,
+                None => return Err("mutual tls requires a ca file".to_string()),
+            };
+
+            if let Err(e) = acceptor.set_ca_file(ca_file) {
+                return Err(format!("failed to load ca file '{}': {}", ca_file, e));
+            }
+
+            acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        }
+
+        let connector = try!(create_connector(ca_file, Some((cert_file, key_file))));
+        Ok(Transport {
+            acceptor: Some(acceptor.build()),
+            connector: Some(connector),
+            allow_insecure_auth: false,
+        })
+    }
+
+    /// Opens TLS connections verified against the ca file, or the system
+    /// roots when none is given, presenting the certificate and key when the
+    /// server requires mutual tls.
+    pub fn client(ca_file: Option<&str>, identity: Option<(&str,&str)>) -> Result<Transport,String> {
+        Ok(Transport {
+            acceptor: None,
+            connector: Some(try!(create_connector(ca_file, identity))),
+            allow_insecure_auth: false,
+        })
+    }
+
+    /// allows credentials to be sent and accepted over plain connections, for
+    /// networks that are otherwise protected
+    pub fn set_allow_insecure_auth(&mut self, allow_insecure_auth: bool) {
+        self.allow_insecure_auth = allow_insecure_auth;
+    }
+
+    /// returns whether credentials may be sent or accepted over this transport
+    pub fn allows_credentials(&self) -> bool {
+        self.acceptor.is_some() || self.connector.is_some() || self.allow_insecure_auth
+    }
+
+    pub fn connect(&self, socket_addr: SocketAddr) -> Result<Stream,String> {
+        let stream = match TcpStream::connect(socket_addr) {
+            Ok(stream) => stream,
+            Err(e) => return Err(format!("failed to connect to {}: {}", socket_addr, e)),
+        };
+
+        match self.connector {
+            Some(ref connector) => match connector.connect(&socket_addr.ip().to_string(), stream) {
+                Ok(stream) => Ok(Stream::Tls(stream)),
+                Err(e) => Err(format!("tls handshake with {} failed: {}", socket_addr, e)),
+            },
+            None => Ok(Stream::Plain(stream)),
+        }
+    }
+
+    pub fn accept(&self, stream: TcpStream) -> Result<Stream,String> {
+        match self.acceptor {
+            Some(ref acceptor) => match acceptor.accept(stream) {
+                Ok(stream) => Ok(Stream::Tls(stream)),
+                Err(e) => Err(format!("tls handshake failed: {}", e)),
+            },
+            None => Ok(Stream::Plain(stream)),
+        }
+    }
+}
+
+fn create_connector(ca_file: Option<&str>, identity: Option<(&str,&str)>) -> Result<SslConnector,String> {
+    let mut connector = match SslConnector::builder(SslMethod::tls()) {
+        Ok(connector) => connector,
+        Err(e) => return Err(format!("failed to create tls connector: {}", e)),
+    };
+
+    if let Some(ca_file) = ca_file {
+        if let Err(e) = connector.set_ca_file(ca_file) {
+            return Err(format!("failed to load ca file '{}': {}", ca_file, e));
+        }
+    }
+
+    if let Some((cert_file, key_file)) = identity {
+        if let Err(e) = connector.set_certificate_chain_file(cert_file) {
+            return Err(format!("failed to load certificate file '{}': {}", cert_file, e));
+        }
+
+        if let Err(e) = connector.set_private_key_file(key_file, SslFiletype::PEM) {
+            return Err(format!("failed to load private key file '{}': {}", key_file, e));
+        }
+    }
+
+    Ok(connector.build())
+}
+
+/// Resolves a hostname or an ip address, where ipv6 addresses may be
+/// written with or without brackets.
+pub fn resolve(host: &str, port: u16) -> Result<Vec<SocketAddr>,String> {
+    let host = host.trim_left_matches('[').trim_right_matches(']');
+    match (host, port).to_socket_addrs() {
+        Ok(socket_addrs) => {
+            let socket_addrs: Vec<SocketAddr> = socket_addrs.collect();
+            if socket_addrs.is_empty() {
+                Err(format!("'{}' did not resolve to any address", host))
+            } else {
+                Ok(socket_addrs)
+            }
+        },
+        Err(e) => Err(format!("failed to resolve '{}': {}", host, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+    use std::thread;
+
+    //fixtures are signed by tests/tls/ca.pem, except untrusted.pem which is self signed
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    //accepts one connection on a new thread and echoes a line back, returning the result of accepting it
+    fn serve(transport: Transport) -> (SocketAddr, thread::JoinHandle<Result<(),String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket_addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut stream = try!(transport.accept(stream));
+            let mut buf = [0; 5];
+            try!(stream.read_exact(&mut buf).map_err(|e| e.to_string()));
+            try!(stream.write_all(&buf).and_then(|_| stream.flush()).map_err(|e| e.to_string()));
+            Ok(())
+        });
+
+        (socket_addr, handle)
+    }
+
+    //sends a message and reads the echo, failing when the server drops the connection
+    fn echo(transport: &Transport, socket_addr: SocketAddr) -> Result<Vec<u8>,String> {
+        let mut stream = try!(transport.connect(socket_addr));
+        try!(stream.write_all(b"hello").and_then(|_| stream.flush()).map_err(|e| e.to_string()));
+        let mut buf = [0; 5];
+        try!(stream.read_exact(&mut buf).map_err(|e| e.to_string()));
+        Ok(buf.to_vec())
+    }
+
+    fn server(mutual: bool) -> Transport {
+        Transport::server(&fixture("node.pem"), &fixture("node.key"), Some(&fixture("ca.pem")), mutual).unwrap()
+    }
+
+    #[test]
+    fn plain_round_trip() {
+        let (socket_addr, handle) = serve(Transport::plain());
+        assert_eq!(echo(&Transport::plain(), socket_addr), Ok(b"hello".to_vec()));
+        assert_eq!(handle.join().unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn tls_round_trip() {
+        let (socket_addr, handle) = serve(server(false));
+        let client = Transport::client(Some(&fixture("ca.pem")), None).unwrap();
+        assert_eq!(echo(&client, socket_addr), Ok(b"hello".to_vec()));
+        assert_eq!(handle.join().unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn untrusted_server_rejected() {
+        let (socket_addr, handle) = serve(server(false));
+        let client = Transport::client(Some(&fixture("untrusted.pem")), None).unwrap();
+        assert!(echo(&client, socket_addr).is_err());
+        assert!(handle.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn mutual_tls_round_trip() {
+        let (socket_addr, handle) = serve(server(true));
+        let client = Transport::client(Some(&fixture("ca.pem")), Some((&fixture("client.pem"), &fixture("client.key")))).unwrap();
+        assert_eq!(echo(&client, socket_addr), Ok(b"hello".to_vec()));
+        assert_eq!(handle.join().unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn mutual_tls_rejects_missing_certificate() {
+        let (socket_addr, handle) = serve(server(true));
+        let client = Transport::client(Some(&fixture("ca.pem")), None).unwrap();
+        assert!(echo(&client, socket_addr).is_err());
+        assert!(handle.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn mutual_tls_rejects_untrusted_certificate() {
+        let (socket_addr, handle) = serve(server(true));
+        let client = Transport::client(Some(&fixture("ca.pem")), Some((&fixture("untrusted.pem"), &fixture("untrusted.key")))).unwrap();
+        assert!(echo(&client, socket_addr).is_err());
+        assert!(handle.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn mutual_tls_requires_ca() {
+        assert!(Transport::server(&fixture("node.pem"), &fixture("node.key"), None, true).is_err());
+        assert!(Transport::server(&fixture("node.pem"), &fixture("client.key"), Some(&fixture("ca.pem")), false).is_err());
+    }
+
+    #[test]
+    fn credentials_require_tls() {
+        let mut transport = Transport::plain();
+        assert!(!transport.allows_credentials());
+        transport.set_allow_insecure_auth(true);
+        assert!(transport.allows_credentials());
+        assert!(server(false).allows_credentials());
+        assert!(Transport::client(Some(&fixture("ca.pem")), None).unwrap().allows_credentials());
+    }
+}